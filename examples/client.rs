@@ -16,19 +16,40 @@ use awc::{
     BoxedSocket,
 };
 use futures::{stream::SplitSink, StreamExt};
-use infotainer::{websocket::{ClientCommand, ServerMessage}};
+use infotainer::{
+    protocol::{Capability, PROTOCOL_VERSION},
+    pubsub::{DeliveryMode, Publication, SubscriptionKind, CONTENT_TYPE_HEADER},
+    websocket::{ClientCommand, ClientRequest, ServerMessage},
+};
 use itertools::Itertools;
 use uuid::Uuid;
 
 static CLI_COMMANDS: &[&str] = &["PublishText", "Subscribe", "Unsubscribe"];
 
-struct Connection(SinkWrite<Message, SplitSink<Framed<BoxedSocket, Codec>, Message>>);
+struct Connection {
+    sink: SinkWrite<Message, SplitSink<Framed<BoxedSocket, Codec>, Message>>,
+    last_request_id: u64,
+}
 
 impl Actor for Connection {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Context<Self>) {
-        self.hb(ctx)
+        self.hb(ctx);
+        let hello = ClientCommand::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::PushDelivery, Capability::Acks],
+        };
+        self.sink.write(Message::Binary(Bytes::from(
+            serde_cbor::to_vec(&hello).unwrap(),
+        )));
+        // Receive publications in full, unless a subscription asks for notifications only
+        let cmd = ClientCommand::SetSessionDeliveryMode {
+            mode: DeliveryMode::Full,
+        };
+        self.sink.write(Message::Binary(Bytes::from(
+            serde_cbor::to_vec(&cmd).unwrap(),
+        )));
     }
 
     fn stopped(&mut self, _: &mut Context<Self>) {
@@ -39,36 +60,57 @@ impl Actor for Connection {
 impl StreamHandler<Result<Frame, WsProtocolError>> for Connection {
     fn handle(&mut self, msg: Result<Frame, WsProtocolError>, _: &mut Context<Self>) {
         match msg {
-            Ok(frame) => match frame {
-                Frame::Binary(data) => {
-                    if let Ok(iss) = serde_cbor::from_slice::<ServerMessage>(&data) {
-                        match iss {
-                            ServerMessage::Issue(i) => {
-                                let cmd = ClientCommand::GetLogEntries {
-                                    log_id: i.0,
-                                    entries: vec![i.1],
-                                };
-                                self.0.write(Message::Binary(Bytes::from(
-                                    serde_cbor::to_vec(&cmd).unwrap(),
-                                )));
-                            }
-                            ServerMessage::LogEntry(e) => {
-                                for p in e {
-                                    let data: String = String::from_utf8(p.data).unwrap();
-                                    println!(
-                                        "Received publication {} for Subscription {}:\n{}",
-                                        p.publication_id, p.subscription_id, data
-                                    )
-                                }
+            Ok(Frame::Binary(data)) => {
+                if let Ok(iss) = serde_cbor::from_slice::<ServerMessage>(&data) {
+                    match iss {
+                        ServerMessage::Issue(i) => {
+                            let cmd = ClientCommand::GetLogEntries {
+                                log_id: i.0,
+                                entries: vec![i.1],
+                            };
+                            self.sink.write(Message::Binary(Bytes::from(
+                                serde_cbor::to_vec(&cmd).unwrap(),
+                            )));
+                        }
+                        ServerMessage::Publication(p) => self.receive_publication(p),
+                        ServerMessage::LogEntry(e) => {
+                            for p in e {
+                                self.receive_publication(p)
                             }
-                            ServerMessage::LogIndex(i) => println!("{:?}", i),
                         }
-                    } else {
-                        println!("Unable to handle received message");
+                        ServerMessage::LogIndex(i) => println!("{:?}", i),
+                        ServerMessage::LogRange(r) => println!("{:?}", r),
+                        ServerMessage::SubscriptionInfo(s) => println!("{:?}", s),
+                        ServerMessage::Offer {
+                            min_version,
+                            max_version,
+                            capabilities,
+                        } => println!(
+                            "Server speaks versions {} to {}, {:?}",
+                            min_version, max_version, capabilities
+                        ),
+                        ServerMessage::Welcome {
+                            version,
+                            capabilities,
+                        } => println!("Protocol version {}, {:?}", version, capabilities),
+                        ServerMessage::Ack { request_id, result } => match result {
+                            Ok(outcome) => println!("Request {}: {:?}", request_id, outcome),
+                            Err(e) => println!(
+                                "Request {} failed with {:?}: {}",
+                                request_id, e.code, e.message
+                            ),
+                        },
+                        ServerMessage::Error {
+                            code,
+                            message,
+                            command,
+                        } => println!("Error {:?} in {:?}: {}", code, command, message),
                     }
+                } else {
+                    println!("Unable to handle received message");
                 }
-                _ => (),
-            },
+            }
+            Ok(_) => (),
             Err(e) => println!("{:?}", e),
         }
     }
@@ -89,16 +131,34 @@ impl Handler<CliCommand> for Connection {
     type Result = ();
 
     fn handle(&mut self, msg: CliCommand, _: &mut Self::Context) -> Self::Result {
-        self.0.write(Message::Binary(Bytes::from(
-            serde_cbor::to_vec(&ClientCommand::from(msg.into())).unwrap(),
+        self.last_request_id += 1;
+        let request = ClientRequest::new(self.last_request_id, ClientCommand::from(msg));
+        self.sink.write(Message::Binary(Bytes::from(
+            serde_cbor::to_vec(&request).unwrap(),
         )));
     }
 }
 
 impl Connection {
+    // Prints a publication and acknowledges it, so it is not replayed on reconnect
+    fn receive_publication(&mut self, p: Publication) {
+        let cmd = ClientCommand::Ack {
+            subscription_id: p.subscription_id,
+            publication_id: p.publication_id,
+        };
+        let data: String = String::from_utf8(p.data).unwrap();
+        println!(
+            "Received publication {} for Subscription {}:\n{}",
+            p.publication_id, p.subscription_id, data
+        );
+        self.sink.write(Message::Binary(Bytes::from(
+            serde_cbor::to_vec(&cmd).unwrap(),
+        )));
+    }
+
     fn hb(&self, ctx: &mut Context<Self>) {
         ctx.run_interval(time::Duration::new(5, 0), |act, _| {
-            act.0.write(Message::Ping(Bytes::new()));
+            act.sink.write(Message::Ping(Bytes::new()));
         });
     }
 }
@@ -120,7 +180,12 @@ impl TryFrom<String> for CliCommand {
             std::io::ErrorKind::InvalidInput,
             "Missing command",
         ))?;
-        if !CLI_COMMANDS.contains(&cmd) {}
+        if !CLI_COMMANDS.contains(&cmd) {
+            return Err(Box::new(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Invalid command",
+            )));
+        }
         let id = Uuid::from_str(cli_input.next().ok_or(Error::new(
             std::io::ErrorKind::InvalidInput,
             "Missing log id parameter",
@@ -137,16 +202,29 @@ impl TryFrom<String> for CliCommand {
     }
 }
 
-impl Into<ClientCommand> for CliCommand {
-    fn into(self) -> ClientCommand {
-        match self {
+impl From<CliCommand> for ClientCommand {
+    fn from(cmd: CliCommand) -> ClientCommand {
+        match cmd {
             CliCommand::PublishText(subscription_id, submission) => {
                 ClientCommand::SubmitPublication {
                     subscription_id,
                     submission: submission.into(),
+                    key: None,
+                    retain: false,
+                    headers: vec![(
+                        String::from(CONTENT_TYPE_HEADER),
+                        String::from("text/plain"),
+                    )]
+                    .into_iter()
+                    .collect(),
                 }
             }
-            CliCommand::Subscribe(subscription_id) => ClientCommand::Subscribe { subscription_id },
+            CliCommand::Subscribe(subscription_id) => ClientCommand::Subscribe {
+                subscription_id,
+                kind: SubscriptionKind::Broadcast,
+                durable: true,
+                create: true,
+            },
             CliCommand::Unsubscribe(subscription_id) => {
                 ClientCommand::Unsubscribe { subscription_id }
             }
@@ -156,21 +234,28 @@ impl Into<ClientCommand> for CliCommand {
 
 fn main() -> std::io::Result<()> {
     env_logger::init();
-    let client_id = Uuid::new_v4();
+    // Connect as the client a token was issued for, if the server requires authentication
+    let client_id = std::env::var("INFOTAINER_CLIENT_ID")
+        .map(|id| Uuid::from_str(&id).expect("Invalid client id"))
+        .unwrap_or_else(|_| Uuid::new_v4());
+    let token = std::env::var("INFOTAINER_TOKEN").ok();
 
     let sys = System::new("infotainer-client-example");
 
     Arbiter::spawn(async move {
-        let (response, framed) = Client::default()
-            .ws(format!("ws://127.0.0.1:1312/ws/{}", client_id))
-            .connect()
-            .await
-            .unwrap();
+        let mut request = Client::default().ws(format!("ws://127.0.0.1:1312/ws/{}", client_id));
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        let (response, framed) = request.connect().await.unwrap();
         println!("Response: {:?}", response);
         let (sink, stream) = framed.split();
         let conn = Connection::create(|ctx| {
             Connection::add_stream(stream, ctx);
-            Connection(SinkWrite::new(sink, ctx))
+            Connection {
+                sink: SinkWrite::new(sink, ctx),
+                last_request_id: 0,
+            }
         });
         thread::spawn(move || loop {
             let mut cmd = String::default();
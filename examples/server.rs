@@ -1,26 +1,59 @@
 use std::fs::create_dir_all;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use actix::prelude::Actor;
 use actix_web::{middleware, web, App, HttpServer};
 
-use infotainer::{data_log::DataLogger, pubsub::PubSubService, websocket::websocket_handler};
+use infotainer::{
+    auth::{Authenticator, StaticTokenAuthenticator},
+    data_log::DataLogger,
+    long_poll::{self, LongPollService},
+    pubsub::PubSubService,
+    rest,
+    sse::event_stream_handler,
+    websocket::websocket_handler,
+};
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
     let data_path = PathBuf::from("/tmp/infotainer-server-example");
     create_dir_all(&data_path)?;
-    let data_logger_addr = DataLogger::new(&data_path)
-        .expect("Could not initiate DataLogger")
-        .start();
-    let pubsub_server_addr = PubSubService::new(&data_logger_addr).start();
+    let data_logger = DataLogger::new(&data_path).expect("Could not initiate DataLogger");
+    let recovered_state = data_logger
+        .recover_state()
+        .expect("Could not recover server state");
+    let data_logger_addr = data_logger.start();
+    let pubsub_server_addr = PubSubService::recover(&data_logger_addr, recovered_state).start();
+    let long_poll_addr = LongPollService::new(&pubsub_server_addr).start();
+    // Sessions are authenticated with bearer tokens if a token file is given
+    let authenticator: Option<Arc<dyn Authenticator>> = std::env::var_os("INFOTAINER_TOKEN_FILE")
+        .map(|path| {
+            Arc::new(
+                StaticTokenAuthenticator::from_file(&PathBuf::from(path))
+                    .expect("Could not load token file"),
+            ) as Arc<dyn Authenticator>
+        });
     HttpServer::new(move || {
-        App::new()
-            .data(pubsub_server_addr.clone())
+        let mut app = App::new();
+        if let Some(authenticator) = &authenticator {
+            app = app.app_data(web::Data::from(authenticator.clone()));
+        }
+        app.data(pubsub_server_addr.clone())
             .data(data_logger_addr.clone())
-            .wrap(middleware::Logger::default())
+            .data(long_poll_addr.clone())
+            // The default format logs query strings, which may carry access tokens
+            .wrap(middleware::Logger::new(
+                r#"%a "%U" %s %b "%{Referer}i" "%{User-Agent}i" %T"#,
+            ))
             .service(web::resource("/ws/{session_id}").route(web::get().to(websocket_handler)))
+            .service(
+                web::scope("/api")
+                    .configure(rest::configure)
+                    .configure(long_poll::configure),
+            )
+            .service(web::resource("/events").route(web::get().to(event_stream_handler)))
     })
     .bind("127.0.0.1:1312")?
     .run()
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::Path;
+use std::str::FromStr;
+
+use actix_web::{
+    error::ResponseError,
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse,
+};
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Name of the query parameter a bearer token can be passed in, for clients unable
+/// to set headers on websocket requests
+pub const TOKEN_QUERY_PARAMETER: &str = "access_token";
+
+/// Represents errors caused while authenticating a client
+#[derive(Debug, Error, PartialEq, Clone)]
+pub enum AuthError {
+    #[error("Missing credentials")]
+    MissingCredentials,
+    #[error("Invalid credentials")]
+    InvalidCredentials,
+    #[error("Client {0} is not allowed to connect as {1}")]
+    IdentityMismatch(Uuid, Uuid),
+    #[error("Could not load credentials: {0}")]
+    Configuration(String),
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::MissingCredentials | AuthError::InvalidCredentials => {
+                StatusCode::UNAUTHORIZED
+            }
+            AuthError::IdentityMismatch(_, _) => StatusCode::FORBIDDEN,
+            AuthError::Configuration(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if self.status_code() == StatusCode::UNAUTHORIZED {
+            response.set_header(header::WWW_AUTHENTICATE, "Bearer");
+        }
+        response.body(self.to_string())
+    }
+}
+
+/// Checks the credentials presented with a websocket upgrade request.
+/// Implementations may verify bearer tokens, signed tokens, shared secrets or anything else
+/// the request carries, and map them to the identity of the connecting client.
+///
+/// An authenticator is enabled by registering it as `web::Data<dyn Authenticator>`:
+/// `App::new().app_data(web::Data::from(Arc::new(authenticator) as Arc<dyn Authenticator>))`
+pub trait Authenticator: Debug + Send + Sync {
+    ///Returns the id of the client the request was made by
+    fn authenticate(&self, req: &HttpRequest) -> Result<Uuid, AuthError>;
+}
+
+///Extracts a bearer token from the `Authorization` header or the `access_token` query parameter.
+///Tokens passed in the query end up wherever request URLs are recorded, e.g. in access logs
+///or proxy logs, so servers accepting them should not log query strings.
+pub fn bearer_token(req: &HttpRequest) -> Option<String> {
+    if let Some(value) = req.headers().get(header::AUTHORIZATION) {
+        return value
+            .to_str()
+            .ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_owned());
+    }
+    web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.get(TOKEN_QUERY_PARAMETER).cloned())
+}
+
+/// An `Authenticator` accepting a fixed set of bearer tokens, each belonging to one client
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StaticTokenAuthenticator {
+    tokens: HashMap<String, Uuid>,
+}
+
+impl StaticTokenAuthenticator {
+    pub fn new(tokens: HashMap<String, Uuid>) -> StaticTokenAuthenticator {
+        StaticTokenAuthenticator { tokens }
+    }
+
+    ///Loads tokens from a file holding one `<token> <client_id>` pair per line.
+    ///Empty lines and lines starting with `#` are ignored.
+    pub fn from_file(path: &Path) -> Result<StaticTokenAuthenticator, AuthError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| AuthError::Configuration(format!("{}: {}", path.display(), e)))?;
+        StaticTokenAuthenticator::from_str(&contents)
+    }
+}
+
+impl FromStr for StaticTokenAuthenticator {
+    type Err = AuthError;
+
+    fn from_str(s: &str) -> Result<StaticTokenAuthenticator, AuthError> {
+        let mut tokens = HashMap::new();
+        for (number, line) in (1..).zip(s.lines()) {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (token, client_id) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(token), Some(client_id), None) => (token, client_id),
+                _ => {
+                    return Err(AuthError::Configuration(format!(
+                        "Line {}: expected <token> <client_id>",
+                        number
+                    )))
+                }
+            };
+            let client_id = Uuid::from_str(client_id)
+                .map_err(|e| AuthError::Configuration(format!("Line {}: {}", number, e)))?;
+            tokens.insert(token.to_owned(), client_id);
+        }
+        Ok(StaticTokenAuthenticator { tokens })
+    }
+}
+
+impl Authenticator for StaticTokenAuthenticator {
+    fn authenticate(&self, req: &HttpRequest) -> Result<Uuid, AuthError> {
+        let token = bearer_token(req).ok_or(AuthError::MissingCredentials)?;
+        self.tokens
+            .get(&token)
+            .copied()
+            .ok_or(AuthError::InvalidCredentials)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_static_token_authenticator() {
+        let client_id = Uuid::new_v4();
+        let authenticator = StaticTokenAuthenticator::from_str(&format!(
+            "# token client_id\n\nsecret-token {}\n",
+            client_id
+        ))
+        .unwrap();
+        let req = TestRequest::default()
+            .header(header::AUTHORIZATION, "Bearer secret-token")
+            .to_http_request();
+        assert_eq!(authenticator.authenticate(&req), Ok(client_id));
+        let req = TestRequest::with_uri("/ws?access_token=secret-token").to_http_request();
+        assert_eq!(authenticator.authenticate(&req), Ok(client_id));
+        let req = TestRequest::default()
+            .header(header::AUTHORIZATION, "Bearer guessed-token")
+            .to_http_request();
+        assert_eq!(
+            authenticator.authenticate(&req),
+            Err(AuthError::InvalidCredentials)
+        );
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(
+            authenticator.authenticate(&req),
+            Err(AuthError::MissingCredentials)
+        );
+    }
+
+    #[test]
+    fn test_invalid_token_file() {
+        assert!(matches!(
+            StaticTokenAuthenticator::from_str("lonely-token"),
+            Err(AuthError::Configuration(_))
+        ));
+        assert!(matches!(
+            StaticTokenAuthenticator::from_str("token notauuid"),
+            Err(AuthError::Configuration(_))
+        ));
+        assert!(matches!(
+            StaticTokenAuthenticator::from_file(Path::new("/frank/nord")),
+            Err(AuthError::Configuration(_))
+        ));
+    }
+}
@@ -1,20 +1,201 @@
 use std::fmt::Debug;
-use std::fs::{create_dir_all, read_dir, DirEntry, OpenOptions};
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::{
-    collections::{HashMap, HashSet},
-    str::FromStr,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use actix::prelude::{Actor, Context, Handler, Message, Recipient, SendError};
-use thiserror::Error;
-use faccess::{AccessMode, PathExt};
+use actix::prelude::{Actor, AsyncContext, Context, Handler, Message, Recipient, SendError};
+use log::{info, warn};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
 use uuid::Uuid;
 
-use crate::pubsub::{Publication, Subscription};
+use crate::data_store::{DataStore, FileSystemStore, MemoryStore};
+use crate::pubsub::{Publication, RecoveredState, Subscription};
+use crate::segment_log::SegmentConfig;
+
+/// Maps collection ids to the index of their log
+pub type DataLogIndex = HashMap<Uuid, CollectionIndex>;
+
+/// Upper bound for the number of publications returned by a single `LogRangePull`
+pub const MAX_RANGE_LIMIT: usize = 1000;
+
+/// How often a running `DataLogger` enforces the retention policies of its collections
+pub const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Determines which publications are removed from the log of a subscription.
+/// Limits are combined, a publication is removed as soon as it exceeds any of them.
+/// The default policy retains everything.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RetentionPolicy {
+    /// Publications older than this are removed
+    pub max_age: Option<Duration>,
+    /// Only this many of the newest publications are retained
+    pub max_count: Option<u64>,
+    /// The newest publications are retained up to this many bytes of log records
+    pub max_bytes: Option<u64>,
+    /// Only the newest publication for each key is retained.
+    /// Publications without a key are not affected by compaction.
+    pub compact_by_key: bool,
+}
+
+impl RetentionPolicy {
+    /// Returns true if the policy never removes any publication
+    pub fn retains_all(&self) -> bool {
+        self == &RetentionPolicy::default()
+    }
+}
+
+/// The index of a single collection log. Keeps publications ordered by their
+/// sequence number and remembers the log offset each of them is stored at.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CollectionIndex {
+    entries: BTreeMap<u64, IndexEntry>,
+    sequences: HashMap<Uuid, u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct IndexEntry {
+    publication_id: Uuid,
+    timestamp: u64,
+    offset: u64,
+    size: u64,
+    key: Option<String>,
+}
 
-pub type DataLogIndex = HashMap<Uuid, HashSet<Uuid>>;
+impl CollectionIndex {
+    /// Records a publication stored at `offset` in a record of `size` bytes under its sequence number
+    pub fn insert(&mut self, sequence: u64, publication: &Publication, offset: u64, size: u64) {
+        self.entries.insert(
+            sequence,
+            IndexEntry {
+                publication_id: publication.publication_id,
+                timestamp: publication.timestamp,
+                offset,
+                size,
+                key: publication.key.clone(),
+            },
+        );
+        self.sequences.insert(publication.publication_id, sequence);
+    }
+
+    /// Removes a publication from the index
+    pub fn remove(&mut self, sequence: u64) {
+        if let Some(entry) = self.entries.remove(&sequence) {
+            self.sequences.remove(&entry.publication_id);
+        }
+    }
+
+    /// Records the new offset and size of publications the store moved, keyed by their previous offset
+    pub fn relocate(&mut self, moved: &HashMap<u64, (u64, u64)>) {
+        if moved.is_empty() {
+            return;
+        }
+        for entry in self.entries.values_mut() {
+            if let Some((offset, size)) = moved.get(&entry.offset) {
+                entry.offset = *offset;
+                entry.size = *size;
+            }
+        }
+    }
+
+    /// Returns the sequence numbers of all publications that `policy` removes
+    /// at `now`, in milliseconds since the UNIX epoch
+    pub fn expired(&self, policy: &RetentionPolicy, now: u64) -> BTreeSet<u64> {
+        let age_limit = policy
+            .max_age
+            .map(|age| now.saturating_sub(age.as_millis() as u64));
+        let mut expired = BTreeSet::new();
+        let mut keys = HashSet::new();
+        let (mut count, mut bytes) = (0u64, 0u64);
+        for (sequence, entry) in self.entries.iter().rev() {
+            let superseded = policy.compact_by_key
+                && entry
+                    .key
+                    .as_ref()
+                    .map_or(false, |k| !keys.insert(k.as_str()));
+            if superseded {
+                expired.insert(*sequence);
+                continue;
+            }
+            count += 1;
+            bytes += entry.size;
+            let too_old = age_limit.map_or(false, |limit| entry.timestamp < limit);
+            let too_many = policy.max_count.map_or(false, |max| count > max);
+            let too_large = policy.max_bytes.map_or(false, |max| bytes > max);
+            if too_old || too_many || too_large {
+                expired.insert(*sequence);
+            }
+        }
+        expired
+    }
+
+    /// Returns the sequence number and log offset of a publication
+    pub fn get(&self, publication_id: &Uuid) -> Option<(u64, u64)> {
+        let sequence = self.sequences.get(publication_id)?;
+        self.entries
+            .get(sequence)
+            .map(|entry| (*sequence, entry.offset))
+    }
+
+    /// Selects up to `limit` publications, starting at `start` and moving in `direction`.
+    /// Returns their sequence numbers and log offsets, along with the position
+    /// to continue from if there are more publications in that direction.
+    pub fn range(
+        &self,
+        start: LogPosition,
+        limit: usize,
+        direction: RangeDirection,
+    ) -> (Vec<(u64, u64)>, Option<LogPosition>) {
+        let entries: Box<dyn Iterator<Item = (&u64, &IndexEntry)>> = match (start, direction) {
+            (LogPosition::Sequence(s), RangeDirection::Forward) => {
+                Box::new(self.entries.range(s..))
+            }
+            (LogPosition::Sequence(s), RangeDirection::Backward) => {
+                Box::new(self.entries.range(..=s).rev())
+            }
+            (LogPosition::Timestamp(t), RangeDirection::Forward) => Box::new(
+                self.entries
+                    .iter()
+                    .skip_while(move |(_, e)| e.timestamp < t),
+            ),
+            (LogPosition::Timestamp(t), RangeDirection::Backward) => Box::new(
+                self.entries
+                    .iter()
+                    .rev()
+                    .skip_while(move |(_, e)| e.timestamp > t),
+            ),
+        };
+        let mut selection: Vec<(u64, u64)> = entries
+            .take(limit.saturating_add(1))
+            .map(|(sequence, entry)| (*sequence, entry.offset))
+            .collect();
+        let next = if selection.len() > limit {
+            selection
+                .pop()
+                .map(|(sequence, _)| LogPosition::Sequence(sequence))
+        } else {
+            None
+        };
+        (selection, next)
+    }
+
+    /// The highest sequence number in the index
+    pub fn last_sequence(&self) -> Option<u64> {
+        self.entries.keys().next_back().copied()
+    }
+
+    /// Returns the ids of all publications with a sequence number greater than `sequence`,
+    /// or of all publications if `sequence` is `None`, keyed by sequence number
+    pub fn publications_since(&self, sequence: Option<u64>) -> BTreeMap<u64, Uuid> {
+        let start = sequence.map(|s| s.saturating_add(1)).unwrap_or(0);
+        self.entries
+            .range(start..)
+            .map(|(sequence, entry)| (*sequence, entry.publication_id))
+            .collect()
+    }
+}
 
 #[derive(Debug, Error)]
 pub enum DataLogError {
@@ -27,6 +208,12 @@ pub enum DataLogError {
     #[error("Failed sending log entries: {0:?}")]
     PullDataLogEntry(#[source] SendError<DataLogPut<Publication>>),
 
+    #[error("Failed sending log range: {0:?}")]
+    PullLogRange(#[source] SendError<LogRangePut>),
+
+    #[error("Failed sending metadata: {0:?}")]
+    PullMetadata(#[source] SendError<MetadataPut<Subscription>>),
+
     #[error("Could not process DataLogPut: {0}")]
     PutDataLogEntry(#[source] serde_cbor::Error),
 
@@ -35,6 +222,21 @@ pub enum DataLogError {
 
     #[error("Could not read data: {0:?}")]
     ReadError(#[source] serde_cbor::Error),
+
+    #[error("Log entry not found: {0}")]
+    EntryNotFound(Uuid),
+
+    #[error("No record at offset {0}")]
+    RecordNotFound(u64),
+
+    #[error("No metadata for subscription {0}")]
+    MetadataNotFound(Uuid),
+
+    #[error("Database error: {0}")]
+    Database(String),
+
+    #[error("Checksum mismatch: {0}")]
+    ChecksumMismatch(String),
 }
 
 impl From<std::io::Error> for DataLogError {
@@ -52,20 +254,86 @@ pub struct DataLogPull {
     pub selection: Vec<Uuid>,
 }
 
+/// A position in a collection log
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum LogPosition {
+    /// The publication with the given sequence number
+    Sequence(u64),
+    /// The first publication accepted at or after (moving forward),
+    /// or at or before (moving backward) the given time in milliseconds since the UNIX epoch
+    Timestamp(u64),
+}
+
+/// The direction in which a log range is read
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum RangeDirection {
+    /// Towards newer publications
+    Forward,
+    /// Towards older publications
+    Backward,
+}
+
+/// A message to request a page of entries from a log collection.
+/// At most `MAX_RANGE_LIMIT` entries are returned, regardless of `limit`.
+#[derive(Debug, Message)]
+#[rtype("Result<(), DataLogError>")]
+pub struct LogRangePull {
+    pub client: Recipient<LogRangePut>,
+    pub data_log_id: Uuid,
+    pub start: LogPosition,
+    pub limit: usize,
+    pub direction: RangeDirection,
+}
+
+/// Message type for a page of log entries. `next` is the position to continue
+/// reading from in the same direction, or `None` if the end of the log was reached.
+#[derive(Debug, Deserialize, PartialEq, Message, Serialize)]
+#[rtype("Result<(), DataLogError>")]
+pub struct LogRangePut {
+    pub data_log_id: Uuid,
+    pub entries: Vec<Publication>,
+    pub next: Option<LogPosition>,
+}
+
+/// A page of publications, along with the position to continue reading from
+/// in the same direction, or `None` if the end of the log was reached
+pub type LogRange = (Vec<Publication>, Option<LogPosition>);
+
 /// A message to request collection metadata
 #[derive(Debug, Message)]
 #[rtype("Result<(), DataLogError>")]
 pub enum MetadataPull {
-    Single(Uuid),
-    All,
+    /// Request the metadata of a single collection
+    Single {
+        client: Recipient<MetadataPut<Subscription>>,
+        data_log_id: Uuid,
+    },
+    /// Request the metadata of all persisted collections
+    All {
+        client: Recipient<MetadataPut<Subscription>>,
+    },
 }
 
-/// A message to request the data log index of a collection
+/// A message to request the data log index of a collection,
+/// optionally limited to publications after a sequence number
 #[derive(Debug, Message)]
 #[rtype("Result<(), DataLogError>")]
 pub struct LogIndexPull {
     pub client: Recipient<LogIndexPut>,
     pub data_log_id: Uuid,
+    pub since: Option<u64>,
+}
+
+/// A message to request up to `limit` publications of a collection with a sequence number
+/// greater than `since`, e.g. to replay what a subscriber missed while it was offline.
+/// Resolves to the publications, oldest first. Like `LogRangePull`, at most `MAX_RANGE_LIMIT`
+/// publications are returned, larger replays are fetched page by page.
+#[derive(Debug, Message)]
+#[rtype("Result<Vec<Publication>, DataLogError>")]
+pub struct ReplayPull {
+    pub data_log_id: Uuid,
+    pub since: u64,
+    pub limit: usize,
 }
 
 /// Message type for one or more log entries
@@ -73,117 +341,312 @@ pub struct LogIndexPull {
 #[rtype("Result<(), DataLogError>")]
 pub struct DataLogPut<T: Serialize>(pub Vec<T>);
 
-impl Into<Vec<Publication>> for DataLogPut<Publication> {
-    fn into(self) -> Vec<Publication> {
-        self.0
+impl From<DataLogPut<Publication>> for Vec<Publication> {
+    fn from(put: DataLogPut<Publication>) -> Vec<Publication> {
+        put.0
     }
 }
 
-/// Message type for Metadata of a collection
+/// Message type for Metadata of one or more collections
 #[derive(Debug, PartialEq, Message)]
 #[rtype("Result<(), DataLogError>")]
-pub struct MetadataPut<T: Serialize + DeserializeOwned>(T);
+pub struct MetadataPut<T: Serialize + DeserializeOwned>(pub Vec<T>);
+
+/// A message to remove the metadata of a subscription that no longer exists
+#[derive(Debug, Message)]
+#[rtype("Result<(), DataLogError>")]
+pub struct MetadataRemove(pub Uuid);
 
-/// Message Type for sending collection index
+/// Message Type for sending collection index, mapping sequence numbers to publication ids
 #[derive(Debug, Deserialize, PartialEq, Message, Serialize)]
 #[rtype("Result<(), DataLogError>")]
-pub struct LogIndexPut(Uuid, pub HashSet<Uuid>);
+pub struct LogIndexPut(pub Uuid, pub BTreeMap<u64, Uuid>);
 
 /// The Actor responsible for processing DataLog requests sent by
 /// PubSubServer actors.
-#[derive(Debug, Clone)]
+/// Publications and subscription metadata are kept in a `DataStore`.
+/// While running, the retention policy of each collection is enforced every `RETENTION_INTERVAL`.
+#[derive(Debug)]
 pub struct DataLogger {
     log_index: DataLogIndex,
-    data_dir: PathBuf,
+    retention: HashMap<Uuid, RetentionPolicy>,
+    sequence_marks: HashMap<Uuid, u64>,
+    store: Box<dyn DataStore>,
 }
 
 impl DataLogger {
-    ///Creates a new DataLogger actor
+    ///Creates a new DataLogger actor storing data in the filesystem, rebuilding the log index
+    ///from any data already present in the data directory.
     ///## Arguments
     ///* `app_dir` - The application base directory. Must exist and be accessible with rwx permissions.
     pub fn new(app_dir: &Path) -> Result<DataLogger, DataLogError> {
-        if app_dir
-            .access(AccessMode::EXISTS | AccessMode::READ | AccessMode::WRITE | AccessMode::EXECUTE)
-            .is_ok()
-        {
-            let data_dir_path = app_dir.join("data");
-            create_dir_all(&data_dir_path)?;
-            Ok(DataLogger {
-                log_index: HashMap::new(),
-                data_dir: PathBuf::from(&data_dir_path),
-            })
-        } else {
-            Err(DataLogError::FileSystem(format!(
-                "Could not access application base directory with required permissions"
-            )))
+        DataLogger::with_segment_config(app_dir, SegmentConfig::default())
+    }
+
+    ///Creates a new DataLogger actor storing collection logs in segments configured by `segment_config`.
+    ///## Arguments
+    ///* `app_dir` - The application base directory. Must exist and be accessible with rwx permissions.
+    ///* `segment_config` - Size limits for segment files and their index
+    pub fn with_segment_config(
+        app_dir: &Path,
+        segment_config: SegmentConfig,
+    ) -> Result<DataLogger, DataLogError> {
+        DataLogger::with_store(FileSystemStore::open(app_dir, segment_config)?)
+    }
+
+    ///Creates a new DataLogger actor keeping all data in memory.
+    ///Needs no application directory, nothing is retained once it stops.
+    pub fn ephemeral() -> DataLogger {
+        DataLogger {
+            log_index: HashMap::new(),
+            retention: HashMap::new(),
+            sequence_marks: HashMap::new(),
+            store: Box::new(MemoryStore::new()),
         }
     }
 
-    fn get_collection_log_path(&self, data_log_id: &Uuid) -> PathBuf {
-        let mut path = self.data_dir.join(data_log_id.to_string());
-        path.push("log");
-        path
+    ///Creates a new DataLogger actor backed by `store`, rebuilding the log index
+    ///from the publications it holds.
+    pub fn with_store<S: DataStore + 'static>(store: S) -> Result<DataLogger, DataLogError> {
+        let mut data_logger = DataLogger {
+            log_index: HashMap::new(),
+            retention: HashMap::new(),
+            sequence_marks: HashMap::new(),
+            store: Box::new(store),
+        };
+        data_logger.recover_logs()?;
+        Ok(data_logger)
     }
 
-    fn _list_entry_ids<P: AsRef<Path>, F: Fn(&DirEntry) -> bool>(
-        &self,
-        path: P,
-        condition: F,
-    ) -> Result<Vec<Uuid>, DataLogError> {
-        let mut results = Vec::new();
-        let entries = read_dir(path)?;
-        for entry in entries {
-            let dir_entry = entry?;
-            if condition(&dir_entry) {
-                if let Some(dir_name) = dir_entry.file_name().to_str() {
-                    if let Some(collection_id) = Uuid::from_str(dir_name).ok() {
-                        results.push(collection_id)
+    /// Reads the persisted metadata of all subscriptions in the store
+    /// along with the highest sequence number ever logged to each collection,
+    /// including publications its retention policy removed since.
+    /// Used to seed a `PubSubService` with the state it had before a restart.
+    /// Subscriptions without subscribers or owner are skipped, as the `PubSubService`
+    /// would have removed them from its store. Retained publications are read back from
+    /// the log, unless its retention policy removed them in the meantime.
+    pub fn recover_state(&self) -> Result<RecoveredState, DataLogError> {
+        let subscriptions: Vec<Subscription> = self
+            .store
+            .read_all_metadata()?
+            .into_iter()
+            .filter(|s| !s.subscribers.is_empty() || s.access.owner.is_some())
+            .collect();
+        let mut sequences = self.sequence_marks.clone();
+        for (id, index) in &self.log_index {
+            if let Some(last_sequence) = index.last_sequence() {
+                let sequence = sequences.entry(*id).or_insert(0);
+                *sequence = last_sequence.max(*sequence);
+            }
+        }
+        let retained = subscriptions
+            .iter()
+            .filter_map(|s| s.retained.map(|publication_id| (s.id, publication_id)))
+            .filter_map(
+                |(id, publication_id)| match self.read_publication(&id, &publication_id) {
+                    Ok(publication) => Some((id, publication)),
+                    Err(e) => {
+                        warn!("Could not recover retained publication of {}: {}", id, e);
+                        None
                     }
-                }
+                },
+            )
+            .collect();
+        info!("Recovered {} subscriptions", subscriptions.len());
+        Ok(RecoveredState {
+            subscriptions,
+            sequences,
+            retained,
+        })
+    }
+
+    /// Rebuilds the log index from the publications in the store
+    /// and loads the retention policies and sequence marks of all collections.
+    fn recover_logs(&mut self) -> Result<(), DataLogError> {
+        self.sequence_marks = self.store.read_sequence_marks()?;
+        for data_log_id in self.store.collections()? {
+            let index = self.build_index(&data_log_id)?;
+            self.log_index.insert(data_log_id, index);
+        }
+        for subscription in self.store.read_all_metadata()? {
+            if !subscription.retention.retains_all() {
+                self.retention
+                    .insert(subscription.id, subscription.retention);
             }
         }
-        Ok(results)
+        info!(
+            "Recovered log index for {} collections",
+            self.log_index.len()
+        );
+        Ok(())
+    }
+
+    /// Builds the index of a collection from the publications in its log
+    fn build_index(&self, data_log_id: &Uuid) -> Result<CollectionIndex, DataLogError> {
+        let mut index = CollectionIndex::default();
+        for (offset, size, publication) in self.store.scan(data_log_id)? {
+            // Publications logged before sequence numbers were introduced are numbered
+            // by their offset, which stays the same when older publications are removed.
+            let sequence = match publication.sequence {
+                0 => offset + 1,
+                s => s,
+            };
+            index.insert(sequence, &publication, offset, size);
+        }
+        Ok(index)
+    }
+
+    /// Removes the publications of every collection that its retention policy expires
+    fn enforce_retention(&mut self) -> Result<(), DataLogError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let policies: Vec<(Uuid, RetentionPolicy)> = self
+            .retention
+            .iter()
+            .map(|(id, policy)| (*id, policy.clone()))
+            .collect();
+        for (data_log_id, policy) in policies {
+            let removed = self.apply_retention(&data_log_id, &policy, now)?;
+            if removed > 0 {
+                info!(
+                    "Removed {} publications from collection {}",
+                    removed, data_log_id
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the publications of a collection that `policy` expires at `now`
+    /// from the store and the log index, returning their number.
+    /// Before the newest publication is removed, its sequence number is recorded
+    /// as the sequence mark of the collection, so numbering continues after it.
+    fn apply_retention(
+        &mut self,
+        data_log_id: &Uuid,
+        policy: &RetentionPolicy,
+        now: u64,
+    ) -> Result<usize, DataLogError> {
+        let index = match self.log_index.get(data_log_id) {
+            Some(index) => index,
+            None => return Ok(0),
+        };
+        let expired = index.expired(policy, now);
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        let offsets = expired
+            .iter()
+            .filter_map(|sequence| index.entries.get(sequence))
+            .map(|entry| entry.offset)
+            .collect();
+        let last_sequence = index.last_sequence().unwrap_or(0);
+        let marked = self.sequence_marks.get(data_log_id).copied().unwrap_or(0);
+        if expired.contains(&last_sequence) && last_sequence > marked {
+            self.store.write_sequence_mark(data_log_id, last_sequence)?;
+            self.sequence_marks.insert(*data_log_id, last_sequence);
+        }
+        let moved = self.store.remove(data_log_id, &offsets)?;
+        let index = self.log_index.entry(*data_log_id).or_default();
+        for sequence in &expired {
+            index.remove(*sequence);
+        }
+        index.relocate(&moved);
+        Ok(expired.len())
     }
 
-    fn read_data_file<T: Serialize + DeserializeOwned>(
+    /// Appends a publication to the log of its collection and records it in the log index
+    fn append_publication(&mut self, publication: &Publication) -> Result<(), DataLogError> {
+        let (offset, size) = self.store.append(publication)?;
+        self.log_index
+            .entry(publication.subscription_id)
+            .or_default()
+            .insert(publication.sequence, publication, offset, size);
+        Ok(())
+    }
+
+    /// Reads the publication stored at `offset` in the log of a collection
+    fn read_publication_at(
         &self,
-        filename: &str,
-        path: &PathBuf,
-    ) -> Result<T, DataLogError> {
-        let file = OpenOptions::new().read(true).open(path.join(filename))?;
-        serde_cbor::from_reader(&file).map_err(|e| DataLogError::ReadError(e))
+        data_log_id: &Uuid,
+        sequence: u64,
+        offset: u64,
+    ) -> Result<Publication, DataLogError> {
+        let mut publication = self.store.read(data_log_id, offset)?;
+        publication.sequence = sequence;
+        Ok(publication)
     }
 
-    fn write_data_file<T: Serialize>(
+    /// Reads up to `limit` publications of a collection from `start` on in `direction`,
+    /// along with the position to continue from. Ranges are selected by the store
+    /// if it can do so itself, otherwise from the log index.
+    fn read_range(
         &self,
-        filename: &str,
-        path: &PathBuf,
-        data: T,
-    ) -> Result<(), DataLogError> {
-        create_dir_all(path)?;
-        let file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&path.join(filename))?;
-        serde_cbor::to_writer(file, &data).map_err(|e| DataLogError::WriteError(e))
+        data_log_id: &Uuid,
+        start: LogPosition,
+        limit: usize,
+        direction: RangeDirection,
+    ) -> Result<LogRange, DataLogError> {
+        if let Some(range) = self
+            .store
+            .read_range(data_log_id, start, limit, direction)?
+        {
+            return Ok(range);
+        }
+        let (selection, next) = match self.log_index.get(data_log_id) {
+            Some(index) => index.range(start, limit, direction),
+            None => (Vec::new(), None),
+        };
+        let mut entries = Vec::with_capacity(selection.len());
+        for (sequence, offset) in selection {
+            entries.push(self.read_publication_at(data_log_id, sequence, offset)?);
+        }
+        Ok((entries, next))
+    }
+
+    /// Reads a single publication from the log of a collection
+    fn read_publication(
+        &self,
+        data_log_id: &Uuid,
+        publication_id: &Uuid,
+    ) -> Result<Publication, DataLogError> {
+        let (sequence, offset) = self
+            .log_index
+            .get(data_log_id)
+            .and_then(|index| index.get(publication_id))
+            .ok_or(DataLogError::EntryNotFound(*publication_id))?;
+        self.read_publication_at(data_log_id, sequence, offset)
     }
 }
 
 impl Actor for DataLogger {
     type Context = Context<DataLogger>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(RETENTION_INTERVAL, |data_logger, _| {
+            if let Err(e) = data_logger.enforce_retention() {
+                warn!("Could not enforce retention policies: {}", e);
+            }
+        });
+    }
 }
 
 impl Handler<MetadataPull> for DataLogger {
     type Result = Result<(), DataLogError>;
 
     fn handle(&mut self, msg: MetadataPull, _: &mut Context<Self>) -> Self::Result {
-        Ok(match &msg {
-            MetadataPull::Single(subscription_id) => {
-                let log_path = self.data_dir.join(subscription_id.to_string());
-                self.read_data_file("metadata.cbor", &log_path)?;
-            }
-            MetadataPull::All => {}
-        })
+        let (client, metadata) = match msg {
+            MetadataPull::Single {
+                client,
+                data_log_id,
+            } => (client, vec![self.store.read_metadata(&data_log_id)?]),
+            MetadataPull::All { client } => (client, self.store.read_all_metadata()?),
+        };
+        client
+            .try_send(MetadataPut(metadata))
+            .map_err(DataLogError::PullMetadata)
     }
 }
 
@@ -191,8 +654,26 @@ impl Handler<MetadataPut<Subscription>> for DataLogger {
     type Result = Result<(), DataLogError>;
 
     fn handle(&mut self, msg: MetadataPut<Subscription>, _: &mut Context<Self>) -> Self::Result {
-        let log_path = self.get_collection_log_path(&msg.0.id);
-        self.write_data_file("metadata.cbor", &log_path, &msg.0)
+        for subscription in msg.0 {
+            self.store.write_metadata(&subscription)?;
+            if subscription.retention.retains_all() {
+                self.retention.remove(&subscription.id);
+            } else {
+                self.retention
+                    .insert(subscription.id, subscription.retention);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Handler<MetadataRemove> for DataLogger {
+    type Result = Result<(), DataLogError>;
+
+    fn handle(&mut self, msg: MetadataRemove, _: &mut Context<Self>) -> Self::Result {
+        self.store.remove_metadata(&msg.0)?;
+        self.retention.remove(&msg.0);
+        Ok(())
     }
 }
 
@@ -200,13 +681,15 @@ impl Handler<LogIndexPull> for DataLogger {
     type Result = Result<(), DataLogError>;
 
     fn handle(&mut self, msg: LogIndexPull, _: &mut Context<Self>) -> Self::Result {
-        Ok(
-            if let Some(log_index_entry) = self.log_index.get_key_value(&msg.data_log_id).clone() {
-                &msg.client
-                    .try_send(LogIndexPut(*log_index_entry.0, log_index_entry.1.clone()))
-                    .map_err(|e| DataLogError::PullIndex(e))?;
-            },
-        )
+        // Logs without publications have no index yet, an empty one is sent for them
+        let index = self
+            .log_index
+            .get(&msg.data_log_id)
+            .map(|index| index.publications_since(msg.since))
+            .unwrap_or_default();
+        msg.client
+            .try_send(LogIndexPut(msg.data_log_id, index))
+            .map_err(DataLogError::PullIndex)
     }
 }
 
@@ -214,38 +697,50 @@ impl Handler<DataLogPull> for DataLogger {
     type Result = Result<(), DataLogError>;
 
     fn handle(&mut self, msg: DataLogPull, _: &mut Context<Self>) -> Self::Result {
-        let log_path = self.get_collection_log_path(&msg.data_log_id);
         let mut read_results = Vec::new();
         for item in msg.selection {
-            read_results.push(self.read_data_file(&item.to_string(), &log_path)?);
+            read_results.push(self.read_publication(&msg.data_log_id, &item)?);
         }
         msg.client
             .try_send(DataLogPut(read_results))
-            .map_err(|e| DataLogError::PullDataLogEntry(e))
+            .map_err(DataLogError::PullDataLogEntry)
     }
 }
 
-impl Handler<DataLogPut<Publication>> for DataLogger {
+impl Handler<LogRangePull> for DataLogger {
     type Result = Result<(), DataLogError>;
 
-    fn handle(&mut self, msg: DataLogPut<Publication>, _: &mut Context<Self>) -> Self::Result {
-        Ok(for item in msg.0 {
-            let log_path = self.get_collection_log_path(&item.subscription_id);
-            self.write_data_file(&item.publication_id.to_string(), &log_path, &item)?;
-            let log_index_entry = self
-                .log_index
-                .entry(item.subscription_id)
-                .or_insert(HashSet::new());
-            log_index_entry.insert(item.publication_id);
-        })
+    fn handle(&mut self, msg: LogRangePull, _: &mut Context<Self>) -> Self::Result {
+        let limit = msg.limit.min(MAX_RANGE_LIMIT);
+        let (entries, next) = self.read_range(&msg.data_log_id, msg.start, limit, msg.direction)?;
+        msg.client
+            .try_send(LogRangePut {
+                data_log_id: msg.data_log_id,
+                entries,
+                next,
+            })
+            .map_err(DataLogError::PullLogRange)
+    }
+}
+
+impl Handler<ReplayPull> for DataLogger {
+    type Result = Result<Vec<Publication>, DataLogError>;
+
+    fn handle(&mut self, msg: ReplayPull, _: &mut Context<Self>) -> Self::Result {
+        let start = LogPosition::Sequence(msg.since.saturating_add(1));
+        let limit = msg.limit.min(MAX_RANGE_LIMIT);
+        self.read_range(&msg.data_log_id, start, limit, RangeDirection::Forward)
+            .map(|(entries, _)| entries)
     }
 }
 
-impl Handler<LogIndexPut> for DataLogger {
+impl Handler<DataLogPut<Publication>> for DataLogger {
     type Result = Result<(), DataLogError>;
 
-    fn handle(&mut self, msg: LogIndexPut, _: &mut Context<Self>) -> Self::Result {
-        self.log_index.insert(msg.0, msg.1);
+    fn handle(&mut self, msg: DataLogPut<Publication>, _: &mut Context<Self>) -> Self::Result {
+        for item in msg.0 {
+            self.append_publication(&item)?;
+        }
         Ok(())
     }
 }
@@ -253,29 +748,311 @@ impl Handler<LogIndexPut> for DataLogger {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::env::temp_dir;
+    use crate::test_utils::{create_test_directory, remove_test_directory};
 
-    fn create_test_directory() -> PathBuf {
-        let mut p = temp_dir();
-        p.push(format!("infotainer-{}", Uuid::new_v4().to_hyphenated()));
-        std::fs::create_dir(&p).unwrap();
-        p
+    #[actix_rt::test]
+    async fn test_starting_data_logger() {
+        let test_dir = create_test_directory();
+        let data_logger = DataLogger::new(&test_dir).unwrap();
+        assert!(test_dir.join("data").is_dir());
+        let data_logger_actor = data_logger.start();
+        assert!(data_logger_actor.connected());
+        remove_test_directory(&test_dir);
     }
 
-    fn remove_test_directory(p: &Path) {
-        std::fs::remove_dir_all(p).unwrap();
+    #[actix_rt::test]
+    async fn test_ephemeral_data_logger() {
+        let mut data_logger = DataLogger::ephemeral();
+        let subscription_id = Uuid::new_v4();
+        let publication = Publication::new(&subscription_id, 1, b"Test Publication");
+        data_logger.append_publication(&publication).unwrap();
+        assert_eq!(
+            data_logger
+                .read_publication(&subscription_id, &publication.publication_id)
+                .unwrap(),
+            publication
+        );
+        assert!(data_logger.start().connected());
     }
 
     #[actix_rt::test]
-    async fn test_starting_data_logger() {
+    async fn test_recovering_data_logger_state() {
         let test_dir = create_test_directory();
+        let mut store = FileSystemStore::open(&test_dir, SegmentConfig::default()).unwrap();
+        let subscriber_id = Uuid::new_v4();
+        let mut subscription = Subscription::new(&Uuid::new_v4(), "Test Subscription");
+        subscription.append_subscriber(&subscriber_id);
+        let abandoned_subscription = Subscription::new(&Uuid::new_v4(), "Abandoned Subscription");
+        // Logged before sequence numbers were introduced
+        let publication = Publication::new(&subscription.id, 0, b"Test Publication");
+        subscription.retained = Some(publication.publication_id);
+        for s in &[&subscription, &abandoned_subscription] {
+            store.write_metadata(s).unwrap();
+        }
+        store.append(&publication).unwrap();
+        drop(store);
+        let mut recovered_logger = DataLogger::new(&test_dir).unwrap();
+        assert_eq!(
+            recovered_logger.log_index[&subscription.id].get(&publication.publication_id),
+            Some((1, 0))
+        );
+        let appended_publication = Publication::new(&subscription.id, 2, b"Appended Publication");
+        recovered_logger
+            .append_publication(&appended_publication)
+            .unwrap();
+        drop(recovered_logger);
+        let recovered_logger = DataLogger::new(&test_dir).unwrap();
+        for (sequence, p) in (1..).zip(&[&publication, &appended_publication]) {
+            let recovered_publication = recovered_logger
+                .read_publication(&subscription.id, &p.publication_id)
+                .unwrap();
+            assert_eq!(recovered_publication.sequence, sequence);
+            assert_eq!(recovered_publication.data, p.data);
+        }
+        assert_eq!(
+            recovered_logger.log_index[&subscription.id].publications_since(Some(1)),
+            vec![(2, appended_publication.publication_id)]
+                .into_iter()
+                .collect()
+        );
+        let recovered_state = recovered_logger.recover_state().unwrap();
+        assert_eq!(recovered_state.subscriptions, vec![subscription.clone()]);
+        assert_eq!(recovered_state.sequences.get(&subscription.id), Some(&2));
+        assert_eq!(
+            recovered_state.retained[&subscription.id].publication_id,
+            publication.publication_id
+        );
+        remove_test_directory(&test_dir);
+    }
+
+    #[test]
+    fn test_recovering_from_damaged_records() {
+        let test_dir = create_test_directory();
+        let segment_config = SegmentConfig {
+            max_segment_bytes: 256,
+            ..SegmentConfig::default()
+        };
+        let mut data_logger = DataLogger::with_segment_config(&test_dir, segment_config).unwrap();
+        let subscription_id = Uuid::new_v4();
+        let publications: Vec<Publication> = (1..=5u64)
+            .map(|sequence| Publication::new(&subscription_id, sequence, b"Publication"))
+            .collect();
+        for publication in &publications {
+            data_logger.append_publication(publication).unwrap();
+        }
+        drop(data_logger);
+        // Damage the first record of the oldest segment
+        let segment_path = test_dir
+            .join("data")
+            .join(subscription_id.to_string())
+            .join("log")
+            .join(format!("{:020}.log", 0));
+        let mut contents = std::fs::read(&segment_path).unwrap();
+        contents[10] ^= 0xff;
+        std::fs::write(&segment_path, &contents).unwrap();
+        let data_logger = DataLogger::with_segment_config(&test_dir, segment_config).unwrap();
+        let recovered: Vec<u64> = data_logger.log_index[&subscription_id]
+            .publications_since(None)
+            .into_keys()
+            .collect();
+        assert_eq!(recovered, vec![2, 3, 4, 5]);
+        assert!(data_logger
+            .read_publication(&subscription_id, &publications[0].publication_id)
+            .is_err());
+        remove_test_directory(&test_dir);
+    }
+
+    #[test]
+    fn test_collection_index_range() {
+        let mut index = CollectionIndex::default();
+        for sequence in 1..=10u64 {
+            let mut publication = Publication::new(&Uuid::new_v4(), sequence, b"");
+            publication.timestamp = sequence * 100;
+            index.insert(sequence, &publication, sequence - 1, 1);
+        }
+        assert_eq!(
+            index.range(LogPosition::Sequence(3), 3, RangeDirection::Forward),
+            (vec![(3, 2), (4, 3), (5, 4)], Some(LogPosition::Sequence(6)))
+        );
+        assert_eq!(
+            index.range(LogPosition::Sequence(2), 3, RangeDirection::Backward),
+            (vec![(2, 1), (1, 0)], None)
+        );
+        assert_eq!(
+            index.range(LogPosition::Timestamp(850), 2, RangeDirection::Forward),
+            (vec![(9, 8), (10, 9)], None)
+        );
+        assert_eq!(
+            index.range(LogPosition::Timestamp(850), 2, RangeDirection::Backward),
+            (vec![(8, 7), (7, 6)], Some(LogPosition::Sequence(6)))
+        );
+    }
+
+    #[test]
+    fn test_collection_index_expiry() {
+        let mut index = CollectionIndex::default();
+        for sequence in 1..=6u64 {
+            let mut publication = Publication::new(&Uuid::new_v4(), sequence, b"");
+            publication.timestamp = sequence * 1000;
+            publication.key = Some(format!("key-{}", sequence % 2)).filter(|_| sequence < 5);
+            index.insert(sequence, &publication, sequence - 1, 10);
+        }
+        let policy = |f: fn(&mut RetentionPolicy)| {
+            let mut policy = RetentionPolicy::default();
+            f(&mut policy);
+            policy
+        };
+        let expired = |policy: RetentionPolicy| -> Vec<u64> {
+            index.expired(&policy, 6500).into_iter().collect()
+        };
+        assert_eq!(expired(RetentionPolicy::default()), Vec::<u64>::new());
+        assert_eq!(
+            expired(policy(|p| p.max_age = Some(Duration::from_secs(3)))),
+            vec![1, 2, 3]
+        );
+        assert_eq!(expired(policy(|p| p.max_count = Some(4))), vec![1, 2]);
+        assert_eq!(expired(policy(|p| p.max_bytes = Some(35))), vec![1, 2, 3]);
+        assert_eq!(expired(policy(|p| p.compact_by_key = true)), vec![1, 2]);
+        assert_eq!(
+            expired(policy(|p| {
+                p.compact_by_key = true;
+                p.max_count = Some(3);
+            })),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_applying_retention_policies() {
+        let test_dir = create_test_directory();
+        let segment_config = SegmentConfig {
+            max_segment_bytes: 256,
+            index_interval_bytes: 64,
+            ..SegmentConfig::default()
+        };
+        let mut data_logger = DataLogger::with_segment_config(&test_dir, segment_config).unwrap();
+        let subscription_id = Uuid::new_v4();
+        let mut publications = Vec::new();
+        for sequence in 1..=20u64 {
+            let mut publication = Publication::new(&subscription_id, sequence, b"Publication");
+            publication.timestamp = sequence;
+            publication.key = Some(format!("key-{}", sequence % 5)).filter(|_| sequence != 10);
+            data_logger.append_publication(&publication).unwrap();
+            publications.push(publication);
+        }
+        let truncation = RetentionPolicy {
+            max_count: Some(12),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(
+            data_logger
+                .apply_retention(&subscription_id, &truncation, 0)
+                .unwrap(),
+            8
+        );
+        assert!(data_logger
+            .read_publication(&subscription_id, &publications[7].publication_id)
+            .is_err());
+        let compaction = RetentionPolicy {
+            compact_by_key: true,
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(
+            data_logger
+                .apply_retention(&subscription_id, &compaction, 0)
+                .unwrap(),
+            6
+        );
+        let retained: Vec<u64> = data_logger.log_index[&subscription_id]
+            .publications_since(None)
+            .into_keys()
+            .collect();
+        assert_eq!(retained, vec![10, 16, 17, 18, 19, 20]);
+        // The index is kept up to date without scanning the rewritten log
+        assert_eq!(
+            data_logger.log_index[&subscription_id],
+            data_logger.build_index(&subscription_id).unwrap()
+        );
+        drop(data_logger);
+        let data_logger = DataLogger::with_segment_config(&test_dir, segment_config).unwrap();
+        for publication in publications[15..].iter().chain(&publications[9..10]) {
+            let recovered = data_logger
+                .read_publication(&subscription_id, &publication.publication_id)
+                .unwrap();
+            assert_eq!(&recovered, publication);
+        }
+        remove_test_directory(&test_dir);
+    }
+
+    #[test]
+    fn test_applying_retention_policies_in_memory() {
+        let mut data_logger = DataLogger::ephemeral();
+        let subscription_id = Uuid::new_v4();
+        for sequence in 1..=10u64 {
+            let mut publication = Publication::new(&subscription_id, sequence, b"Publication");
+            publication.key = Some(format!("key-{}", sequence % 2));
+            data_logger.append_publication(&publication).unwrap();
+        }
+        let policy = RetentionPolicy {
+            compact_by_key: true,
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(
+            data_logger
+                .apply_retention(&subscription_id, &policy, 0)
+                .unwrap(),
+            8
+        );
+        assert_eq!(
+            data_logger.log_index[&subscription_id].last_sequence(),
+            Some(10)
+        );
+    }
+
+    #[test]
+    fn test_keeping_sequence_numbers_after_retention() {
+        let test_dir = create_test_directory();
+        let (emptied_id, legacy_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut store = FileSystemStore::open(&test_dir, SegmentConfig::default()).unwrap();
+        for _ in 1..=3 {
+            // Logged before sequence numbers were introduced
+            let publication = Publication::new(&legacy_id, 0, b"Legacy Publication");
+            store.append(&publication).unwrap();
+        }
+        drop(store);
+        let mut data_logger = DataLogger::new(&test_dir).unwrap();
+        for sequence in 1..=3u64 {
+            let mut publication = Publication::new(&emptied_id, sequence, b"Publication");
+            publication.timestamp = sequence;
+            data_logger.append_publication(&publication).unwrap();
+        }
+        let expiry = RetentionPolicy {
+            max_age: Some(Duration::from_millis(1)),
+            ..RetentionPolicy::default()
+        };
+        let truncation = RetentionPolicy {
+            max_count: Some(2),
+            ..RetentionPolicy::default()
+        };
+        data_logger
+            .apply_retention(&emptied_id, &expiry, 10)
+            .unwrap();
+        data_logger
+            .apply_retention(&legacy_id, &truncation, 0)
+            .unwrap();
+        drop(data_logger);
         let data_logger = DataLogger::new(&test_dir).unwrap();
-        let data_logger_actor = data_logger.clone().start();
-        let mut test_data_dir = PathBuf::from(&test_dir);
-        test_data_dir.push("data");
-        assert_eq!(data_logger.data_dir, test_data_dir);
-        assert!(data_logger_actor.connected());
-        remove_test_directory(&test_data_dir);
+        assert_eq!(data_logger.log_index[&emptied_id].last_sequence(), None);
+        let legacy_sequences: Vec<u64> = data_logger.log_index[&legacy_id]
+            .entries
+            .keys()
+            .copied()
+            .collect();
+        assert_eq!(legacy_sequences, vec![2, 3]);
+        let recovered_state = data_logger.recover_state().unwrap();
+        assert_eq!(recovered_state.sequences.get(&emptied_id), Some(&3));
+        assert_eq!(recovered_state.sequences.get(&legacy_id), Some(&3));
+        remove_test_directory(&test_dir);
     }
 
     #[actix_rt::test]
@@ -0,0 +1,692 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Debug;
+use std::fs::{create_dir_all, read, read_dir, remove_file, DirEntry};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::{str::FromStr, time::SystemTime};
+
+use faccess::{AccessMode, PathExt};
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Serialize};
+use uuid::Uuid;
+
+use crate::data_log::{DataLogError, LogPosition, LogRange, RangeDirection};
+use crate::pubsub::{Publication, Subscription};
+use crate::segment_log::{write_atomically, SegmentConfig, SegmentedLog};
+
+const METADATA_FILE: &str = "metadata.cbor";
+const SEQUENCE_MARK_FILE: &str = "sequence.cbor";
+/// Marks data files starting with a checksum of their contents
+const DATA_FILE_MAGIC: &[u8] = b"ITC1";
+
+/// Storage for the publications and subscription metadata handled by a `DataLogger`.
+/// Publications are kept in one log per collection and addressed by the offset
+/// the store assigned when appending them. Offsets grow with every append.
+pub trait DataStore: Debug {
+    /// Returns the ids of all collections holding publications
+    fn collections(&self) -> Result<Vec<Uuid>, DataLogError>;
+
+    /// Appends a publication to the log of its collection.
+    /// Returns the offset it is stored at and the number of bytes it occupies.
+    fn append(&mut self, publication: &Publication) -> Result<(u64, u64), DataLogError>;
+
+    /// Reads the publication stored at `offset` in the log of a collection
+    fn read(&self, data_log_id: &Uuid, offset: u64) -> Result<Publication, DataLogError>;
+
+    /// Reads all publications of a collection, oldest first,
+    /// along with their offsets and the number of bytes they occupy.
+    /// Publications that are damaged or can not be decoded are skipped.
+    fn scan(&self, data_log_id: &Uuid) -> Result<Vec<(u64, u64, Publication)>, DataLogError>;
+
+    /// Reads up to `limit` publications of a collection from `start` on in `direction`,
+    /// along with the position of the publication that follows them, if there is one.
+    /// Stores that can't select ranges themselves return `None`,
+    /// the `DataLogger` then selects them from its log index.
+    fn read_range(
+        &self,
+        _data_log_id: &Uuid,
+        _start: LogPosition,
+        _limit: usize,
+        _direction: RangeDirection,
+    ) -> Result<Option<LogRange>, DataLogError> {
+        Ok(None)
+    }
+
+    /// Removes the publications stored at `offsets` from the log of a collection.
+    /// Offsets of the remaining publications may change: the new offset and size
+    /// of each publication that was moved are returned, keyed by its previous offset.
+    fn remove(
+        &mut self,
+        data_log_id: &Uuid,
+        offsets: &BTreeSet<u64>,
+    ) -> Result<HashMap<u64, (u64, u64)>, DataLogError>;
+
+    /// Reads the metadata of a single subscription
+    fn read_metadata(&self, subscription_id: &Uuid) -> Result<Subscription, DataLogError>;
+
+    /// Reads the metadata of every subscription
+    fn read_all_metadata(&self) -> Result<Vec<Subscription>, DataLogError>;
+
+    /// Stores the metadata of a subscription, replacing what was stored before
+    fn write_metadata(&mut self, subscription: &Subscription) -> Result<(), DataLogError>;
+
+    /// Removes the metadata of a subscription. Removing missing metadata is not an error.
+    fn remove_metadata(&mut self, subscription_id: &Uuid) -> Result<(), DataLogError>;
+
+    /// Reads the recorded sequence mark of every collection, the highest sequence number
+    /// ever logged to it. Marks outlive the publications they were taken from.
+    fn read_sequence_marks(&self) -> Result<HashMap<Uuid, u64>, DataLogError>;
+
+    /// Records the highest sequence number logged to a collection, replacing its previous mark
+    fn write_sequence_mark(
+        &mut self,
+        data_log_id: &Uuid,
+        sequence: u64,
+    ) -> Result<(), DataLogError>;
+}
+
+/// A `DataStore` keeping everything in memory. Nothing survives a restart.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    logs: HashMap<Uuid, MemoryLog>,
+    metadata: HashMap<Uuid, Subscription>,
+    sequence_marks: HashMap<Uuid, u64>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryLog {
+    entries: BTreeMap<u64, (u64, Publication)>,
+    next_offset: u64,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl DataStore for MemoryStore {
+    fn collections(&self) -> Result<Vec<Uuid>, DataLogError> {
+        Ok(self.logs.keys().copied().collect())
+    }
+
+    fn append(&mut self, publication: &Publication) -> Result<(u64, u64), DataLogError> {
+        let size = serde_cbor::to_vec(publication)
+            .map_err(DataLogError::WriteError)?
+            .len() as u64;
+        let log = self.logs.entry(publication.subscription_id).or_default();
+        let offset = log.next_offset;
+        log.entries.insert(offset, (size, publication.clone()));
+        log.next_offset += 1;
+        Ok((offset, size))
+    }
+
+    fn read(&self, data_log_id: &Uuid, offset: u64) -> Result<Publication, DataLogError> {
+        self.logs
+            .get(data_log_id)
+            .and_then(|log| log.entries.get(&offset))
+            .map(|(_, publication)| publication.clone())
+            .ok_or(DataLogError::RecordNotFound(offset))
+    }
+
+    fn scan(&self, data_log_id: &Uuid) -> Result<Vec<(u64, u64, Publication)>, DataLogError> {
+        Ok(self
+            .logs
+            .get(data_log_id)
+            .map(|log| {
+                log.entries
+                    .iter()
+                    .map(|(offset, (size, publication))| (*offset, *size, publication.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn remove(
+        &mut self,
+        data_log_id: &Uuid,
+        offsets: &BTreeSet<u64>,
+    ) -> Result<HashMap<u64, (u64, u64)>, DataLogError> {
+        if let Some(log) = self.logs.get_mut(data_log_id) {
+            log.entries.retain(|offset, _| !offsets.contains(offset));
+        }
+        Ok(HashMap::new())
+    }
+
+    fn read_metadata(&self, subscription_id: &Uuid) -> Result<Subscription, DataLogError> {
+        self.metadata
+            .get(subscription_id)
+            .cloned()
+            .ok_or(DataLogError::MetadataNotFound(*subscription_id))
+    }
+
+    fn read_all_metadata(&self) -> Result<Vec<Subscription>, DataLogError> {
+        Ok(self.metadata.values().cloned().collect())
+    }
+
+    fn write_metadata(&mut self, subscription: &Subscription) -> Result<(), DataLogError> {
+        self.metadata.insert(subscription.id, subscription.clone());
+        Ok(())
+    }
+
+    fn remove_metadata(&mut self, subscription_id: &Uuid) -> Result<(), DataLogError> {
+        self.metadata.remove(subscription_id);
+        Ok(())
+    }
+
+    fn read_sequence_marks(&self) -> Result<HashMap<Uuid, u64>, DataLogError> {
+        Ok(self.sequence_marks.clone())
+    }
+
+    fn write_sequence_mark(
+        &mut self,
+        data_log_id: &Uuid,
+        sequence: u64,
+    ) -> Result<(), DataLogError> {
+        self.sequence_marks.insert(*data_log_id, sequence);
+        Ok(())
+    }
+}
+
+/// A `DataStore` keeping collections in the `data` directory below the application directory.
+/// Publications of each collection are stored in a `SegmentedLog` under `data/<id>/log`,
+/// subscription metadata in `data/<id>/metadata.cbor` and its sequence mark in `data/<id>/sequence.cbor`.
+#[derive(Debug)]
+pub struct FileSystemStore {
+    logs: HashMap<Uuid, SegmentedLog>,
+    data_dir: PathBuf,
+    segment_config: SegmentConfig,
+}
+
+impl FileSystemStore {
+    ///Opens the store in `app_dir`, opening any collection logs already present
+    ///and migrating logs written in the previous one-file-per-publication layout.
+    ///## Arguments
+    ///* `app_dir` - The application base directory. Must exist and be accessible with rwx permissions.
+    ///* `segment_config` - Size limits for segment files and their index
+    pub fn open(
+        app_dir: &Path,
+        segment_config: SegmentConfig,
+    ) -> Result<FileSystemStore, DataLogError> {
+        if app_dir
+            .access(AccessMode::EXISTS | AccessMode::READ | AccessMode::WRITE | AccessMode::EXECUTE)
+            .is_err()
+        {
+            return Err(DataLogError::FileSystem(String::from(
+                "Could not access application base directory with required permissions",
+            )));
+        }
+        let data_dir = app_dir.join("data");
+        create_dir_all(&data_dir)?;
+        let mut store = FileSystemStore {
+            logs: HashMap::new(),
+            data_dir,
+            segment_config,
+        };
+        store.open_logs()?;
+        Ok(store)
+    }
+
+    /// The directory holding the collections
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// Opens the log of every collection in the data directory
+    fn open_logs(&mut self) -> Result<(), DataLogError> {
+        for data_log_id in self.list_entry_ids(&self.data_dir, |e| e.path().is_dir())? {
+            let log_path = self.get_collection_log_path(&data_log_id);
+            if !SegmentedLog::exists(&log_path) {
+                continue;
+            }
+            let mut log = SegmentedLog::open(&log_path, self.segment_config)?;
+            self.migrate_legacy_log(&log_path, &mut log)?;
+            self.logs.insert(data_log_id, log);
+        }
+        Ok(())
+    }
+
+    /// Moves publications stored in the previous layout, one file per publication
+    /// named by its id, into the segmented log, oldest file first.
+    /// Migrated publications are numbered in that order, continuing after the publications
+    /// already in the log. A legacy file is only removed after its publication was appended,
+    /// so an interrupted migration is resumed by skipping the publications logged already.
+    fn migrate_legacy_log(
+        &self,
+        log_path: &Path,
+        log: &mut SegmentedLog,
+    ) -> Result<(), DataLogError> {
+        let mut legacy_entries = Vec::new();
+        for entry_id in self.list_entry_ids(log_path, |e| e.path().is_file())? {
+            let modified = log_path
+                .join(entry_id.to_string())
+                .metadata()?
+                .modified()
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            legacy_entries.push((modified, entry_id));
+        }
+        if legacy_entries.is_empty() {
+            return Ok(());
+        }
+        legacy_entries.sort();
+        info!(
+            "Migrating {} publications in {:?} to segmented log",
+            legacy_entries.len(),
+            log_path
+        );
+        let mut last_sequence = 0;
+        let mut migrated = HashSet::new();
+        for (_, record) in log.scan()? {
+            if let Ok(publication) = serde_cbor::from_slice::<Publication>(&record) {
+                last_sequence = last_sequence.max(publication.sequence);
+                migrated.insert(publication.publication_id);
+            }
+        }
+        for (_, entry_id) in legacy_entries {
+            let filename = entry_id.to_string();
+            let mut publication: Publication = self.read_data_file(&filename, log_path)?;
+            if !migrated.contains(&publication.publication_id) {
+                last_sequence += 1;
+                publication.sequence = last_sequence;
+                log.append(&serde_cbor::to_vec(&publication).map_err(DataLogError::WriteError)?)?;
+            }
+            remove_file(log_path.join(filename))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the log of a collection, opening it if it is not open yet
+    fn get_collection_log(
+        &mut self,
+        data_log_id: &Uuid,
+    ) -> Result<&mut SegmentedLog, DataLogError> {
+        if !self.logs.contains_key(data_log_id) {
+            let log_path = self.get_collection_log_path(data_log_id);
+            let log = SegmentedLog::open(&log_path, self.segment_config)?;
+            self.logs.insert(*data_log_id, log);
+        }
+        Ok(self.logs.get_mut(data_log_id).unwrap())
+    }
+
+    fn get_collection_path(&self, data_log_id: &Uuid) -> PathBuf {
+        self.data_dir.join(data_log_id.to_string())
+    }
+
+    fn get_collection_log_path(&self, data_log_id: &Uuid) -> PathBuf {
+        let mut path = self.get_collection_path(data_log_id);
+        path.push("log");
+        path
+    }
+
+    fn list_entry_ids<P: AsRef<Path>, F: Fn(&DirEntry) -> bool>(
+        &self,
+        path: P,
+        condition: F,
+    ) -> Result<Vec<Uuid>, DataLogError> {
+        let mut results = Vec::new();
+        let entries = read_dir(path)?;
+        for entry in entries {
+            let dir_entry = entry?;
+            if condition(&dir_entry) {
+                if let Some(dir_name) = dir_entry.file_name().to_str() {
+                    if let Ok(collection_id) = Uuid::from_str(dir_name) {
+                        results.push(collection_id)
+                    }
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    /// Reads a data file, verifying its checksum.
+    /// Files written before checksums were introduced are plain CBOR and read as such.
+    fn read_data_file<T: Serialize + DeserializeOwned>(
+        &self,
+        filename: &str,
+        path: &Path,
+    ) -> Result<T, DataLogError> {
+        let file_path = path.join(filename);
+        let contents = read(&file_path)?;
+        let data = match contents.strip_prefix(DATA_FILE_MAGIC) {
+            Some(checked) if checked.len() >= 4 => {
+                let (checksum, data) = checked.split_at(4);
+                if crc32fast::hash(data).to_be_bytes() != checksum {
+                    return Err(DataLogError::ChecksumMismatch(format!("{:?}", file_path)));
+                }
+                data
+            }
+            Some(_) => return Err(DataLogError::ChecksumMismatch(format!("{:?}", file_path))),
+            None => &contents[..],
+        };
+        serde_cbor::from_slice(data).map_err(DataLogError::ReadError)
+    }
+
+    /// Writes a data file atomically, prefixed with a checksum of its contents
+    fn write_data_file<T: Serialize>(
+        &self,
+        filename: &str,
+        path: &Path,
+        data: T,
+    ) -> Result<(), DataLogError> {
+        create_dir_all(path)?;
+        let data = serde_cbor::to_vec(&data).map_err(DataLogError::WriteError)?;
+        let mut contents = Vec::with_capacity(DATA_FILE_MAGIC.len() + 4 + data.len());
+        contents.extend_from_slice(DATA_FILE_MAGIC);
+        contents.extend_from_slice(&crc32fast::hash(&data).to_be_bytes());
+        contents.extend_from_slice(&data);
+        write_atomically(&path.join(filename), &contents)
+    }
+}
+
+impl DataStore for FileSystemStore {
+    fn collections(&self) -> Result<Vec<Uuid>, DataLogError> {
+        Ok(self.logs.keys().copied().collect())
+    }
+
+    fn append(&mut self, publication: &Publication) -> Result<(u64, u64), DataLogError> {
+        let record = serde_cbor::to_vec(publication).map_err(DataLogError::WriteError)?;
+        let offset = self
+            .get_collection_log(&publication.subscription_id)?
+            .append(&record)?;
+        Ok((offset, record.len() as u64))
+    }
+
+    fn read(&self, data_log_id: &Uuid, offset: u64) -> Result<Publication, DataLogError> {
+        let record = self
+            .logs
+            .get(data_log_id)
+            .ok_or(DataLogError::RecordNotFound(offset))?
+            .read(offset)?;
+        serde_cbor::from_slice(&record).map_err(DataLogError::ReadError)
+    }
+
+    fn scan(&self, data_log_id: &Uuid) -> Result<Vec<(u64, u64, Publication)>, DataLogError> {
+        let log = match self.logs.get(data_log_id) {
+            Some(log) => log,
+            None => return Ok(Vec::new()),
+        };
+        let mut publications = Vec::new();
+        for (offset, record) in log.scan()? {
+            match serde_cbor::from_slice(&record) {
+                Ok(publication) => publications.push((offset, record.len() as u64, publication)),
+                Err(e) => warn!(
+                    "Skipping record {} of collection {}: {}",
+                    offset, data_log_id, e
+                ),
+            }
+        }
+        Ok(publications)
+    }
+
+    /// Truncates the log if only its oldest publications are removed,
+    /// otherwise rewrites it without the removed publications
+    fn remove(
+        &mut self,
+        data_log_id: &Uuid,
+        offsets: &BTreeSet<u64>,
+    ) -> Result<HashMap<u64, (u64, u64)>, DataLogError> {
+        let log = self.get_collection_log(data_log_id)?;
+        let first_retained = (log.first_offset()..log.next_offset()).find(|o| !offsets.contains(o));
+        let is_prefix = offsets
+            .iter()
+            .all(|o| first_retained.map_or(true, |f| *o < f));
+        if is_prefix {
+            let truncate_at = first_retained.unwrap_or_else(|| log.next_offset());
+            log.truncate_before(truncate_at)?;
+            return Ok(HashMap::new());
+        }
+        // The rewritten log assigns offsets to the retained publications in order
+        let mut moved = HashMap::new();
+        log.rewrite(|offset, record| {
+            let mut publication: Publication =
+                serde_cbor::from_slice(&record).map_err(DataLogError::ReadError)?;
+            // Publications logged before sequence numbers were introduced are numbered
+            // by their offset, which the rewrite would change. Their numbers are made explicit.
+            if publication.sequence == 0 {
+                publication.sequence = offset + 1;
+            }
+            if offsets.contains(&offset) {
+                return Ok(None);
+            }
+            let record = serde_cbor::to_vec(&publication).map_err(DataLogError::WriteError)?;
+            moved.insert(offset, (moved.len() as u64, record.len() as u64));
+            Ok(Some(record))
+        })?;
+        Ok(moved)
+    }
+
+    fn read_metadata(&self, subscription_id: &Uuid) -> Result<Subscription, DataLogError> {
+        let collection_path = self.get_collection_path(subscription_id);
+        if !collection_path.join(METADATA_FILE).is_file() {
+            return Err(DataLogError::MetadataNotFound(*subscription_id));
+        }
+        self.read_data_file(METADATA_FILE, &collection_path)
+    }
+
+    /// Reads the metadata of every collection in the data directory that has any,
+    /// skipping records that can not be decoded.
+    fn read_all_metadata(&self) -> Result<Vec<Subscription>, DataLogError> {
+        let mut subscriptions = Vec::new();
+        for subscription_id in self.list_entry_ids(&self.data_dir, |e| e.path().is_dir())? {
+            let collection_path = self.get_collection_path(&subscription_id);
+            if !collection_path.join(METADATA_FILE).is_file() {
+                continue;
+            }
+            match self.read_data_file::<Subscription>(METADATA_FILE, &collection_path) {
+                Ok(subscription) => subscriptions.push(subscription),
+                Err(e) => warn!(
+                    "Skipping metadata of subscription {}: {}",
+                    subscription_id, e
+                ),
+            }
+        }
+        Ok(subscriptions)
+    }
+
+    fn write_metadata(&mut self, subscription: &Subscription) -> Result<(), DataLogError> {
+        let collection_path = self.get_collection_path(&subscription.id);
+        self.write_data_file(METADATA_FILE, &collection_path, subscription)
+    }
+
+    fn remove_metadata(&mut self, subscription_id: &Uuid) -> Result<(), DataLogError> {
+        let collection_path = self.get_collection_path(subscription_id);
+        match remove_file(collection_path.join(METADATA_FILE)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn read_sequence_marks(&self) -> Result<HashMap<Uuid, u64>, DataLogError> {
+        let mut marks = HashMap::new();
+        for data_log_id in self.list_entry_ids(&self.data_dir, |e| e.path().is_dir())? {
+            let collection_path = self.get_collection_path(&data_log_id);
+            if collection_path.join(SEQUENCE_MARK_FILE).is_file() {
+                let sequence = self.read_data_file(SEQUENCE_MARK_FILE, &collection_path)?;
+                marks.insert(data_log_id, sequence);
+            }
+        }
+        Ok(marks)
+    }
+
+    fn write_sequence_mark(
+        &mut self,
+        data_log_id: &Uuid,
+        sequence: u64,
+    ) -> Result<(), DataLogError> {
+        let collection_path = self.get_collection_path(data_log_id);
+        self.write_data_file(SEQUENCE_MARK_FILE, &collection_path, sequence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{create_test_directory, remove_test_directory};
+
+    /// Writes a publication to its own file as plain CBOR, like the one-file-per-publication layout did
+    fn write_legacy_file(log_path: &Path, publication: &Publication) {
+        std::fs::create_dir_all(log_path).unwrap();
+        let file = std::fs::File::create(log_path.join(publication.publication_id.to_string()));
+        serde_cbor::to_writer(file.unwrap(), publication).unwrap();
+    }
+
+    /// Runs the same operations against any store
+    fn exercise_store(store: &mut dyn DataStore) {
+        let mut subscription = Subscription::new(&Uuid::new_v4(), "Test Subscription");
+        store.write_metadata(&subscription).unwrap();
+        subscription.append_subscriber(&Uuid::new_v4());
+        store.write_metadata(&subscription).unwrap();
+        assert_eq!(store.read_metadata(&subscription.id).unwrap(), subscription);
+        assert_eq!(
+            store.read_all_metadata().unwrap(),
+            vec![subscription.clone()]
+        );
+        assert!(matches!(
+            store.read_metadata(&Uuid::new_v4()),
+            Err(DataLogError::MetadataNotFound(_))
+        ));
+        let removed = Subscription::new(&Uuid::new_v4(), "Removed Subscription");
+        store.write_metadata(&removed).unwrap();
+        store.remove_metadata(&removed.id).unwrap();
+        store.remove_metadata(&removed.id).unwrap();
+        assert!(matches!(
+            store.read_metadata(&removed.id),
+            Err(DataLogError::MetadataNotFound(_))
+        ));
+
+        let publications: Vec<Publication> = (1..=5)
+            .map(|sequence| Publication::new(&subscription.id, sequence, b"Test Publication"))
+            .collect();
+        for publication in &publications {
+            store.append(publication).unwrap();
+        }
+        assert_eq!(store.collections().unwrap(), vec![subscription.id]);
+        let scanned = store.scan(&subscription.id).unwrap();
+        assert_eq!(scanned.len(), 5);
+        assert_eq!(
+            store.read(&subscription.id, scanned[2].0).unwrap(),
+            publications[2]
+        );
+
+        let removed: BTreeSet<u64> = [scanned[0].0, scanned[3].0].iter().copied().collect();
+        let moved = store.remove(&subscription.id, &removed).unwrap();
+        let remaining = store.scan(&subscription.id).unwrap();
+        let sequences: Vec<u64> = remaining.iter().map(|(_, _, p)| p.sequence).collect();
+        assert_eq!(sequences, vec![2, 3, 5]);
+        // Publications are found where the store reported moving them
+        let locations: Vec<(u64, u64)> = scanned
+            .iter()
+            .filter(|(offset, _, _)| !removed.contains(offset))
+            .map(|(offset, size, _)| moved.get(offset).copied().unwrap_or((*offset, *size)))
+            .collect();
+        let remaining: Vec<(u64, u64)> = remaining
+            .iter()
+            .map(|(offset, size, _)| (*offset, *size))
+            .collect();
+        assert_eq!(remaining, locations);
+        store.write_sequence_mark(&subscription.id, 4).unwrap();
+        store.write_sequence_mark(&subscription.id, 5).unwrap();
+        assert_eq!(
+            store.read_sequence_marks().unwrap(),
+            vec![(subscription.id, 5)].into_iter().collect()
+        );
+        assert!(matches!(
+            store.read(&Uuid::new_v4(), 0),
+            Err(DataLogError::RecordNotFound(0))
+        ));
+    }
+
+    #[test]
+    fn test_memory_store() {
+        exercise_store(&mut MemoryStore::new());
+    }
+
+    #[test]
+    fn test_file_system_store() {
+        let test_dir = create_test_directory();
+        let mut store = FileSystemStore::open(&test_dir, SegmentConfig::default()).unwrap();
+        assert_eq!(store.data_dir(), test_dir.join("data"));
+        exercise_store(&mut store);
+        remove_test_directory(&test_dir);
+    }
+
+    #[test]
+    fn test_migrating_legacy_logs() {
+        let test_dir = create_test_directory();
+        let store = FileSystemStore::open(&test_dir, SegmentConfig::default()).unwrap();
+        let subscription_id = Uuid::new_v4();
+        let publication = Publication::new(&subscription_id, 0, b"Test Publication");
+        let log_path = store.get_collection_log_path(&subscription_id);
+        write_legacy_file(&log_path, &publication);
+        let store = FileSystemStore::open(&test_dir, SegmentConfig::default()).unwrap();
+        assert!(!log_path
+            .join(publication.publication_id.to_string())
+            .exists());
+        let scanned = store.scan(&subscription_id).unwrap();
+        assert_eq!(scanned.len(), 1);
+        assert_eq!(scanned[0].2.sequence, 1);
+        assert_eq!(scanned[0].2.data, publication.data);
+        remove_test_directory(&test_dir);
+    }
+
+    #[test]
+    fn test_resuming_interrupted_migration() {
+        let test_dir = create_test_directory();
+        let store = FileSystemStore::open(&test_dir, SegmentConfig::default()).unwrap();
+        let subscription_id = Uuid::new_v4();
+        let log_path = store.get_collection_log_path(&subscription_id);
+        let migrated = Publication::new(&subscription_id, 0, b"Migrated Publication");
+        let pending = Publication::new(&subscription_id, 0, b"Pending Publication");
+        write_legacy_file(&log_path, &migrated);
+        drop(FileSystemStore::open(&test_dir, SegmentConfig::default()).unwrap());
+        // Interrupted after appending the first publication, before removing its file
+        write_legacy_file(&log_path, &migrated);
+        write_legacy_file(&log_path, &pending);
+        let store = FileSystemStore::open(&test_dir, SegmentConfig::default()).unwrap();
+        let scanned: Vec<(u64, Uuid)> = store
+            .scan(&subscription_id)
+            .unwrap()
+            .into_iter()
+            .map(|(_, _, p)| (p.sequence, p.publication_id))
+            .collect();
+        assert_eq!(
+            scanned,
+            vec![(1, migrated.publication_id), (2, pending.publication_id)]
+        );
+        assert!(store
+            .list_entry_ids(&log_path, |e| e.path().is_file())
+            .unwrap()
+            .is_empty());
+        remove_test_directory(&test_dir);
+    }
+
+    #[test]
+    fn test_verifying_metadata_checksums() {
+        let test_dir = create_test_directory();
+        let mut store = FileSystemStore::open(&test_dir, SegmentConfig::default()).unwrap();
+        let subscription = Subscription::new(&Uuid::new_v4(), "Test Subscription");
+        let metadata_path = store
+            .get_collection_path(&subscription.id)
+            .join(METADATA_FILE);
+        // Written before checksums were introduced
+        std::fs::create_dir_all(metadata_path.parent().unwrap()).unwrap();
+        std::fs::write(&metadata_path, serde_cbor::to_vec(&subscription).unwrap()).unwrap();
+        assert_eq!(store.read_metadata(&subscription.id).unwrap(), subscription);
+        store.write_metadata(&subscription).unwrap();
+        assert_eq!(store.read_metadata(&subscription.id).unwrap(), subscription);
+        let mut contents = std::fs::read(&metadata_path).unwrap();
+        *contents.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&metadata_path, &contents).unwrap();
+        assert!(matches!(
+            store.read_metadata(&subscription.id),
+            Err(DataLogError::ChecksumMismatch(_))
+        ));
+        remove_test_directory(&test_dir);
+    }
+
+    #[test]
+    fn test_opening_file_system_store_failure() {
+        let store = FileSystemStore::open(Path::new("/frank/nord"), SegmentConfig::default());
+        assert!(store.is_err());
+    }
+}
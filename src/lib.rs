@@ -23,6 +23,18 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
+pub mod auth;
 pub mod data_log;
+pub mod data_store;
+pub mod long_poll;
+pub mod protocol;
 pub mod pubsub;
+pub mod rest;
+pub mod segment_log;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_store;
+pub mod sse;
+#[cfg(test)]
+mod test_utils;
+pub mod topic;
 pub mod websocket;
@@ -0,0 +1,615 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use actix::prelude::{
+    Actor, ActorContext, Addr, AsyncContext, Context, Handler, Message, ResponseFuture, Running,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures_channel::oneshot;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    auth::AuthError,
+    pubsub::{
+        AckCommand, DeliveryMode, Issue, ManageSession, ManageSubscription, PubSubService,
+        Publication, PublicationError, QueueStrategy, SessionHandle, SubscriptionKind,
+    },
+    rest::identify_client,
+    websocket::ErrorResponse,
+};
+
+/// Time a poll waits for publications if the request doesn't name one
+pub const DEFAULT_POLL_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest time a poll is allowed to wait for publications
+pub const MAX_POLL_TIMEOUT: Duration = Duration::from_secs(120);
+/// Time after its last poll a poll session is closed, unless it is polled again
+const SESSION_EXPIRY: Duration = Duration::from_secs(300);
+/// Interval at which poll sessions check whether they expired
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Number of publications, and of issues, a poll session keeps between polls.
+/// Beyond that the oldest are dropped unacknowledged, so those that have to be
+/// acknowledged are redelivered later.
+const MAX_PENDING: usize = 1024;
+
+/// Registers the routes of the long-poll transport, e.g. `App::new().service(web::scope("/api").configure(configure))`.
+/// They expect `web::Data<Addr<PubSubService>>` and `web::Data<Addr<LongPollService>>` to be registered
+/// and identify clients like the HTTP API. Poll sessions belong to a client, so unlike the HTTP API,
+/// the routes refuse anonymous requests and require an `Authenticator`.
+///
+/// * `GET /poll`: publications of the client's subscriptions, see `PollQuery`
+/// * `POST /poll/subscriptions/{id}`: subscribes the client to a subscription, see `SubscribeQuery`
+/// * `DELETE /poll/subscriptions/{id}`: unsubscribes the client from a subscription
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/poll", web::get().to(poll))
+        .route("/poll/subscriptions/{id}", web::post().to(subscribe))
+        .route("/poll/subscriptions/{id}", web::delete().to(unsubscribe));
+}
+
+/// Options of a poll
+#[derive(Debug, Deserialize)]
+pub struct PollQuery {
+    /// Seconds to wait for publications, at most `MAX_POLL_TIMEOUT`
+    pub timeout: Option<u64>,
+}
+
+/// Options of a subscription made by a polling client, see `ManageSubscription::Add`.
+/// Missing subscriptions are only created if `create` is set, broadcast-type
+/// unless a `queue` strategy is given.
+#[derive(Debug, Deserialize)]
+pub struct SubscribeQuery {
+    pub queue: Option<QueueStrategy>,
+    #[serde(default)]
+    pub durable: bool,
+    #[serde(default)]
+    pub create: bool,
+}
+
+/// What a client received since its previous poll. Publications are sent in full,
+/// unless their subscription delivers notifications only.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PollBatch {
+    pub publications: Vec<Publication>,
+    pub issues: Vec<Issue>,
+}
+
+impl PollBatch {
+    fn is_empty(&self) -> bool {
+        self.publications.is_empty() && self.issues.is_empty()
+    }
+}
+
+/// Identifies a polling client, which has to be authenticated
+fn identify_poller(req: &HttpRequest) -> Result<Uuid, AuthError> {
+    identify_client(req)?.ok_or(AuthError::MissingCredentials)
+}
+
+/// Waits until publications arrive for the client's subscriptions or the timeout passes,
+/// then responds with the batch of publications received since the previous poll.
+async fn poll(
+    req: HttpRequest,
+    query: web::Query<PollQuery>,
+    polls: web::Data<Addr<LongPollService>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let client_id = identify_poller(&req)?;
+    let timeout = query
+        .timeout
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_POLL_TIMEOUT)
+        .min(MAX_POLL_TIMEOUT);
+    let batch = polls
+        .send(Poll { client_id, timeout })
+        .await
+        .map_err(ErrorResponse::from)?
+        .map_err(ErrorResponse::from)?;
+    Ok(HttpResponse::Ok().json(batch))
+}
+
+async fn subscribe(
+    req: HttpRequest,
+    subscription_id: web::Path<Uuid>,
+    query: web::Query<SubscribeQuery>,
+    pubsub: web::Data<Addr<PubSubService>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let client_id = identify_poller(&req)?;
+    pubsub
+        .send(ManageSubscription::Add {
+            client_id,
+            subscription_id: *subscription_id,
+            kind: query
+                .queue
+                .map_or(SubscriptionKind::Broadcast, SubscriptionKind::Queue),
+            durable: query.durable,
+            topic: None,
+            create: query.create,
+        })
+        .await
+        .map_err(ErrorResponse::from)?
+        .map_err(ErrorResponse::from)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn unsubscribe(
+    req: HttpRequest,
+    subscription_id: web::Path<Uuid>,
+    pubsub: web::Data<Addr<PubSubService>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let client_id = identify_poller(&req)?;
+    pubsub
+        .send(ManageSubscription::Remove {
+            client_id,
+            subscription_id: *subscription_id,
+        })
+        .await
+        .map_err(ErrorResponse::from)?
+        .map_err(ErrorResponse::from)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Keeps a `PollSession` per polling client. The first poll of a client opens its session,
+/// which receives publications from the `PubSubService` in place of any other session
+/// of the client, until it isn't polled for a while.
+#[derive(Debug)]
+pub struct LongPollService {
+    pubsub: Addr<PubSubService>,
+    sessions: HashMap<Uuid, Addr<PollSession>>,
+}
+
+impl LongPollService {
+    pub fn new(pubsub: &Addr<PubSubService>) -> LongPollService {
+        LongPollService {
+            pubsub: pubsub.clone(),
+            sessions: HashMap::new(),
+        }
+    }
+}
+
+impl Actor for LongPollService {
+    type Context = Context<Self>;
+}
+
+/// A message requesting the publications a client received since its previous poll
+#[derive(Debug, Message)]
+#[rtype("Result<PollBatch, PublicationError>")]
+pub struct Poll {
+    pub client_id: Uuid,
+    pub timeout: Duration,
+}
+
+impl Handler<Poll> for LongPollService {
+    type Result = ResponseFuture<Result<PollBatch, PublicationError>>;
+
+    fn handle(&mut self, msg: Poll, ctx: &mut Context<Self>) -> Self::Result {
+        let session = match self.sessions.get(&msg.client_id) {
+            Some(session) if session.connected() => session.clone(),
+            _ => {
+                info!("Opening poll session of {}", msg.client_id);
+                let session = PollSession::new(msg.client_id, &self.pubsub, &ctx.address()).start();
+                self.sessions.insert(msg.client_id, session.clone());
+                session
+            }
+        };
+        Box::pin(async move {
+            session
+                .send(msg)
+                .await
+                .map_err(|e| PublicationError::SessionService(e.to_string()))?
+        })
+    }
+}
+
+/// Informs the `LongPollService` that the session of a client was closed
+#[derive(Debug, Message)]
+#[rtype("()")]
+struct SessionClosed(Uuid, Addr<PollSession>);
+
+impl Handler<SessionClosed> for LongPollService {
+    type Result = ();
+
+    fn handle(&mut self, msg: SessionClosed, _: &mut Context<Self>) {
+        if self.sessions.get(&msg.0) == Some(&msg.1) {
+            self.sessions.remove(&msg.0);
+        }
+    }
+}
+
+/// The session of a polling client. Buffers what the `PubSubService` delivers between polls
+/// and keeps track of the client's position: the publications handed out by a poll
+/// are acknowledged when the client polls again, so publications that have to be acknowledged
+/// are redelivered if the client doesn't come back for them.
+#[derive(Debug)]
+pub struct PollSession {
+    id: Uuid,
+    pubsub: Addr<PubSubService>,
+    service: Addr<LongPollService>,
+    /// Received since the last poll
+    pending: PollBatch,
+    /// Subscription and publication ids handed out by the last poll, in full or as issues
+    unacknowledged: Vec<(Uuid, Uuid)>,
+    /// The poll waiting for publications, numbered to tell it apart from later polls
+    waiting: Option<(u64, oneshot::Sender<PollBatch>)>,
+    polls: u64,
+    last_poll: Instant,
+}
+
+impl PollSession {
+    pub fn new(
+        id: Uuid,
+        pubsub: &Addr<PubSubService>,
+        service: &Addr<LongPollService>,
+    ) -> PollSession {
+        PollSession {
+            id,
+            pubsub: pubsub.clone(),
+            service: service.clone(),
+            pending: PollBatch::default(),
+            unacknowledged: Vec::new(),
+            waiting: None,
+            polls: 0,
+            last_poll: Instant::now(),
+        }
+    }
+
+    /// Answers the waiting poll, if any, with everything received since the previous one
+    fn respond(&mut self) {
+        if let Some((_, sender)) = self.waiting.take() {
+            let batch = std::mem::take(&mut self.pending);
+            self.unacknowledged = batch
+                .publications
+                .iter()
+                .map(|p| (p.subscription_id, p.publication_id))
+                .chain(batch.issues.iter().map(|i| (i.0, i.1)))
+                .collect();
+            if let Err(batch) = sender.send(batch) {
+                // The client went away, keep the batch for its next poll
+                self.unacknowledged.clear();
+                self.pending = batch;
+            }
+        }
+    }
+}
+
+impl Actor for PollSession {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        // Publications replayed to durable subscribers arrive in full as well
+        self.pubsub.do_send(ManageSession::Add {
+            client_id: self.id,
+            session: SessionHandle::new(&ctx.address()),
+            delivery: Some(DeliveryMode::Full),
+        });
+        ctx.run_interval(EXPIRY_CHECK_INTERVAL, |act, ctx| {
+            if act.waiting.is_none() && act.last_poll.elapsed() > SESSION_EXPIRY {
+                debug!("Poll session of {} expired", act.id);
+                ctx.stop();
+            }
+        });
+    }
+
+    fn stopping(&mut self, ctx: &mut Context<Self>) -> Running {
+        info!("Closing poll session of {}", self.id);
+        self.pubsub.do_send(ManageSession::Remove {
+            client_id: self.id,
+            session: SessionHandle::new(&ctx.address()),
+        });
+        self.service.do_send(SessionClosed(self.id, ctx.address()));
+        Running::Stop
+    }
+}
+
+impl Handler<Poll> for PollSession {
+    type Result = ResponseFuture<Result<PollBatch, PublicationError>>;
+
+    fn handle(&mut self, msg: Poll, ctx: &mut Context<Self>) -> Self::Result {
+        self.last_poll = Instant::now();
+        for (subscription_id, publication_id) in self.unacknowledged.drain(..) {
+            self.pubsub
+                .do_send(AckCommand::new(&self.id, &subscription_id, &publication_id));
+        }
+        // A newer poll of the same client replaces the waiting one, which is answered empty
+        if let Some((_, superseded)) = self.waiting.take() {
+            let _ = superseded.send(PollBatch::default());
+        }
+        self.polls += 1;
+        let (sender, receiver) = oneshot::channel();
+        self.waiting = Some((self.polls, sender));
+        if !self.pending.is_empty() {
+            self.respond();
+        } else {
+            let poll = self.polls;
+            ctx.run_later(msg.timeout, move |act, _| {
+                if act.waiting.as_ref().map_or(false, |(p, _)| *p == poll) {
+                    act.respond();
+                }
+            });
+        }
+        Box::pin(async move {
+            receiver
+                .await
+                .map_err(|_| PublicationError::SessionService(String::from("Poll was cancelled")))
+        })
+    }
+}
+
+impl Handler<Publication> for PollSession {
+    type Result = Result<(), PublicationError>;
+
+    fn handle(&mut self, msg: Publication, _: &mut Context<Self>) -> Self::Result {
+        // Redeliveries of publications still waiting to be handed out are dropped
+        if self
+            .pending
+            .publications
+            .iter()
+            .any(|p| p.publication_id == msg.publication_id)
+        {
+            return Ok(());
+        }
+        self.pending.publications.push(msg);
+        if self.pending.publications.len() > MAX_PENDING {
+            let dropped = self.pending.publications.remove(0);
+            debug!(
+                "Dropped {} buffered for {}",
+                dropped.publication_id, self.id
+            );
+        }
+        self.respond();
+        Ok(())
+    }
+}
+
+impl Handler<Issue> for PollSession {
+    type Result = Result<(), PublicationError>;
+
+    fn handle(&mut self, msg: Issue, _: &mut Context<Self>) -> Self::Result {
+        // Redeliveries of issues still waiting to be handed out are dropped
+        if self.pending.issues.iter().any(|i| i.1 == msg.1) {
+            return Ok(());
+        }
+        self.pending.issues.push(msg);
+        if self.pending.issues.len() > MAX_PENDING {
+            let dropped = self.pending.issues.remove(0);
+            debug!("Dropped issue of {} buffered for {}", dropped.1, self.id);
+        }
+        self.respond();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::{
+        http::{header, StatusCode},
+        test, App,
+    };
+
+    use actix_web_actors::ws;
+    use futures_util::{SinkExt, StreamExt};
+
+    use crate::{
+        data_log::DataLogger,
+        protocol::{Capability, PROTOCOL_VERSION},
+        pubsub::{RedeliveryPolicy, SubmitCommand},
+        test_utils::{bearer, id_token_authenticator},
+        websocket::{websocket_handler, ClientCommand},
+    };
+
+    #[actix_rt::test]
+    async fn test_long_polling() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub = PubSubService::new(&data_log).start();
+        let polls = LongPollService::new(&pubsub).start();
+        let (client_id, subscription_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut app = test::init_service(
+            App::new()
+                .data(pubsub.clone())
+                .data(polls.clone())
+                .app_data(id_token_authenticator(&[client_id]))
+                .configure(configure),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/poll").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::post()
+            .uri(&format!(
+                "/poll/subscriptions/{}?durable=true&create=true",
+                subscription_id
+            ))
+            .header(header::AUTHORIZATION, bearer(&client_id))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let poll = || {
+            test::TestRequest::get()
+                .uri("/poll?timeout=1")
+                .header(header::AUTHORIZATION, bearer(&client_id))
+                .to_request()
+        };
+        let batch: PollBatch = test::read_response_json(&mut app, poll()).await;
+        assert!(batch.is_empty());
+
+        // A waiting poll is answered as soon as a publication arrives, long before its timeout
+        let waiting = test::TestRequest::get()
+            .uri("/poll?timeout=5")
+            .header(header::AUTHORIZATION, bearer(&client_id))
+            .to_request();
+        let publish = async {
+            pubsub
+                .send(SubmitCommand::new(&client_id, &subscription_id, b"first"))
+                .await
+                .unwrap()
+                .unwrap()
+        };
+        let started = Instant::now();
+        let (batch, first): (PollBatch, Uuid) =
+            futures::join!(test::read_response_json(&mut app, waiting), publish);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(batch.publications.len(), 1);
+        assert_eq!(batch.publications[0].publication_id, first);
+        assert_eq!(batch.publications[0].data, b"first");
+
+        // Publications arriving between polls are kept for the next poll
+        let mut published = Vec::new();
+        for data in &[b"other", b"third"] {
+            let submit = SubmitCommand::new(&client_id, &subscription_id, *data);
+            published.push(pubsub.send(submit).await.unwrap().unwrap());
+        }
+        let batch: PollBatch = test::read_response_json(&mut app, poll()).await;
+        let received: Vec<Uuid> = batch
+            .publications
+            .iter()
+            .map(|p| p.publication_id)
+            .collect();
+        assert_eq!(received, published);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/poll/subscriptions/{}", subscription_id))
+            .header(header::AUTHORIZATION, bearer(&client_id))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        pubsub
+            .send(SubmitCommand::new(&client_id, &subscription_id, b"unheard"))
+            .await
+            .unwrap()
+            .ok();
+        let batch: PollBatch = test::read_response_json(&mut app, poll()).await;
+        assert!(batch.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_outliving_replaced_sessions() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub = PubSubService::new(&data_log).start();
+        let polls = LongPollService::new(&pubsub).start();
+        let (client_id, subscription_id) = (Uuid::new_v4(), Uuid::new_v4());
+        pubsub
+            .send(ManageSubscription::Add {
+                client_id,
+                subscription_id,
+                kind: SubscriptionKind::Broadcast,
+                durable: false,
+                topic: None,
+                create: true,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let mut srv = {
+            let (pubsub, data_log) = (pubsub.clone(), data_log.clone());
+            test::start(move || {
+                App::new()
+                    .data(pubsub.clone())
+                    .data(data_log.clone())
+                    .route("/{session_id}", web::get().to(websocket_handler))
+            })
+        };
+        let mut conn = srv.ws_at(&format!("/{}", client_id)).await.unwrap();
+        let hello = ClientCommand::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::PushDelivery],
+        };
+        conn.send(ws::Message::Binary(
+            serde_cbor::to_vec(&hello).unwrap().into(),
+        ))
+        .await
+        .unwrap();
+        // Skip the offer and wait for the welcome, so the websocket session is registered
+        let mut received = 0;
+        while received < 2 {
+            if let ws::Frame::Binary(_) = conn.next().await.unwrap().unwrap() {
+                received += 1;
+            }
+        }
+        let mut app = test::init_service(
+            App::new()
+                .data(pubsub.clone())
+                .data(polls.clone())
+                .app_data(id_token_authenticator(&[client_id]))
+                .configure(configure),
+        )
+        .await;
+        let poll = || {
+            test::TestRequest::get()
+                .uri("/poll?timeout=0")
+                .header(header::AUTHORIZATION, bearer(&client_id))
+                .to_request()
+        };
+        // The poll session replaces the websocket session
+        let batch: PollBatch = test::read_response_json(&mut app, poll()).await;
+        assert!(batch.is_empty());
+        // Closing the replaced session must not unregister the poll session
+        // The server hangs up once the websocket session stopped
+        conn.send(ws::Message::Close(None)).await.unwrap();
+        while let Some(Ok(_)) = conn.next().await {}
+        let published = pubsub
+            .send(SubmitCommand::new(&client_id, &subscription_id, b"Test"))
+            .await
+            .unwrap()
+            .unwrap();
+        let batch: PollBatch = test::read_response_json(&mut app, poll()).await;
+        assert_eq!(batch.publications.len(), 1);
+        assert_eq!(batch.publications[0].publication_id, published);
+    }
+
+    #[actix_rt::test]
+    async fn test_acknowledging_issues() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub = PubSubService::new(&data_log)
+            .with_redelivery(RedeliveryPolicy {
+                timeout: Duration::from_millis(100),
+                max_attempts: 10,
+            })
+            .start();
+        let polls = LongPollService::new(&pubsub).start();
+        let (client_id, subscription_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut app = test::init_service(
+            App::new()
+                .data(pubsub.clone())
+                .data(polls.clone())
+                .app_data(id_token_authenticator(&[client_id]))
+                .configure(configure),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri(&format!(
+                "/poll/subscriptions/{}?queue=RoundRobin&create=true",
+                subscription_id
+            ))
+            .header(header::AUTHORIZATION, bearer(&client_id))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        pubsub
+            .send(ManageSubscription::SetDelivery {
+                client_id,
+                subscription_id,
+                mode: Some(DeliveryMode::Notify),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let poll = || {
+            test::TestRequest::get()
+                .uri("/poll?timeout=1")
+                .header(header::AUTHORIZATION, bearer(&client_id))
+                .to_request()
+        };
+        let published = pubsub
+            .send(SubmitCommand::new(&client_id, &subscription_id, b"Test"))
+            .await
+            .unwrap()
+            .unwrap();
+        let batch: PollBatch = test::read_response_json(&mut app, poll()).await;
+        assert!(batch.publications.is_empty());
+        assert_eq!(batch.issues.len(), 1);
+        assert_eq!(batch.issues[0].1, published);
+        // Polling again acknowledges the issue, so it is not redelivered
+        let batch: PollBatch = test::read_response_json(&mut app, poll()).await;
+        assert!(batch.is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Version of the websocket protocol spoken by this server
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version the server is able to fall back to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features of the websocket protocol, agreed upon during the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Capability {
+    /// The server pushes publications to the session as they are submitted.
+    /// Without it, the session is not registered with the `PubSubService`
+    /// and clients have to poll the data log.
+    PushDelivery,
+    /// Commands carrying a request id are answered with `ServerMessage::Ack`.
+    /// Without it, request ids are ignored and only failures are reported.
+    Acks,
+    /// Compressed payloads. Not offered by this server yet.
+    Compression,
+    /// A capability unknown to this server, e.g. one requested by a newer client
+    #[serde(other)]
+    Unknown,
+}
+
+/// Capabilities the server offers to its clients
+pub const SERVER_CAPABILITIES: &[Capability] = &[Capability::PushDelivery, Capability::Acks];
+/// Behaviour of version 1 sessions before capabilities were negotiated,
+/// kept for clients that don't send `Hello`
+pub const BASELINE_CAPABILITIES: &[Capability] = &[Capability::PushDelivery, Capability::Acks];
+
+/// Represents errors caused by incompatible handshakes
+#[derive(Debug, Error, PartialEq, Clone)]
+pub enum ProtocolError {
+    #[error("Protocol version {0} is not supported, the server supports versions {min} to {max}", min = MIN_PROTOCOL_VERSION, max = PROTOCOL_VERSION)]
+    UnsupportedVersion(u32),
+    #[error("Handshake already completed")]
+    RepeatedHandshake,
+}
+
+/// The protocol version and capabilities a session agreed upon.
+/// Sessions skipping the handshake use `MIN_PROTOCOL_VERSION` with `BASELINE_CAPABILITIES`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Agreement {
+    pub version: u32,
+    pub capabilities: Vec<Capability>,
+}
+
+impl Default for Agreement {
+    fn default() -> Self {
+        Agreement {
+            version: MIN_PROTOCOL_VERSION,
+            capabilities: BASELINE_CAPABILITIES.to_vec(),
+        }
+    }
+}
+
+impl Agreement {
+    /// Agrees on the version the client speaks and the capabilities both sides support.
+    /// Versions outside of `MIN_PROTOCOL_VERSION` to `PROTOCOL_VERSION` are refused,
+    /// clients learn which versions the server supports from `ServerMessage::Offer`.
+    pub fn negotiate(version: u32, requested: &[Capability]) -> Result<Agreement, ProtocolError> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+        let capabilities = SERVER_CAPABILITIES
+            .iter()
+            .filter(|c| requested.contains(c))
+            .copied()
+            .collect();
+        Ok(Agreement {
+            version,
+            capabilities,
+        })
+    }
+
+    /// Returns true if the capability was agreed upon
+    pub fn grants(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiating_agreements() {
+        assert_eq!(
+            Agreement::negotiate(PROTOCOL_VERSION, &[Capability::Acks]),
+            Ok(Agreement {
+                version: PROTOCOL_VERSION,
+                capabilities: vec![Capability::Acks],
+            })
+        );
+        assert_eq!(
+            Agreement::negotiate(PROTOCOL_VERSION + 1, &[Capability::Acks]),
+            Err(ProtocolError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+        let agreement = Agreement::negotiate(
+            PROTOCOL_VERSION,
+            &[
+                Capability::Compression,
+                Capability::Acks,
+                Capability::PushDelivery,
+                Capability::Unknown,
+            ],
+        )
+        .unwrap();
+        assert!(agreement.grants(Capability::PushDelivery));
+        assert!(agreement.grants(Capability::Acks));
+        assert!(!agreement.grants(Capability::Compression));
+        assert_eq!(
+            Agreement::negotiate(MIN_PROTOCOL_VERSION - 1, &[]),
+            Err(ProtocolError::UnsupportedVersion(MIN_PROTOCOL_VERSION - 1))
+        );
+        assert_eq!(Agreement::default().version, MIN_PROTOCOL_VERSION);
+        assert_eq!(Agreement::default().capabilities, BASELINE_CAPABILITIES);
+    }
+
+    #[test]
+    fn test_decoding_unknown_capabilities() {
+        let capabilities: Vec<Capability> =
+            serde_json::from_str(r#"["Acks", "Teleportation"]"#).unwrap();
+        assert_eq!(capabilities, vec![Capability::Acks, Capability::Unknown]);
+        let encoded = serde_cbor::to_vec(&vec!["PushDelivery", "Teleportation"]).unwrap();
+        let capabilities: Vec<Capability> = serde_cbor::from_slice(&encoded).unwrap();
+        assert_eq!(
+            capabilities,
+            vec![Capability::PushDelivery, Capability::Unknown]
+        );
+    }
+}
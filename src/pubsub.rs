@@ -1,17 +1,25 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    iter::FromIterator,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use actix::{
-    prelude::{Actor, Context, Handler, Message},
+    dev::ToEnvelope,
+    prelude::{
+        Actor, ActorFuture, AsyncContext, Context, Handler, Message, Recipient, ResponseActFuture,
+        WrapFuture,
+    },
     Addr,
 };
-use log::{debug, error, info};
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use uuid::Uuid;
 
 use crate::{
-    data_log::{DataLogPut, DataLogger},
-    websocket::WebSocketSession,
+    data_log::{DataLogPut, DataLogger, MetadataPut, MetadataRemove, ReplayPull, RetentionPolicy},
+    topic::{is_pattern, matches, topic_id, validate_pattern, validate_topic, TopicError},
 };
 
 /// Represents errors caused during interaction with the PubSubService actor
@@ -28,54 +36,227 @@ pub enum PublicationError {
 
     #[error("Error while handling subscriptions: {0}")]
     Subscriptions(&'static str),
+
+    #[error("Access denied: {0}")]
+    AccessDenied(String),
+
+    #[error("Invalid topic: {0}")]
+    InvalidTopic(String),
+}
+
+impl From<TopicError> for PublicationError {
+    fn from(e: TopicError) -> PublicationError {
+        PublicationError::InvalidTopic(format!("{}", e))
+    }
+}
+
+/// The recipients a connected session is informed about publications through,
+/// either by an `Issue` or by the whole `Publication`.
+/// Handles of the same session actor are equal.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionHandle {
+    issues: Recipient<Issue>,
+    publications: Recipient<Publication>,
+}
+
+impl SessionHandle {
+    /// Creates the handle of a session actor able to receive `Issue`s and `Publication`s
+    pub fn new<A>(addr: &Addr<A>) -> SessionHandle
+    where
+        A: Actor + Handler<Issue> + Handler<Publication>,
+        A::Context: ToEnvelope<A, Issue> + ToEnvelope<A, Publication>,
+    {
+        SessionHandle {
+            issues: addr.clone().recipient(),
+            publications: addr.clone().recipient(),
+        }
+    }
 }
 
-/// A message to register a websocket session with the pubsub service
+/// A message to register a client session, e.g. a `WebSocketSession`, with the pubsub service.
+/// Each client has at most one session, registering another one replaces it.
 #[derive(Debug, Message)]
 #[rtype("Result<(), PublicationError>")]
 pub enum ManageSession {
-    /// Add [Addr] of clients [WebsocketSession] to [PubSubService.sessions]
+    /// Add the session of a client to [PubSubService.sessions]. `delivery` is the session's
+    /// own delivery mode, see `SetDelivery`; it is set before publications are replayed
+    /// to the client and does not carry over to later sessions of the client.
     Add {
         client_id: Uuid,
-        addr: Addr<WebSocketSession>,
+        session: SessionHandle,
+        delivery: Option<DeliveryMode>,
+    },
+    /// Remove client session from [PubSubService.sessions],
+    /// unless it was already replaced by another session of the client
+    Remove {
+        client_id: Uuid,
+        session: SessionHandle,
     },
-    /// Remove websocket session from [PubSubService.sessions]
-    Remove { client_id: Uuid },
+    /// Set how publications are delivered to the session,
+    /// for subscriptions that do not specify a `DeliveryMode` themselves
+    SetDelivery { client_id: Uuid, mode: DeliveryMode },
 }
 
 /// A message to add or remove a client id from a subscription
 #[derive(Debug, Message)]
 #[rtype("Result<(), PublicationError>")]
 pub enum ManageSubscription {
-    /// Add client to a Subscription, creating it, if it doesn't exist
+    /// Add client to a Subscription. If it doesn't exist and `create` is set, it is created
+    /// as `kind`, owned by the client, otherwise adding the client fails.
+    /// Durable subscribers of broadcast-type Subscriptions receive the publications
+    /// they did not acknowledge once their session reconnects.
+    /// Subscriptions created for a `topic` have to be identified by its `topic_id`,
+    /// if the topic is a pattern, its subscribers receive the publications of every matching topic.
     Add {
         client_id: Uuid,
         subscription_id: Uuid,
+        kind: SubscriptionKind,
+        durable: bool,
+        topic: Option<String>,
+        create: bool,
     },
     /// Clients _are_ allowed to cancel their Subscription
     Remove {
         client_id: Uuid,
         subscription_id: Uuid,
     },
+    /// Set how publications of an existing Subscription are delivered to its subscribers.
+    /// `None` leaves the choice to each subscriber's session.
+    SetDelivery {
+        client_id: Uuid,
+        subscription_id: Uuid,
+        mode: Option<DeliveryMode>,
+    },
+    /// Replace the retention policy of an existing Subscription
+    SetRetention {
+        client_id: Uuid,
+        subscription_id: Uuid,
+        policy: RetentionPolicy,
+    },
+    /// Restrict which clients may publish to or subscribe to an existing Subscription.
+    /// Subscribers no longer permitted are removed.
+    SetAccess {
+        client_id: Uuid,
+        subscription_id: Uuid,
+        publishers: Option<Vec<Uuid>>,
+        subscribers: Option<Vec<Uuid>>,
+    },
+    /// Discard the publication retained by a Subscription.
+    /// Allowed for every client permitted to publish to it.
+    ClearRetained {
+        client_id: Uuid,
+        subscription_id: Uuid,
+    },
+    /// Publish given up publications to an existing Subscription owned by the client,
+    /// see `RedeliveryPolicy`. `None` drops them. Replacing the dead-letter Subscription
+    /// requires owning the current one, too.
+    SetDeadLetter {
+        client_id: Uuid,
+        subscription_id: Option<Uuid>,
+    },
 }
 
-/// A message to submit data for publishing
+/// A message to add a client to an existing Subscription alongside its other subscriptions,
+/// e.g. for a stream listening to it. Unlike `ManageSubscription::Add`, it never creates
+/// the Subscription and leaves the client's durable cursor untouched.
+/// Resolves to whether the client was added, `false` if it already subscribed.
 #[derive(Debug, Message)]
-#[rtype(result = "Result<(), PublicationError>")]
+#[rtype("Result<bool, PublicationError>")]
+pub struct Listen {
+    pub client_id: Uuid,
+    pub subscription_id: Uuid,
+}
+
+/// A message to submit data for publishing.
+/// Resolves to the id of the resulting publication.
+#[derive(Debug, Message)]
+#[rtype(result = "Result<Uuid, PublicationError>")]
 pub struct SubmitCommand {
     client_id: Uuid,
     subscription_id: Uuid,
     submission: Vec<u8>,
+    key: Option<String>,
+    topic: Option<String>,
+    retain: bool,
+    headers: HashMap<String, String>,
 }
 
 impl SubmitCommand {
-    pub fn new(client: &Uuid, subscription_id: &Uuid, submission: &Vec<u8>) -> Self {
+    pub fn new(client: &Uuid, subscription_id: &Uuid, submission: &[u8]) -> Self {
         SubmitCommand {
-            client_id: client.clone(),
-            subscription_id: subscription_id.clone(),
-            submission: submission.clone(),
+            client_id: *client,
+            subscription_id: *subscription_id,
+            submission: submission.to_vec(),
+            key: None,
+            topic: None,
+            retain: false,
+            headers: HashMap::new(),
         }
     }
+
+    /// Sets the compaction key of the resulting publication
+    pub fn with_key(mut self, key: Option<String>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Submits to the subscription of a topic, creating it if it doesn't exist.
+    /// The subscription id has to be the topic's `topic_id`.
+    pub fn with_topic(mut self, topic: String) -> Self {
+        self.topic = Some(topic);
+        self
+    }
+
+    /// Keeps the resulting publication as the subscription's retained value,
+    /// which is delivered to every client subscribing later on
+    pub fn with_retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    /// Sets the headers of the resulting publication, e.g. its `content-type`
+    pub fn with_headers(mut self, headers: HashMap<String, String>) -> Self {
+        self.headers = headers;
+        self
+    }
+}
+
+/// A message acknowledging that a client has processed a publication
+#[derive(Debug, Message)]
+#[rtype(result = "Result<(), PublicationError>")]
+pub struct AckCommand {
+    client_id: Uuid,
+    subscription_id: Uuid,
+    publication_id: Uuid,
+}
+
+impl AckCommand {
+    pub fn new(client: &Uuid, subscription_id: &Uuid, publication_id: &Uuid) -> Self {
+        AckCommand {
+            client_id: *client,
+            subscription_id: *subscription_id,
+            publication_id: *publication_id,
+        }
+    }
+}
+
+/// A message asking whether a client may read the log and metadata of a subscription,
+/// which is permitted to the clients allowed to subscribe to it
+#[derive(Debug, Message)]
+#[rtype("Result<(), PublicationError>")]
+pub struct CheckReadAccess {
+    pub client_id: Uuid,
+    pub subscription_id: Uuid,
+}
+
+/// A message to request the metadata of all topic subscriptions matching `pattern`
+/// that `client_id` may subscribe to
+#[derive(Debug, Message)]
+#[rtype("Result<(), PublicationError>")]
+pub struct TopicPull {
+    pub client_id: Uuid,
+    pub client: Recipient<MetadataPut<Subscription>>,
+    pub pattern: String,
 }
 
 /// A message informing clients about newly submitted publications
@@ -83,43 +264,771 @@ impl SubmitCommand {
 #[rtype("Result<(), PublicationError>")]
 pub struct Issue(pub Uuid, pub Uuid);
 
+/// Name of the header describing the media type of a publication's data, e.g. `application/json`
+pub const CONTENT_TYPE_HEADER: &str = "content-type";
+
+/// Lower bound for the interval at which a `PubSubService` looks for publications to redeliver
+const MIN_REDELIVERY_INTERVAL: Duration = Duration::from_millis(10);
+/// Number of publications a durable subscriber missed that are fetched and delivered at once.
+/// The next page is fetched once the previous one was acknowledged, so a page has to fit
+/// into a session's mailbox.
+const REPLAY_PAGE_SIZE: usize = 16;
+/// Interval at which the cursors of durable subscribers are persisted if they advanced
+const CURSOR_PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Determines how publications that have to be acknowledged are redelivered.
+/// Publications of queue-type subscriptions and those delivered to durable subscribers
+/// have to be acknowledged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedeliveryPolicy {
+    /// Time to wait for an acknowledgement before delivering a publication again
+    pub timeout: Duration,
+    /// Number of deliveries after which an unacknowledged publication is given up.
+    /// Given up publications are published, CBOR encoded, to the dead-letter Subscription
+    /// set with `ManageSubscription::SetDeadLetter` and dropped if there is none.
+    pub max_attempts: u32,
+}
+
+impl Default for RedeliveryPolicy {
+    fn default() -> Self {
+        RedeliveryPolicy {
+            timeout: Duration::from_secs(30),
+            max_attempts: 5,
+        }
+    }
+}
+
+/// State of a `PubSubService` recovered from persisted data
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RecoveredState {
+    /// Subscriptions to seed the subscription store with
+    pub subscriptions: Vec<Subscription>,
+    /// The last sequence number assigned to a publication, per subscription
+    pub sequences: HashMap<Uuid, u64>,
+    /// The retained publication, per subscription
+    pub retained: HashMap<Uuid, Publication>,
+}
+
 /// The actor managing `Subscriptions` and handling dissemination of `Publication`s.
 /// Holds a list of currently connected sessions and a `Subscription` store.
 #[derive(Debug, Clone)]
 pub struct PubSubService {
     subscriptions: Subscriptions,
-    sessions: HashMap<Uuid, Addr<WebSocketSession>>,
+    sequences: HashMap<Uuid, u64>,
+    queues: HashMap<Uuid, QueueState>,
+    sessions: HashMap<Uuid, SessionHandle>,
+    session_delivery: HashMap<Uuid, DeliveryMode>,
+    /// Publications delivered to a session and not acknowledged yet,
+    /// by subscription and subscriber, keyed by sequence number
+    in_flight: HashMap<(Uuid, Uuid), BTreeMap<u64, Delivery>>,
+    /// Durable subscribers waiting for the publications they missed, by subscription
+    replaying: HashMap<(Uuid, Uuid), Replay>,
+    /// Subscriptions whose cursors advanced since they were last persisted
+    unpersisted_cursors: HashSet<Uuid>,
+    /// The last publication submitted with `retain`, per subscription
+    retained: HashMap<Uuid, Publication>,
+    redelivery: RedeliveryPolicy,
+    /// Subscription given up publications are published to
+    dead_letter: Option<Uuid>,
     data_log_addr: Addr<DataLogger>,
 }
 
 impl PubSubService {
     /// Creates a new `PubSubService` actor.
     pub fn new(data_log_addr: &Addr<DataLogger>) -> Self {
-        let subs = Subscriptions::new();
+        PubSubService::recover(data_log_addr, RecoveredState::default())
+    }
+
+    /// Creates a new `PubSubService` actor, seeding it with previously persisted state,
+    /// e.g. the one returned by `DataLogger::recover_state`.
+    pub fn recover(data_log_addr: &Addr<DataLogger>, state: RecoveredState) -> Self {
         PubSubService {
-            subscriptions: subs,
+            subscriptions: state.subscriptions.into_iter().collect(),
+            sequences: state.sequences,
+            queues: HashMap::new(),
             sessions: HashMap::new(),
+            session_delivery: HashMap::new(),
+            in_flight: HashMap::new(),
+            replaying: HashMap::new(),
+            unpersisted_cursors: HashSet::new(),
+            retained: state.retained,
+            redelivery: RedeliveryPolicy::default(),
+            dead_letter: None,
             data_log_addr: data_log_addr.clone(),
         }
     }
+
+    /// Sets how publications that have to be acknowledged are redelivered
+    pub fn with_redelivery(mut self, policy: RedeliveryPolicy) -> Self {
+        self.redelivery = policy;
+        self
+    }
+
+    /// Assigns the next sequence number of a subscription
+    fn next_sequence(&mut self, subscription_id: &Uuid) -> u64 {
+        let sequence = self.sequences.entry(*subscription_id).or_insert(0);
+        *sequence += 1;
+        *sequence
+    }
+
+    /// Gives back the sequence number of a publication that could not be logged,
+    /// unless a later publication of the subscription was numbered already
+    fn release_sequence(&mut self, subscription_id: &Uuid, sequence: u64) {
+        if let Some(last) = self.sequences.get_mut(subscription_id) {
+            if *last == sequence {
+                *last -= 1;
+            }
+        }
+    }
+
+    /// Sends a publication to a connected client, either in full or as an `Issue`,
+    /// depending on the `DeliveryMode` of the subscription or, if it has none, of the session.
+    fn deliver(
+        &self,
+        client_id: &Uuid,
+        subscription: &Subscription,
+        publication: &Publication,
+    ) -> Result<(), PublicationError> {
+        let session = match self.sessions.get(client_id) {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        let mode = subscription
+            .delivery
+            .or_else(|| self.session_delivery.get(client_id).copied())
+            .unwrap_or_default();
+        let result = match mode {
+            DeliveryMode::Notify => session
+                .issues
+                .try_send(Issue(subscription.id, publication.publication_id))
+                .map_err(|e| e.to_string()),
+            DeliveryMode::Full => session
+                .publications
+                .try_send(publication.clone())
+                .map_err(|e| e.to_string()),
+        };
+        result.map_err(PublicationError::Publishing)
+    }
+
+    /// Adds a publication delivered to a client to the client's publications in flight
+    fn track(&mut self, client_id: &Uuid, subscription_id: &Uuid, publication: &Publication) {
+        self.in_flight
+            .entry((*subscription_id, *client_id))
+            .or_default()
+            .insert(
+                publication.sequence,
+                Delivery {
+                    publication: publication.clone(),
+                    attempts: 1,
+                    sent: Instant::now(),
+                },
+            );
+    }
+
+    /// Delivers a publication that has to be acknowledged to a connected client.
+    /// Failed deliveries are retried like unacknowledged ones.
+    fn deliver_tracked(
+        &mut self,
+        client_id: &Uuid,
+        subscription: &Subscription,
+        publication: &Publication,
+    ) {
+        if !self.sessions.contains_key(client_id) {
+            return;
+        }
+        self.track(client_id, &subscription.id, publication);
+        if let Err(e) = self.deliver(client_id, subscription, publication) {
+            warn!(
+                "Could not deliver {} to {}: {}",
+                publication.publication_id, client_id, e
+            );
+        }
+    }
+
+    /// Delivers a publication of a broadcast-type subscription to a subscriber,
+    /// tracking it until it is acknowledged if the subscriber is durable
+    fn broadcast(
+        &mut self,
+        client_id: &Uuid,
+        subscription: &Subscription,
+        publication: &Publication,
+    ) -> Result<(), PublicationError> {
+        if subscription.cursors.contains_key(client_id) {
+            self.deliver_tracked(client_id, subscription, publication);
+            return Ok(());
+        }
+        self.deliver(client_id, subscription, publication)
+    }
+
+    /// Distributes a new publication to the subscribers of a subscription.
+    /// Failing to deliver it to one subscriber doesn't keep it from the others.
+    fn distribute(&mut self, subscription: &Subscription, publication: Publication) {
+        match subscription.kind {
+            SubscriptionKind::Broadcast => {
+                for client_id in &subscription.subscribers {
+                    if let Err(e) = self.broadcast(client_id, subscription, &publication) {
+                        warn!(
+                            "Could not deliver {} to {}: {}",
+                            publication.publication_id, client_id, e
+                        );
+                    }
+                }
+            }
+            SubscriptionKind::Queue(_) => {
+                self.queues
+                    .entry(subscription.id)
+                    .or_default()
+                    .backlog
+                    .push_back(publication);
+                self.dispatch_queue(subscription);
+            }
+        }
+    }
+
+    /// Informs the subscribers of pattern subscriptions matching the topic of a subscription
+    /// about a publication. Clients subscribed to the topic itself or not permitted to subscribe
+    /// to it are skipped.
+    fn distribute_to_patterns(&self, subscription: &Subscription, publication: &Publication) {
+        let topic = match &subscription.topic {
+            Some(topic) => topic,
+            None => return,
+        };
+        let clients: HashSet<Uuid> = self
+            .subscriptions
+            .iter()
+            .filter(|s| {
+                s.topic.as_deref().map_or(false, |pattern| {
+                    is_pattern(pattern) && matches(pattern, topic)
+                })
+            })
+            .flat_map(|s| s.subscribers.iter().copied())
+            .filter(|c| {
+                !subscription.subscribers.contains(c)
+                    && subscription.access.check_subscriber(c).is_ok()
+            })
+            .collect();
+        for client_id in clients {
+            if let Err(e) = self.deliver(&client_id, subscription, publication) {
+                warn!(
+                    "Could not deliver {} to {}: {}",
+                    publication.publication_id, client_id, e
+                );
+            }
+        }
+    }
+
+    /// Delivers the retained publications of a subscription to a client that just subscribed.
+    /// Subscribers of a topic pattern receive those of every matching topic
+    /// they are permitted to subscribe to.
+    fn deliver_retained(&self, client_id: &Uuid, subscription: &Subscription) {
+        let pattern = subscription.topic.as_deref().filter(|t| is_pattern(t));
+        let retained: Vec<(Subscription, &Publication)> = match pattern {
+            Some(pattern) => self
+                .subscriptions
+                .iter()
+                .filter(|s| {
+                    s.topic
+                        .as_deref()
+                        .map_or(false, |topic| !is_pattern(topic) && matches(pattern, topic))
+                        && s.access.check_subscriber(client_id).is_ok()
+                })
+                .filter_map(|s| self.retained.get(&s.id).map(|p| (s.clone(), p)))
+                .collect(),
+            None => self
+                .retained
+                .get(&subscription.id)
+                .map(|p| (subscription.clone(), p))
+                .into_iter()
+                .collect(),
+        };
+        for (subscription, publication) in retained {
+            if let Err(e) = self.deliver(client_id, &subscription, publication) {
+                warn!(
+                    "Could not deliver retained {} to {}: {}",
+                    publication.publication_id, client_id, e
+                );
+            }
+        }
+    }
+
+    /// Fetches the publications a durable subscriber missed from the data log,
+    /// up to sequence number `until`, and delivers them page by page.
+    /// Later publications are delivered as they are submitted.
+    fn replay(
+        &mut self,
+        client_id: Uuid,
+        subscription_id: Uuid,
+        until: u64,
+        ctx: &mut Context<Self>,
+    ) {
+        let since = match self
+            .subscriptions
+            .fetch(&subscription_id)
+            .ok()
+            .and_then(|s| s.cursors.get(&client_id).copied())
+        {
+            Some(cursor) if cursor < until => cursor,
+            _ => return,
+        };
+        debug!(
+            "Replaying {} publications of {} to {}",
+            until - since,
+            subscription_id,
+            client_id
+        );
+        // Holds the cursor back until the replayed publications are delivered
+        self.replaying.insert(
+            (subscription_id, client_id),
+            Replay {
+                fetched: since,
+                until,
+                fetching: false,
+            },
+        );
+        self.replay_page(client_id, subscription_id, ctx);
+    }
+
+    /// Fetches and delivers the next page of publications a durable subscriber missed
+    fn replay_page(&mut self, client_id: Uuid, subscription_id: Uuid, ctx: &mut Context<Self>) {
+        let since = match self.replaying.get_mut(&(subscription_id, client_id)) {
+            Some(replay) if !replay.fetching => {
+                replay.fetching = true;
+                replay.fetched
+            }
+            _ => return,
+        };
+        let request = self.data_log_addr.send(ReplayPull {
+            data_log_id: subscription_id,
+            since,
+            limit: REPLAY_PAGE_SIZE,
+        });
+        ctx.spawn(request.into_actor(self).map(move |result, act, _| {
+            let key = (subscription_id, client_id);
+            let publications = match result {
+                Ok(Ok(publications)) => publications,
+                Ok(Err(e)) => {
+                    act.replaying.remove(&key);
+                    return warn!("Could not replay {}: {}", subscription_id, e);
+                }
+                Err(e) => {
+                    act.replaying.remove(&key);
+                    return warn!("Could not replay {}: {}", subscription_id, e);
+                }
+            };
+            let subscription = match act.subscriptions.fetch(&subscription_id) {
+                Ok(subscription) => subscription,
+                Err(_) => {
+                    act.replaying.remove(&key);
+                    return;
+                }
+            };
+            // The session went away while the page was fetched
+            let replay = match act.replaying.get_mut(&key) {
+                Some(replay) => replay,
+                None => return,
+            };
+            let until = replay.until;
+            let page: Vec<&Publication> = publications
+                .iter()
+                .filter(|p| p.sequence <= until)
+                .collect();
+            replay.fetching = false;
+            replay.fetched = match page.last() {
+                Some(last) if publications.len() == REPLAY_PAGE_SIZE => last.sequence,
+                _ => until,
+            };
+            if replay.fetched >= until {
+                act.replaying.remove(&key);
+            }
+            for publication in page {
+                if let Err(e) = act.broadcast(&client_id, &subscription, publication) {
+                    warn!(
+                        "Could not replay {} to {}: {}",
+                        publication.publication_id, client_id, e
+                    );
+                }
+            }
+        }));
+    }
+
+    /// Removes an acknowledged publication from the publications in flight to a client.
+    /// The cursor of a durable subscriber advances to the last publication it acknowledged
+    /// without gaps, it is persisted with the next `persist_cursors`.
+    /// Once a replayed page is acknowledged completely, the next one is fetched.
+    fn acknowledge(
+        &mut self,
+        client_id: &Uuid,
+        subscription_id: &Uuid,
+        publication_id: &Uuid,
+        ctx: &mut Context<Self>,
+    ) -> Result<(), PublicationError> {
+        if let Some(queue) = self.queues.get_mut(subscription_id) {
+            queue
+                .in_flight
+                .retain(|(p, c)| !(&p.publication_id == publication_id && c == client_id));
+        }
+        let pending = match self.in_flight.get_mut(&(*subscription_id, *client_id)) {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        let sequence = match pending
+            .iter()
+            .find(|(_, d)| &d.publication.publication_id == publication_id)
+        {
+            Some((sequence, _)) => *sequence,
+            None => return Ok(()),
+        };
+        pending.remove(&sequence);
+        let mut acknowledged = match pending.keys().next() {
+            Some(first_pending) => first_pending.saturating_sub(1),
+            None => sequence,
+        };
+        // Publications that were not fetched yet are not acknowledged either
+        if let Some(replay) = self.replaying.get(&(*subscription_id, *client_id)) {
+            acknowledged = acknowledged.min(replay.fetched);
+            if pending
+                .keys()
+                .next()
+                .map_or(true, |first| *first > replay.fetched)
+            {
+                self.replay_page(*client_id, *subscription_id, ctx);
+            }
+        }
+        let mut subscription = self.subscriptions.fetch(subscription_id)?;
+        match subscription.cursors.get_mut(client_id) {
+            Some(cursor) if *cursor < acknowledged => *cursor = acknowledged,
+            _ => return Ok(()),
+        }
+        self.subscriptions.update(&subscription);
+        self.unpersisted_cursors.insert(subscription.id);
+        Ok(())
+    }
+
+    /// Persists the subscriptions whose cursors advanced, all in one `MetadataPut`
+    fn persist_cursors(&mut self) {
+        if self.unpersisted_cursors.is_empty() {
+            return;
+        }
+        let subscriptions: Vec<Subscription> = self
+            .unpersisted_cursors
+            .iter()
+            .filter_map(|id| self.subscriptions.fetch(id).ok())
+            .collect();
+        match self.data_log_addr.try_send(MetadataPut(subscriptions)) {
+            Ok(()) => self.unpersisted_cursors.clear(),
+            Err(e) => warn!("Could not persist cursors, retrying: {}", e),
+        }
+    }
+
+    /// Hands the backlog of a queue-type subscription to its connected subscribers,
+    /// one subscriber per publication.
+    fn dispatch_queue(&mut self, subscription: &Subscription) {
+        let strategy = match subscription.kind {
+            SubscriptionKind::Queue(strategy) => strategy,
+            SubscriptionKind::Broadcast => return,
+        };
+        let mut consumers: Vec<Uuid> = subscription
+            .subscribers
+            .iter()
+            .filter(|s| self.sessions.contains_key(s))
+            .copied()
+            .collect();
+        let mut queue = self.queues.remove(&subscription.id).unwrap_or_default();
+        while let Some(publication) = queue.backlog.pop_front() {
+            let consumer = match queue.pick_consumer(&consumers, strategy) {
+                Some(c) => c,
+                None => {
+                    queue.backlog.push_front(publication);
+                    break;
+                }
+            };
+            match self.deliver(&consumer, subscription, &publication) {
+                Ok(()) => {
+                    self.track(&consumer, &subscription.id, &publication);
+                    queue.in_flight.push((publication, consumer))
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not hand {} to {}: {}",
+                        publication.publication_id, consumer, e
+                    );
+                    queue.backlog.push_front(publication);
+                    consumers.retain(|c| c != &consumer);
+                }
+            }
+        }
+        self.queues.insert(subscription.id, queue);
+    }
+
+    /// Returns unacknowledged publications a client received from queue-type subscriptions
+    /// to their backlog and hands them to the remaining subscribers.
+    /// If `subscription_id` is given, only that subscription's queue is affected.
+    fn release_queued(&mut self, client_id: &Uuid, subscription_id: Option<&Uuid>) {
+        let released: Vec<Uuid> = self
+            .queues
+            .iter_mut()
+            .filter(|(id, _)| subscription_id.map(|s| s == *id).unwrap_or(true))
+            .filter_map(|(id, queue)| queue.release(client_id).then_some(*id))
+            .collect();
+        for id in released {
+            if let Ok(subscription) = self.subscriptions.fetch(&id) {
+                self.dispatch_queue(&subscription);
+            }
+        }
+    }
+
+    /// Delivers publications again that were not acknowledged within the redelivery timeout.
+    /// Publications that reached the maximum number of attempts are given up.
+    fn redeliver_expired(&mut self, ctx: &mut Context<Self>) {
+        let now = Instant::now();
+        let timeout = self.redelivery.timeout;
+        let max_attempts = self.redelivery.max_attempts;
+        let mut expired = Vec::new();
+        for ((subscription_id, client_id), pending) in self.in_flight.iter_mut() {
+            for delivery in pending.values_mut() {
+                if now.duration_since(delivery.sent) < timeout {
+                    continue;
+                }
+                delivery.attempts += 1;
+                delivery.sent = now;
+                expired.push((
+                    *subscription_id,
+                    *client_id,
+                    delivery.publication.clone(),
+                    delivery.attempts > max_attempts,
+                ));
+            }
+        }
+        for (subscription_id, client_id, publication, give_up) in expired {
+            let subscription = match self.subscriptions.fetch(&subscription_id) {
+                Ok(subscription) if !give_up => subscription,
+                _ => {
+                    self.give_up(&client_id, &subscription_id, publication, ctx);
+                    continue;
+                }
+            };
+            debug!(
+                "Redelivering {} to {}",
+                publication.publication_id, client_id
+            );
+            if let Err(e) = self.deliver(&client_id, &subscription, &publication) {
+                warn!(
+                    "Could not redeliver {} to {}: {}",
+                    publication.publication_id, client_id, e
+                );
+            }
+        }
+    }
+
+    /// Stops delivering a publication to a client, treating it as acknowledged,
+    /// and publishes it to the dead-letter subscription.
+    /// Publications of the dead-letter subscription itself are dropped.
+    fn give_up(
+        &mut self,
+        client_id: &Uuid,
+        subscription_id: &Uuid,
+        publication: Publication,
+        ctx: &mut Context<Self>,
+    ) {
+        warn!(
+            "Giving up delivering {} to {}",
+            publication.publication_id, client_id
+        );
+        let publication_id = publication.publication_id;
+        if let Err(e) = self.acknowledge(client_id, subscription_id, &publication_id, ctx) {
+            warn!("Could not remove {}: {}", publication.publication_id, e);
+        }
+        let dead_letter_id = match self.dead_letter {
+            Some(id) if &id != subscription_id => id,
+            _ => return,
+        };
+        let data = match serde_cbor::to_vec(&publication) {
+            Ok(data) => data,
+            Err(e) => return warn!("Could not encode dead letter: {}", e),
+        };
+        let sequence = self.next_sequence(&dead_letter_id);
+        let dead_letter = Publication::new(&dead_letter_id, sequence, &data);
+        ctx.spawn(self.publish(dead_letter, false).map(|result, _, _| {
+            if let Err(e) = result {
+                warn!("Could not publish dead letter: {}", e);
+            }
+        }));
+    }
+
+    /// Writes a publication to the data log, then distributes it to the subscribers of its
+    /// subscription and of matching topic patterns. Resolves to the publication's id.
+    /// The sequence number of a publication that could not be logged is given back.
+    fn publish(
+        &mut self,
+        publication: Publication,
+        retain: bool,
+    ) -> ResponseActFuture<Self, Result<Uuid, PublicationError>> {
+        let (subscription_id, sequence) = (publication.subscription_id, publication.sequence);
+        let publication_id = publication.publication_id;
+        let logged = self
+            .data_log_addr
+            .send(DataLogPut(vec![publication.clone()]));
+        Box::pin(logged.into_actor(self).map(move |result, act, _| {
+            let error = match result {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some(format!(
+                    "Could not write published message to datalog: {}",
+                    e
+                )),
+                Err(e) => Some(format!(
+                    "Could not send published message to datalog: {}",
+                    e
+                )),
+            };
+            if let Some(error) = error {
+                act.release_sequence(&subscription_id, sequence);
+                return Err(PublicationError::DataLoggingError(error));
+            }
+            // The subscription may have changed while the publication was logged
+            let mut subscription = match act.subscriptions.fetch(&subscription_id) {
+                Ok(subscription) => subscription,
+                Err(_) => return Ok(publication_id),
+            };
+            // Persisted after the publication, so the retained id always refers to a logged one
+            let retained = if retain {
+                subscription.retained = Some(publication_id);
+                act.subscriptions.update(&subscription);
+                act.retained.insert(subscription.id, publication.clone());
+                act.persist_subscription(&subscription)
+            } else {
+                Ok(())
+            };
+            act.distribute_to_patterns(&subscription, &publication);
+            act.distribute(&subscription, publication);
+            retained.map(|_| publication_id)
+        }))
+    }
+
+    /// Looks up the subscription a submission is addressed to. The subscription of a topic
+    /// is created, owned by the submitting client, if it doesn't exist yet.
+    fn fetch_submission_target(
+        &mut self,
+        msg: &SubmitCommand,
+    ) -> Result<Subscription, PublicationError> {
+        let topic = match &msg.topic {
+            Some(topic) => topic,
+            None => return self.subscriptions.fetch(&msg.subscription_id),
+        };
+        validate_topic(topic)?;
+        if topic_id(topic) != msg.subscription_id {
+            return Err(PublicationError::InvalidTopic(format!(
+                "{} does not identify topic {}",
+                msg.subscription_id, topic
+            )));
+        }
+        if let Ok(subscription) = self.subscriptions.fetch(&msg.subscription_id) {
+            return Ok(subscription);
+        }
+        info!("Creating subscription for topic {}", topic);
+        let mut subscription = Subscription::new(&msg.subscription_id, topic);
+        subscription.topic = Some(topic.clone());
+        subscription.access.owner = Some(msg.client_id);
+        self.subscriptions.update(&subscription);
+        self.persist_subscription(&subscription)?;
+        Ok(subscription)
+    }
+
+    /// Sends the current state of a `Subscription` to the `DataLogger` to be persisted
+    fn persist_subscription(&self, subscription: &Subscription) -> Result<(), PublicationError> {
+        self.data_log_addr
+            .try_send(MetadataPut(vec![subscription.clone()]))
+            .map_err(|e| {
+                PublicationError::DataLoggingError(format!(
+                    "Could not write subscription metadata to datalog: {}",
+                    e
+                ))
+            })
+    }
+
+    /// Asks the `DataLogger` to remove the persisted metadata of a `Subscription` that was dropped
+    fn forget_subscription(&self, subscription_id: &Uuid) -> Result<(), PublicationError> {
+        self.data_log_addr
+            .try_send(MetadataRemove(*subscription_id))
+            .map_err(|e| {
+                PublicationError::DataLoggingError(format!(
+                    "Could not remove subscription metadata from datalog: {}",
+                    e
+                ))
+            })
+    }
 }
 
 impl Actor for PubSubService {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        let interval = (self.redelivery.timeout / 2).max(MIN_REDELIVERY_INTERVAL);
+        ctx.run_interval(interval, |pubsub, ctx| pubsub.redeliver_expired(ctx));
+        ctx.run_interval(CURSOR_PERSIST_INTERVAL, |pubsub, _| {
+            pubsub.persist_cursors()
+        });
+    }
+
+    fn stopped(&mut self, _: &mut Context<Self>) {
+        self.persist_cursors();
+    }
 }
 
 impl Handler<ManageSession> for PubSubService {
     type Result = Result<(), PublicationError>;
 
-    fn handle(&mut self, msg: ManageSession, _: &mut Self::Context) -> Self::Result {
-        Ok(match msg {
-            ManageSession::Add { client_id, addr } => {
-                self.sessions.insert(client_id, addr);
+    fn handle(&mut self, msg: ManageSession, ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            ManageSession::Add {
+                client_id,
+                session,
+                delivery,
+            } => {
+                self.sessions.insert(client_id, session);
+                match delivery {
+                    Some(mode) => self.session_delivery.insert(client_id, mode),
+                    None => self.session_delivery.remove(&client_id),
+                };
+                let missed: Vec<(Uuid, u64)> = self
+                    .subscriptions
+                    .iter()
+                    .filter(|s| s.cursors.contains_key(&client_id))
+                    .filter_map(|s| self.sequences.get(&s.id).map(|until| (s.id, *until)))
+                    .collect();
+                for (subscription_id, until) in missed {
+                    self.replay(client_id, subscription_id, until, ctx);
+                }
+                let waiting: Vec<Uuid> = self
+                    .queues
+                    .iter()
+                    .filter(|(_, q)| !q.backlog.is_empty())
+                    .map(|(id, _)| *id)
+                    .collect();
+                for id in waiting {
+                    if let Ok(subscription) = self.subscriptions.fetch(&id) {
+                        self.dispatch_queue(&subscription);
+                    }
+                }
             }
-            ManageSession::Remove { client_id } => {
+            ManageSession::Remove { client_id, session } => {
+                if self.sessions.get(&client_id) != Some(&session) {
+                    debug!(
+                        "Session of {} was replaced before it was removed",
+                        client_id
+                    );
+                    return Ok(());
+                }
                 self.sessions.remove(&client_id);
+                self.session_delivery.remove(&client_id);
+                self.in_flight.retain(|(_, c), _| c != &client_id);
+                self.replaying.retain(|(_, c), _| c != &client_id);
+                self.release_queued(&client_id, None);
+            }
+            ManageSession::SetDelivery { client_id, mode } => {
+                self.session_delivery.insert(client_id, mode);
             }
-        })
+        }
+        Ok(())
     }
 }
 
@@ -131,24 +1040,70 @@ impl Handler<ManageSubscription> for PubSubService {
             ManageSubscription::Add {
                 client_id,
                 subscription_id,
+                kind,
+                durable,
+                topic,
+                create,
             } => {
                 debug!(
                     "Handling SubscriptionCommand::Add for {} with param {}",
                     &client_id, &subscription_id
                 );
-                Ok(match self.subscriptions.fetch(&subscription_id) {
+                if let Some(topic) = &topic {
+                    validate_pattern(topic)?;
+                    if topic_id(topic) != subscription_id {
+                        return Err(PublicationError::InvalidTopic(format!(
+                            "{} does not identify topic {}",
+                            subscription_id, topic
+                        )));
+                    }
+                }
+                let mut subscription = match self.subscriptions.fetch(&subscription_id) {
                     Ok(mut s) => {
+                        s.access.check_subscriber(&client_id)?;
                         s.append_subscriber(&client_id);
-                        self.subscriptions.update(&s);
+                        s
                     }
+                    Err(e) if !create => return Err(e),
                     Err(e) => {
-                        info!("{} :: Creating new subscription.", e);
+                        info!("{} :: Creating new {:?} subscription.", e, kind);
                         let mut new_sub =
                             Subscription::new(&subscription_id, format!("{}", &client_id).as_str());
+                        new_sub.kind = kind;
+                        new_sub.access.owner = Some(client_id);
+                        if let Some(topic) = topic {
+                            new_sub.name = topic.clone();
+                            new_sub.topic = Some(topic);
+                        }
                         new_sub.append_subscriber(&client_id);
-                        self.subscriptions.update(&new_sub);
+                        new_sub
                     }
-                })
+                };
+                if subscription.topic.as_deref().map_or(false, is_pattern)
+                    && (durable || subscription.kind != SubscriptionKind::Broadcast)
+                {
+                    return Err(PublicationError::Subscriptions(
+                        "Pattern subscriptions can only be non-durable broadcasts",
+                    ));
+                }
+                if !durable {
+                    subscription.cursors.remove(&client_id);
+                } else if let SubscriptionKind::Queue(_) = subscription.kind {
+                    return Err(PublicationError::Subscriptions(
+                        "Queue-type subscriptions can not be subscribed durably",
+                    ));
+                } else {
+                    let last_sequence = self.sequences.get(&subscription_id).copied();
+                    subscription
+                        .cursors
+                        .entry(client_id)
+                        .or_insert_with(|| last_sequence.unwrap_or(0));
+                }
+                self.subscriptions.update(&subscription);
+                self.dispatch_queue(&subscription);
+                self.persist_subscription(&subscription)?;
+                self.deliver_retained(&client_id, &subscription);
+                Ok(())
             }
             ManageSubscription::Remove {
                 client_id,
@@ -160,58 +1115,414 @@ impl Handler<ManageSubscription> for PubSubService {
                 );
                 let mut s = self.subscriptions.fetch(&subscription_id)?;
                 s.remove_subscriber(&client_id);
-                Ok(if s.subscribers.is_empty() {
-                    self.subscriptions.remove(&subscription_id)
+                s.cursors.remove(&client_id);
+                self.in_flight.remove(&(subscription_id, client_id));
+                // Owned subscriptions are kept, so their ownership can not be claimed by others
+                if s.subscribers.is_empty() && s.access.owner.is_none() {
+                    self.subscriptions.remove(&subscription_id);
+                    self.queues.remove(&subscription_id);
+                    self.retained.remove(&subscription_id);
+                    self.forget_subscription(&subscription_id)
                 } else {
-                    self.subscriptions.update(&s)
-                })
+                    self.subscriptions.update(&s);
+                    self.release_queued(&client_id, Some(&subscription_id));
+                    self.persist_subscription(&s)
+                }
+            }
+            ManageSubscription::SetDelivery {
+                client_id,
+                subscription_id,
+                mode,
+            } => {
+                debug!(
+                    "Handling SubscriptionCommand::SetDelivery for {} with param {}",
+                    &client_id, &subscription_id
+                );
+                let mut s = self.subscriptions.fetch(&subscription_id)?;
+                s.access.check_owner(&client_id)?;
+                s.delivery = mode;
+                self.subscriptions.update(&s);
+                self.persist_subscription(&s)
+            }
+            ManageSubscription::SetRetention {
+                client_id,
+                subscription_id,
+                policy,
+            } => {
+                debug!(
+                    "Handling SubscriptionCommand::SetRetention for {} with param {}",
+                    &client_id, &subscription_id
+                );
+                let mut s = self.subscriptions.fetch(&subscription_id)?;
+                s.access.check_owner(&client_id)?;
+                s.retention = policy;
+                self.subscriptions.update(&s);
+                self.persist_subscription(&s)
+            }
+            ManageSubscription::SetAccess {
+                client_id,
+                subscription_id,
+                publishers,
+                subscribers,
+            } => {
+                debug!(
+                    "Handling SubscriptionCommand::SetAccess for {} with param {}",
+                    &client_id, &subscription_id
+                );
+                let mut s = self.subscriptions.fetch(&subscription_id)?;
+                s.access.check_owner(&client_id)?;
+                s.access.publishers = publishers;
+                s.access.subscribers = subscribers;
+                let (permitted, revoked): (Vec<Uuid>, Vec<Uuid>) = s
+                    .subscribers
+                    .iter()
+                    .partition(|c| s.access.check_subscriber(c).is_ok());
+                s.subscribers = permitted;
+                for c in &revoked {
+                    s.cursors.remove(c);
+                    self.in_flight.remove(&(subscription_id, *c));
+                }
+                self.subscriptions.update(&s);
+                for c in &revoked {
+                    self.release_queued(c, Some(&subscription_id));
+                }
+                self.persist_subscription(&s)
+            }
+            ManageSubscription::ClearRetained {
+                client_id,
+                subscription_id,
+            } => {
+                debug!(
+                    "Handling SubscriptionCommand::ClearRetained for {} with param {}",
+                    &client_id, &subscription_id
+                );
+                let mut s = self.subscriptions.fetch(&subscription_id)?;
+                s.access.check_publisher(&client_id)?;
+                self.retained.remove(&subscription_id);
+                if s.retained.take().is_none() {
+                    return Ok(());
+                }
+                self.subscriptions.update(&s);
+                self.persist_subscription(&s)
+            }
+            ManageSubscription::SetDeadLetter {
+                client_id,
+                subscription_id,
+            } => {
+                debug!(
+                    "Handling SubscriptionCommand::SetDeadLetter for {} with param {:?}",
+                    &client_id, &subscription_id
+                );
+                if let Some(Ok(current)) = self.dead_letter.map(|id| self.subscriptions.fetch(&id))
+                {
+                    current.access.check_owner(&client_id)?;
+                }
+                if let Some(subscription_id) = subscription_id {
+                    self.subscriptions
+                        .fetch(&subscription_id)?
+                        .access
+                        .check_owner(&client_id)?;
+                }
+                self.dead_letter = subscription_id;
+                Ok(())
             }
         }
     }
 }
 
 impl Handler<SubmitCommand> for PubSubService {
-    type Result = Result<(), PublicationError>;
+    type Result = ResponseActFuture<Self, Result<Uuid, PublicationError>>;
 
+    /// Distributes the publication once it was written to the data log
+    /// and resolves to the publication's id.
     fn handle(&mut self, msg: SubmitCommand, _: &mut Context<Self>) -> Self::Result {
         debug!(" {} submitted {:?}", msg.client_id, msg.submission);
-        Ok(
-            if let Ok(subscription) = self.subscriptions.fetch(&msg.subscription_id) {
-                let publication = Publication::new(&msg.subscription_id, &msg.submission);
-                self.data_log_addr
-                    .try_send(DataLogPut(vec![publication.clone()]))
-                    .map_err(|e| {
-                        PublicationError::DataLoggingError(format!(
-                            "Could not write published message to datalog: {}",
-                            e.to_string()
-                        ))
-                    })?;
-                for s in subscription.subscribers {
-                    if let Some(recipient) = self.sessions.get(&s) {
-                        recipient
-                            .try_send(Issue(subscription.id, publication.publication_id))
-                            .map_err(|e| PublicationError::Publishing(e.to_string()))?;
-                    }
-                }
-            },
+        if let Err(e) = self
+            .fetch_submission_target(&msg)
+            .and_then(|s| s.access.check_publisher(&msg.client_id))
+        {
+            return Box::pin(async move { Err(e) }.into_actor(self));
+        }
+        let sequence = self.next_sequence(&msg.subscription_id);
+        let mut publication = Publication::new(&msg.subscription_id, sequence, &msg.submission);
+        publication.key = msg.key;
+        publication.publisher = Some(msg.client_id);
+        publication.headers = msg.headers;
+        self.publish(publication, msg.retain)
+    }
+}
+
+impl Handler<Listen> for PubSubService {
+    type Result = Result<bool, PublicationError>;
+
+    fn handle(&mut self, msg: Listen, _: &mut Context<Self>) -> Self::Result {
+        let mut subscription = self.subscriptions.fetch(&msg.subscription_id)?;
+        subscription.access.check_subscriber(&msg.client_id)?;
+        if subscription.subscribers.contains(&msg.client_id) {
+            return Ok(false);
+        }
+        subscription.append_subscriber(&msg.client_id);
+        self.subscriptions.update(&subscription);
+        self.dispatch_queue(&subscription);
+        self.persist_subscription(&subscription)?;
+        self.deliver_retained(&msg.client_id, &subscription);
+        Ok(true)
+    }
+}
+
+impl Handler<CheckReadAccess> for PubSubService {
+    type Result = Result<(), PublicationError>;
+
+    fn handle(&mut self, msg: CheckReadAccess, _: &mut Context<Self>) -> Self::Result {
+        self.subscriptions
+            .fetch(&msg.subscription_id)?
+            .access
+            .check_subscriber(&msg.client_id)
+    }
+}
+
+impl Handler<TopicPull> for PubSubService {
+    type Result = Result<(), PublicationError>;
+
+    fn handle(&mut self, msg: TopicPull, _: &mut Context<Self>) -> Self::Result {
+        validate_pattern(&msg.pattern)?;
+        let subscriptions: Vec<Subscription> = self
+            .subscriptions
+            .iter()
+            .filter(|s| {
+                s.topic.as_deref().map_or(false, |topic| {
+                    !is_pattern(topic) && matches(&msg.pattern, topic)
+                })
+            })
+            .filter(|s| s.access.check_subscriber(&msg.client_id).is_ok())
+            .cloned()
+            .collect();
+        msg.client
+            .try_send(MetadataPut(subscriptions))
+            .map_err(|e| PublicationError::Publishing(e.to_string()))
+    }
+}
+
+impl Handler<AckCommand> for PubSubService {
+    type Result = Result<(), PublicationError>;
+
+    fn handle(&mut self, msg: AckCommand, ctx: &mut Context<Self>) -> Self::Result {
+        debug!(
+            "{} acknowledged {} of {}",
+            msg.client_id, msg.publication_id, msg.subscription_id
+        );
+        self.acknowledge(
+            &msg.client_id,
+            &msg.subscription_id,
+            &msg.publication_id,
+            ctx,
         )
     }
 }
 
-/// Represents an accepted Submission that can be stored and distributed
-#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+/// A publication delivered to a session that has not been acknowledged yet
+#[derive(Debug, Clone, PartialEq)]
+struct Delivery {
+    publication: Publication,
+    /// Number of times the publication was delivered
+    attempts: u32,
+    /// Time of the last delivery
+    sent: Instant,
+}
+
+/// Tracks how far the publications a durable subscriber missed were replayed
+#[derive(Debug, Clone, PartialEq)]
+struct Replay {
+    /// Sequence number of the last publication fetched from the data log
+    fetched: u64,
+    /// Sequence number of the last publication to replay, later ones are delivered as submitted
+    until: u64,
+    /// Whether a page of publications is being fetched
+    fetching: bool,
+}
+
+/// Tracks the publications of a queue-type subscription that have not been acknowledged yet
+#[derive(Debug, Default, Clone, PartialEq)]
+struct QueueState {
+    /// Publications waiting to be handed to a subscriber
+    backlog: VecDeque<Publication>,
+    /// Publications handed to a subscriber, in the order they were handed out
+    in_flight: Vec<(Publication, Uuid)>,
+    /// Number of publications handed out by round robin so far
+    handed_out: usize,
+}
+
+impl QueueState {
+    /// Selects the subscriber the next publication is handed to
+    fn pick_consumer(&mut self, consumers: &[Uuid], strategy: QueueStrategy) -> Option<Uuid> {
+        if consumers.is_empty() {
+            return None;
+        }
+        match strategy {
+            QueueStrategy::RoundRobin => {
+                let consumer = consumers[self.handed_out % consumers.len()];
+                self.handed_out = self.handed_out.wrapping_add(1);
+                Some(consumer)
+            }
+            QueueStrategy::LeastLoaded => consumers
+                .iter()
+                .min_by_key(|c| self.in_flight.iter().filter(|(_, i)| i == *c).count())
+                .copied(),
+        }
+    }
+
+    /// Moves publications handed to `client_id` back to the front of the backlog,
+    /// returning true if there were any
+    fn release(&mut self, client_id: &Uuid) -> bool {
+        let (released, remaining): (Vec<_>, Vec<_>) = self
+            .in_flight
+            .drain(..)
+            .partition(|(_, consumer)| consumer == client_id);
+        self.in_flight = remaining;
+        for (publication, _) in released.iter().rev() {
+            self.backlog.push_front(publication.clone());
+        }
+        !released.is_empty()
+    }
+}
+
+/// Represents an accepted Submission that can be stored and distributed.
+/// Sent to sessions receiving publications in full.
+#[derive(Debug, Clone, PartialEq, Deserialize, Message, Serialize)]
+#[rtype("Result<(), PublicationError>")]
 pub struct Publication {
     pub publication_id: Uuid,
     pub subscription_id: Uuid,
+    /// Position of the publication in its subscription's log, starting at 1.
+    /// A publication that could not be logged is not distributed and gives its number back,
+    /// unless later publications were numbered in the meantime. Gaps in the sequence
+    /// therefore stand for publications no subscriber received, not for lost ones.
+    #[serde(default)]
+    pub sequence: u64,
+    /// Time the publication was accepted by the server, in milliseconds since the UNIX epoch
+    #[serde(default)]
+    pub timestamp: u64,
+    /// Publications sharing a key replace each other when their log is compacted
+    #[serde(default)]
+    pub key: Option<String>,
+    /// The client that submitted the publication, `None` for publications created by the server
+    #[serde(default)]
+    pub publisher: Option<Uuid>,
+    /// Metadata describing the publication's data, see `CONTENT_TYPE_HEADER`
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
     pub data: Vec<u8>,
 }
 
 impl Publication {
-    fn new(subscription_id: &Uuid, data: &Vec<u8>) -> Self {
+    pub(crate) fn new(subscription_id: &Uuid, sequence: u64, data: &[u8]) -> Self {
         Publication {
             publication_id: Uuid::new_v4(),
             subscription_id: *subscription_id,
-            data: data.clone(),
+            sequence,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            key: None,
+            publisher: None,
+            headers: HashMap::new(),
+            data: data.to_vec(),
+        }
+    }
+}
+
+/// Determines how a `Subscription` distributes its publications
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SubscriptionKind {
+    /// Every connected subscriber is informed about every publication
+    #[default]
+    Broadcast,
+    /// Every publication is handed to exactly one connected subscriber,
+    /// which has to acknowledge it. Publications not acknowledged before their
+    /// subscriber disconnects are handed to another subscriber.
+    Queue(QueueStrategy),
+}
+
+/// Determines how subscribers are informed about a publication
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DeliveryMode {
+    /// An `Issue` carrying the ids of subscription and publication is sent,
+    /// the publication has to be fetched from the data log
+    #[default]
+    Notify,
+    /// The whole `Publication` is sent
+    Full,
+}
+
+/// Determines which subscriber of a queue-type `Subscription` receives a publication
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum QueueStrategy {
+    /// Subscribers take turns
+    RoundRobin,
+    /// The subscriber with the fewest unacknowledged publications is picked
+    LeastLoaded,
+}
+
+/// Determines which clients may use a `Subscription`.
+/// The owner is always granted access and is the only client allowed to change
+/// a Subscription's settings. Subscriptions without owner, e.g. recovered from metadata
+/// persisted before owners were recorded, can't be managed by any client.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccessControl {
+    /// The client that created the Subscription
+    pub owner: Option<Uuid>,
+    /// Clients allowed to submit publications, `None` allows every client
+    pub publishers: Option<Vec<Uuid>>,
+    /// Clients allowed to subscribe, `None` allows every client
+    pub subscribers: Option<Vec<Uuid>>,
+}
+
+impl AccessControl {
+    fn is_owner(&self, client_id: &Uuid) -> bool {
+        self.owner.as_ref() == Some(client_id)
+    }
+
+    fn permits(&self, permitted: &Option<Vec<Uuid>>, client_id: &Uuid) -> bool {
+        self.is_owner(client_id)
+            || permitted
+                .as_ref()
+                .map_or(true, |clients| clients.contains(client_id))
+    }
+
+    /// Fails unless the client may change the Subscription's settings
+    pub fn check_owner(&self, client_id: &Uuid) -> Result<(), PublicationError> {
+        if self.is_owner(client_id) {
+            Ok(())
+        } else {
+            Err(PublicationError::AccessDenied(format!(
+                "{} does not own the subscription",
+                client_id
+            )))
+        }
+    }
+
+    /// Fails unless the client may submit publications
+    pub fn check_publisher(&self, client_id: &Uuid) -> Result<(), PublicationError> {
+        if self.permits(&self.publishers, client_id) {
+            Ok(())
+        } else {
+            Err(PublicationError::AccessDenied(format!(
+                "{} may not publish to the subscription",
+                client_id
+            )))
+        }
+    }
+
+    /// Fails unless the client may subscribe
+    pub fn check_subscriber(&self, client_id: &Uuid) -> Result<(), PublicationError> {
+        if self.permits(&self.subscribers, client_id) {
+            Ok(())
+        } else {
+            Err(PublicationError::AccessDenied(format!(
+                "{} may not subscribe to the subscription",
+                client_id
+            )))
         }
     }
 }
@@ -225,6 +1536,29 @@ pub struct Subscription {
     pub name: String,
     /// List of currently subscribed clients
     pub subscribers: Vec<Uuid>,
+    /// How publications are distributed to subscribers
+    #[serde(default)]
+    pub kind: SubscriptionKind,
+    /// Which publications are removed from the subscription's log
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+    /// How publications are delivered to subscribers. Takes precedence over
+    /// the mode chosen by a subscriber's session, e.g. to keep large payloads out of notifications.
+    #[serde(default)]
+    pub delivery: Option<DeliveryMode>,
+    /// Which clients may publish to, subscribe to and manage the subscription
+    #[serde(default)]
+    pub access: AccessControl,
+    /// Sequence number of the last publication each durable subscriber acknowledged
+    #[serde(default)]
+    pub cursors: HashMap<Uuid, u64>,
+    /// Hierarchical topic the subscription is addressed by, e.g. `sensors/building-a/temp`.
+    /// Topics containing wildcards are patterns matching other topics.
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// Id of the publication new subscribers receive right away, if one was retained
+    #[serde(default)]
+    pub retained: Option<Uuid>,
 }
 
 impl Subscription {
@@ -234,6 +1568,13 @@ impl Subscription {
             id: *id,
             name: name.to_owned(),
             subscribers: Vec::new(),
+            kind: SubscriptionKind::default(),
+            retention: RetentionPolicy::default(),
+            delivery: None,
+            access: AccessControl::default(),
+            cursors: HashMap::new(),
+            topic: None,
+            retained: None,
         }
     }
 
@@ -254,16 +1595,16 @@ impl Subscription {
 
 /// Holds the subscription store. Subscriptions are stored
 /// in a HashMap, identified by their id.
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Subscriptions {
-    store: Box<HashMap<Uuid, Subscription>>,
+    store: HashMap<Uuid, Subscription>,
 }
 
 impl Subscriptions {
     /// Initialize a new subscription store
     pub fn new() -> Subscriptions {
         Subscriptions {
-            store: Box::new(HashMap::new()),
+            store: HashMap::new(),
         }
     }
 
@@ -278,18 +1619,58 @@ impl Subscriptions {
         self.store
             .get(id)
             .ok_or(PublicationError::Subscriptions("Subscription not found"))
-            .and_then(|s| Ok(s.to_owned()))
+            .map(|s| s.to_owned())
     }
 
     /// Removes a subscription from the subscription store
     pub fn remove(&mut self, id: &Uuid) {
         self.store.remove(id);
     }
+
+    /// Iterates over all subscriptions in the store
+    pub fn iter(&self) -> impl Iterator<Item = &Subscription> {
+        self.store.values()
+    }
+}
+
+impl FromIterator<Subscription> for Subscriptions {
+    fn from_iter<I: IntoIterator<Item = Subscription>>(iter: I) -> Self {
+        Subscriptions {
+            store: iter.into_iter().map(|s| (s.id, s)).collect(),
+        }
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use futures_channel::mpsc::{unbounded, UnboundedSender};
+    use futures_util::stream::StreamExt;
+
+    /// A session passing on the ids of the publications it is informed about
+    struct TestSession(UnboundedSender<Uuid>);
+
+    impl Actor for TestSession {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Issue> for TestSession {
+        type Result = Result<(), PublicationError>;
+
+        fn handle(&mut self, msg: Issue, _: &mut Context<Self>) -> Self::Result {
+            self.0.unbounded_send(msg.1).ok();
+            Ok(())
+        }
+    }
+
+    impl Handler<Publication> for TestSession {
+        type Result = Result<(), PublicationError>;
+
+        fn handle(&mut self, msg: Publication, _: &mut Context<Self>) -> Self::Result {
+            self.0.unbounded_send(msg.publication_id).ok();
+            Ok(())
+        }
+    }
 
     #[test]
     fn test_subscription() {
@@ -299,10 +1680,7 @@ pub mod tests {
         dummy_subscription.append_subscriber(&dummy_client);
         assert!(dummy_subscription.subscribers.contains(&dummy_client));
         dummy_subscription.remove_subscriber(&dummy_client);
-        assert_eq!(
-            dummy_subscription.subscribers.contains(&dummy_client),
-            false
-        );
+        assert!(!dummy_subscription.subscribers.contains(&dummy_client));
     }
 
     #[test]
@@ -315,4 +1693,376 @@ pub mod tests {
         subscriptions.remove(&fetched_subscription.id);
         assert!(subscriptions.fetch(&fetched_subscription.id).is_err())
     }
+
+    #[actix_rt::test]
+    async fn test_assigning_sequence_numbers() {
+        let data_log = DataLogger::ephemeral().start();
+        let subscription_id = Uuid::new_v4();
+        let mut sequences = HashMap::new();
+        sequences.insert(subscription_id, 41);
+        let mut pubsub = PubSubService::recover(
+            &data_log,
+            RecoveredState {
+                subscriptions: Vec::new(),
+                sequences,
+                retained: HashMap::new(),
+            },
+        );
+        assert_eq!(pubsub.next_sequence(&subscription_id), 42);
+        assert_eq!(pubsub.next_sequence(&subscription_id), 43);
+        assert_eq!(pubsub.next_sequence(&Uuid::new_v4()), 1);
+        // Only the last number assigned can be given back
+        pubsub.release_sequence(&subscription_id, 42);
+        pubsub.release_sequence(&subscription_id, 43);
+        assert_eq!(pubsub.next_sequence(&subscription_id), 43);
+    }
+
+    #[test]
+    fn test_queue_state() {
+        let consumers = [Uuid::new_v4(), Uuid::new_v4()];
+        let subscription_id = Uuid::new_v4();
+        let publications: Vec<Publication> = (1..=3)
+            .map(|sequence| Publication::new(&subscription_id, sequence, b"Test Publication"))
+            .collect();
+        let mut queue = QueueState::default();
+        assert_eq!(
+            queue.pick_consumer(&consumers, QueueStrategy::RoundRobin),
+            Some(consumers[0])
+        );
+        assert_eq!(
+            queue.pick_consumer(&consumers, QueueStrategy::RoundRobin),
+            Some(consumers[1])
+        );
+        assert_eq!(queue.pick_consumer(&[], QueueStrategy::RoundRobin), None);
+        queue
+            .in_flight
+            .push((publications[0].clone(), consumers[0]));
+        queue
+            .in_flight
+            .push((publications[1].clone(), consumers[1]));
+        queue
+            .in_flight
+            .push((publications[2].clone(), consumers[0]));
+        assert_eq!(
+            queue.pick_consumer(&consumers, QueueStrategy::LeastLoaded),
+            Some(consumers[1])
+        );
+        assert!(queue.release(&consumers[0]));
+        assert_eq!(
+            queue.in_flight,
+            vec![(publications[1].clone(), consumers[1])]
+        );
+        assert_eq!(
+            queue.backlog,
+            vec![publications[0].clone(), publications[2].clone()]
+                .into_iter()
+                .collect::<VecDeque<Publication>>()
+        );
+        assert!(!queue.release(&consumers[0]));
+    }
+
+    #[test]
+    fn test_access_control() {
+        let (owner, friend, stranger) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let open = AccessControl::default();
+        assert!(open.check_owner(&stranger).is_err());
+        assert!(open.check_publisher(&stranger).is_ok());
+        assert!(open.check_subscriber(&stranger).is_ok());
+        let restricted = AccessControl {
+            owner: Some(owner),
+            publishers: Some(Vec::new()),
+            subscribers: Some(vec![friend]),
+        };
+        assert!(restricted.check_owner(&owner).is_ok());
+        assert!(restricted.check_publisher(&owner).is_ok());
+        assert!(restricted.check_subscriber(&friend).is_ok());
+        assert!(matches!(
+            restricted.check_owner(&friend),
+            Err(PublicationError::AccessDenied(_))
+        ));
+        assert!(matches!(
+            restricted.check_publisher(&friend),
+            Err(PublicationError::AccessDenied(_))
+        ));
+        assert!(matches!(
+            restricted.check_subscriber(&stranger),
+            Err(PublicationError::AccessDenied(_))
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn test_enforcing_access_control() {
+        let pubsub = PubSubService::new(&DataLogger::ephemeral().start()).start();
+        let (owner, friend, stranger) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let subscription_id = Uuid::new_v4();
+        let add = |client_id| ManageSubscription::Add {
+            client_id,
+            subscription_id,
+            kind: SubscriptionKind::Broadcast,
+            durable: false,
+            topic: None,
+            create: true,
+        };
+        // Subscriptions are only created on request
+        let join = ManageSubscription::Add {
+            client_id: stranger,
+            subscription_id,
+            kind: SubscriptionKind::Broadcast,
+            durable: false,
+            topic: None,
+            create: false,
+        };
+        assert!(matches!(
+            pubsub.send(join).await.unwrap(),
+            Err(PublicationError::Subscriptions(_))
+        ));
+        pubsub.send(add(owner)).await.unwrap().unwrap();
+        pubsub.send(add(stranger)).await.unwrap().unwrap();
+        let restrict = |client_id| ManageSubscription::SetAccess {
+            client_id,
+            subscription_id,
+            publishers: Some(vec![friend]),
+            subscribers: Some(vec![friend]),
+        };
+        assert!(matches!(
+            pubsub.send(restrict(stranger)).await.unwrap(),
+            Err(PublicationError::AccessDenied(_))
+        ));
+        pubsub.send(restrict(owner)).await.unwrap().unwrap();
+        assert!(matches!(
+            pubsub.send(add(stranger)).await.unwrap(),
+            Err(PublicationError::AccessDenied(_))
+        ));
+        pubsub.send(add(friend)).await.unwrap().unwrap();
+        let submit = |client_id| SubmitCommand::new(&client_id, &subscription_id, b"Test");
+        assert!(matches!(
+            pubsub.send(submit(stranger)).await.unwrap(),
+            Err(PublicationError::AccessDenied(_))
+        ));
+        assert!(pubsub.send(submit(friend)).await.unwrap().is_ok());
+        assert!(pubsub.send(submit(owner)).await.unwrap().is_ok());
+        assert!(matches!(
+            pubsub
+                .send(ManageSubscription::SetDelivery {
+                    client_id: friend,
+                    subscription_id,
+                    mode: Some(DeliveryMode::Full),
+                })
+                .await
+                .unwrap(),
+            Err(PublicationError::AccessDenied(_))
+        ));
+        for client_id in [owner, friend] {
+            pubsub
+                .send(ManageSubscription::Remove {
+                    client_id,
+                    subscription_id,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert!(matches!(
+            pubsub.send(add(stranger)).await.unwrap(),
+            Err(PublicationError::AccessDenied(_))
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn test_setting_dead_letter() {
+        let pubsub = PubSubService::new(&DataLogger::ephemeral().start()).start();
+        let (owner, stranger) = (Uuid::new_v4(), Uuid::new_v4());
+        let (dead_letter_id, other_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let set = |client_id, subscription_id| ManageSubscription::SetDeadLetter {
+            client_id,
+            subscription_id,
+        };
+        assert!(matches!(
+            pubsub.send(set(owner, Some(dead_letter_id))).await.unwrap(),
+            Err(PublicationError::Subscriptions(_))
+        ));
+        for (client_id, subscription_id) in [(owner, dead_letter_id), (stranger, other_id)] {
+            pubsub
+                .send(ManageSubscription::Add {
+                    client_id,
+                    subscription_id,
+                    kind: SubscriptionKind::Broadcast,
+                    durable: false,
+                    topic: None,
+                    create: true,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert!(matches!(
+            pubsub
+                .send(set(stranger, Some(dead_letter_id)))
+                .await
+                .unwrap(),
+            Err(PublicationError::AccessDenied(_))
+        ));
+        pubsub
+            .send(set(owner, Some(dead_letter_id)))
+            .await
+            .unwrap()
+            .unwrap();
+        // Only the owner of the current dead-letter subscription may replace it
+        assert!(matches!(
+            pubsub.send(set(stranger, Some(other_id))).await.unwrap(),
+            Err(PublicationError::AccessDenied(_))
+        ));
+        pubsub.send(set(owner, None)).await.unwrap().unwrap();
+        pubsub
+            .send(set(stranger, Some(other_id)))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_distributing_past_unreachable_subscribers() {
+        let pubsub = PubSubService::new(&DataLogger::ephemeral().start()).start();
+        let subscription_id = Uuid::new_v4();
+        let (sender, mut receiver) = unbounded();
+        let reachable = TestSession(sender).start();
+        let mut sessions = vec![(Uuid::new_v4(), SessionHandle::new(&reachable))];
+        for _ in 0..8 {
+            // A session whose mailbox is gone refuses every message
+            let (mailbox, _) = actix::dev::channel::channel::<TestSession>(16);
+            let unreachable = Addr::new(mailbox);
+            sessions.push((Uuid::new_v4(), SessionHandle::new(&unreachable)));
+        }
+        for (client_id, session) in sessions.iter().cloned() {
+            pubsub
+                .send(ManageSession::Add {
+                    client_id,
+                    session,
+                    delivery: None,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            pubsub
+                .send(ManageSubscription::Add {
+                    client_id,
+                    subscription_id,
+                    kind: SubscriptionKind::Broadcast,
+                    durable: false,
+                    topic: None,
+                    create: true,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        let publication_id = pubsub
+            .send(SubmitCommand::new(
+                &sessions[0].0,
+                &subscription_id,
+                b"Test",
+            ))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(receiver.next().await, Some(publication_id));
+    }
+
+    #[actix_rt::test]
+    async fn test_keeping_replacing_sessions() {
+        let pubsub = PubSubService::new(&DataLogger::ephemeral().start()).start();
+        let (client_id, subscription_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let (replaced_sender, _) = unbounded();
+        let replaced = SessionHandle::new(&TestSession(replaced_sender).start());
+        let (sender, mut receiver) = unbounded();
+        let replacing = SessionHandle::new(&TestSession(sender).start());
+        for session in [replaced.clone(), replacing] {
+            pubsub
+                .send(ManageSession::Add {
+                    client_id,
+                    session,
+                    delivery: None,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        pubsub
+            .send(ManageSubscription::Add {
+                client_id,
+                subscription_id,
+                kind: SubscriptionKind::Broadcast,
+                durable: false,
+                topic: None,
+                create: true,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        // The replaced session closing must not unregister the one replacing it
+        pubsub
+            .send(ManageSession::Remove {
+                client_id,
+                session: replaced,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let publication_id = pubsub
+            .send(SubmitCommand::new(&client_id, &subscription_id, b"Test"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(receiver.next().await, Some(publication_id));
+    }
+
+    #[actix_rt::test]
+    async fn test_listening() {
+        let pubsub = PubSubService::new(&DataLogger::ephemeral().start()).start();
+        let (client_id, subscription_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let listen = |client_id| Listen {
+            client_id,
+            subscription_id,
+        };
+        assert!(pubsub.send(listen(client_id)).await.unwrap().is_err());
+        pubsub
+            .send(ManageSubscription::Add {
+                client_id,
+                subscription_id,
+                kind: SubscriptionKind::Broadcast,
+                durable: true,
+                topic: None,
+                create: true,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pubsub.send(listen(client_id)).await.unwrap(), Ok(false));
+        assert_eq!(pubsub.send(listen(Uuid::new_v4())).await.unwrap(), Ok(true));
+        // The durable cursor is kept, so publications missed without a session are replayed
+        let publication_id = pubsub
+            .send(SubmitCommand::new(&client_id, &subscription_id, b"Test"))
+            .await
+            .unwrap()
+            .unwrap();
+        let (sender, mut receiver) = unbounded();
+        let session = SessionHandle::new(&TestSession(sender).start());
+        pubsub
+            .send(ManageSession::Add {
+                client_id,
+                session,
+                delivery: None,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(receiver.next().await, Some(publication_id));
+    }
+
+    #[test]
+    fn test_collecting_subscriptions() {
+        let subscription = Subscription::new(&Uuid::new_v4(), "Test Subscription");
+        let subscriptions: Subscriptions = vec![subscription.clone()].into_iter().collect();
+        assert_eq!(subscriptions.fetch(&subscription.id).unwrap(), subscription);
+    }
 }
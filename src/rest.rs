@@ -0,0 +1,635 @@
+use std::collections::{BTreeMap, HashMap};
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use actix::prelude::{Actor, ActorContext, Addr, Context, Handler, Message, Recipient};
+use actix_web::{
+    error::ResponseError,
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse,
+};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    auth::{AuthError, Authenticator},
+    data_log::{
+        DataLogError, DataLogPull, DataLogPut, DataLogger, LogIndexPull, LogIndexPut, LogPosition,
+        LogRangePull, LogRangePut, MetadataPull, MetadataPut, RangeDirection, RetentionPolicy,
+        MAX_RANGE_LIMIT,
+    },
+    pubsub::{
+        CheckReadAccess, PubSubService, Publication, PublicationError, SubmitCommand, Subscription,
+        SubscriptionKind, CONTENT_TYPE_HEADER,
+    },
+    websocket::{CommandOutcome, ErrorCode, ErrorResponse},
+};
+
+/// Header naming the client a request is meant to be authenticated as, see `identify_client`
+pub const CLIENT_ID_HEADER: &str = "x-client-id";
+
+impl ResponseError for ErrorResponse {
+    fn status_code(&self) -> StatusCode {
+        match self.code {
+            ErrorCode::InvalidInput | ErrorCode::UnsupportedVersion => StatusCode::BAD_REQUEST,
+            ErrorCode::SubscriptionNotFound | ErrorCode::PublicationNotFound => {
+                StatusCode::NOT_FOUND
+            }
+            ErrorCode::AccessDenied => StatusCode::FORBIDDEN,
+            ErrorCode::PublishingFailed | ErrorCode::DataCorrupted | ErrorCode::DataLogFailure => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self)
+    }
+}
+
+/// Registers the routes of the HTTP API, e.g. `App::new().service(web::scope("/api").configure(configure))`.
+/// Like the websocket interface, they expect `web::Data<Addr<PubSubService>>`
+/// and `web::Data<Addr<DataLogger>>` to be registered.
+///
+/// Every route identifies the client, see `identify_client`, requests without an `Authenticator`
+/// are anonymous. Reading a subscription's metadata or log requires permission to subscribe to it.
+///
+/// * `GET /subscriptions`: the `SubscriptionInfo` of all subscriptions the client may read
+/// * `GET /subscriptions/{id}`: the `SubscriptionInfo` of a subscription
+/// * `POST /subscriptions/{id}/publications`: publishes the request body
+/// * `GET /subscriptions/{id}/publications`: a page of publications, see `RangeQuery`
+/// * `GET /subscriptions/{id}/publications/{publication_id}`: a single publication
+/// * `GET /subscriptions/{id}/index`: the log index, mapping sequence numbers to publication ids
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/subscriptions", web::get().to(list_subscriptions))
+        .route("/subscriptions/{id}", web::get().to(get_subscription))
+        .route(
+            "/subscriptions/{id}/publications",
+            web::post().to(submit_publication),
+        )
+        .route(
+            "/subscriptions/{id}/publications",
+            web::get().to(get_publication_range),
+        )
+        .route(
+            "/subscriptions/{id}/publications/{publication_id}",
+            web::get().to(get_publication),
+        )
+        .route("/subscriptions/{id}/index", web::get().to(get_log_index));
+}
+
+/// Identifies the client making a request through the registered `Authenticator`.
+/// Without one, the request is anonymous and `None` is returned: the `x-client-id` header
+/// would let any caller claim to be any client, so it is not trusted on its own.
+/// Authenticated requests may still name their client by it, which then has to match.
+pub fn identify_client(req: &HttpRequest) -> Result<Option<Uuid>, AuthError> {
+    let authenticator = match req.app_data::<web::Data<dyn Authenticator>>() {
+        Some(authenticator) => authenticator,
+        None => return Ok(None),
+    };
+    let client_id = authenticator.authenticate(req)?;
+    let named = match req.headers().get(CLIENT_ID_HEADER) {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|id| Uuid::from_str(id).ok())
+            .ok_or(AuthError::InvalidCredentials)?,
+        None => return Ok(Some(client_id)),
+    };
+    if named != client_id {
+        return Err(AuthError::IdentityMismatch(client_id, named));
+    }
+    Ok(Some(client_id))
+}
+
+/// Identifies the client making a request like `identify_client`,
+/// giving an anonymous request a new client id of its own
+pub fn identify_or_anonymous(req: &HttpRequest) -> Result<Uuid, AuthError> {
+    Ok(identify_client(req)?.unwrap_or_else(Uuid::new_v4))
+}
+
+/// Options of a submission, see `SubmitCommand`
+#[derive(Debug, Deserialize)]
+pub struct SubmitQuery {
+    pub key: Option<String>,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+/// Selects a page of publications. The page starts at `timestamp` if it is given,
+/// otherwise at `sequence`, the start of the log by default.
+#[derive(Debug, Deserialize)]
+pub struct RangeQuery {
+    pub sequence: Option<u64>,
+    pub timestamp: Option<u64>,
+    pub limit: Option<usize>,
+    pub direction: Option<RangeDirection>,
+}
+
+/// Selects the part of a log index after a sequence number
+#[derive(Debug, Deserialize)]
+pub struct IndexQuery {
+    pub since: Option<u64>,
+}
+
+/// The metadata of a `Subscription` returned by the HTTP API.
+/// Its subscribers, access control and cursors stay private.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SubscriptionInfo {
+    pub id: Uuid,
+    pub kind: SubscriptionKind,
+    pub topic: Option<String>,
+    pub retention: RetentionPolicy,
+}
+
+impl From<Subscription> for SubscriptionInfo {
+    fn from(subscription: Subscription) -> Self {
+        SubscriptionInfo {
+            id: subscription.id,
+            kind: subscription.kind,
+            topic: subscription.topic,
+            retention: subscription.retention,
+        }
+    }
+}
+
+/// Publishes the request body to a subscription. The request's `Content-Type`
+/// is stored as the publication's `content-type` header.
+async fn submit_publication(
+    req: HttpRequest,
+    subscription_id: web::Path<Uuid>,
+    query: web::Query<SubmitQuery>,
+    body: web::Bytes,
+    pubsub: web::Data<Addr<PubSubService>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let client_id = identify_or_anonymous(&req)?;
+    let mut headers = HashMap::new();
+    if let Some(content_type) = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    {
+        headers.insert(String::from(CONTENT_TYPE_HEADER), content_type.to_owned());
+    }
+    let query = query.into_inner();
+    let publication_id = pubsub
+        .send(
+            SubmitCommand::new(&client_id, &subscription_id, &body)
+                .with_key(query.key)
+                .with_retain(query.retain)
+                .with_headers(headers),
+        )
+        .await
+        .map_err(ErrorResponse::from)?
+        .map_err(ErrorResponse::from)?;
+    debug!("{} published {} via HTTP", client_id, publication_id);
+    Ok(HttpResponse::Created().json(CommandOutcome::from(publication_id)))
+}
+
+async fn list_subscriptions(
+    req: HttpRequest,
+    datalog: web::Data<Addr<DataLogger>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let client_id = identify_or_anonymous(&req)?;
+    let subscriptions: Vec<SubscriptionInfo> =
+        collect(&datalog, |client| MetadataPull::All { client })
+            .await?
+            .map(|put: MetadataPut<Subscription>| put.0)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|s| s.access.check_subscriber(&client_id).is_ok())
+            .map(SubscriptionInfo::from)
+            .collect();
+    Ok(HttpResponse::Ok().json(subscriptions))
+}
+
+async fn get_subscription(
+    req: HttpRequest,
+    subscription_id: web::Path<Uuid>,
+    pubsub: web::Data<Addr<PubSubService>>,
+    datalog: web::Data<Addr<DataLogger>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let client_id = identify_or_anonymous(&req)?;
+    check_read_access(&pubsub, client_id, *subscription_id).await?;
+    let subscription = collect(&datalog, |client| MetadataPull::Single {
+        client,
+        data_log_id: *subscription_id,
+    })
+    .await?
+    .and_then(|put: MetadataPut<Subscription>| put.0.into_iter().next())
+    .ok_or_else(|| {
+        ErrorResponse::from(PublicationError::Subscriptions("Subscription not found"))
+    })?;
+    Ok(HttpResponse::Ok().json(SubscriptionInfo::from(subscription)))
+}
+
+async fn get_publication(
+    req: HttpRequest,
+    path: web::Path<(Uuid, Uuid)>,
+    pubsub: web::Data<Addr<PubSubService>>,
+    datalog: web::Data<Addr<DataLogger>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let client_id = identify_or_anonymous(&req)?;
+    let (data_log_id, publication_id) = path.into_inner();
+    check_read_access(&pubsub, client_id, data_log_id).await?;
+    let publication = collect(&datalog, |client| DataLogPull {
+        data_log_id,
+        client,
+        selection: vec![publication_id],
+    })
+    .await?
+    .and_then(|put: DataLogPut<Publication>| put.0.into_iter().next())
+    .ok_or_else(|| ErrorResponse::from(DataLogError::EntryNotFound(publication_id)))?;
+    Ok(HttpResponse::Ok().json(publication))
+}
+
+async fn get_publication_range(
+    req: HttpRequest,
+    data_log_id: web::Path<Uuid>,
+    query: web::Query<RangeQuery>,
+    pubsub: web::Data<Addr<PubSubService>>,
+    datalog: web::Data<Addr<DataLogger>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let client_id = identify_or_anonymous(&req)?;
+    check_read_access(&pubsub, client_id, *data_log_id).await?;
+    let start = match query.timestamp {
+        Some(timestamp) => LogPosition::Timestamp(timestamp),
+        None => LogPosition::Sequence(query.sequence.unwrap_or(0)),
+    };
+    let range = collect(&datalog, |client| LogRangePull {
+        client,
+        data_log_id: *data_log_id,
+        start,
+        limit: query.limit.unwrap_or(MAX_RANGE_LIMIT),
+        direction: query.direction.unwrap_or(RangeDirection::Forward),
+    })
+    .await?
+    .unwrap_or(LogRangePut {
+        data_log_id: *data_log_id,
+        entries: Vec::new(),
+        next: None,
+    });
+    Ok(HttpResponse::Ok().json(range))
+}
+
+async fn get_log_index(
+    req: HttpRequest,
+    data_log_id: web::Path<Uuid>,
+    query: web::Query<IndexQuery>,
+    pubsub: web::Data<Addr<PubSubService>>,
+    datalog: web::Data<Addr<DataLogger>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let client_id = identify_or_anonymous(&req)?;
+    check_read_access(&pubsub, client_id, *data_log_id).await?;
+    let index = collect(&datalog, |client| LogIndexPull {
+        client,
+        data_log_id: *data_log_id,
+        since: query.since,
+    })
+    .await?
+    .map(|put: LogIndexPut| put.1)
+    .unwrap_or_else(BTreeMap::new);
+    Ok(HttpResponse::Ok().json(index))
+}
+
+/// Fails unless the client may read the log and metadata of a subscription, see `CheckReadAccess`
+async fn check_read_access(
+    pubsub: &Addr<PubSubService>,
+    client_id: Uuid,
+    subscription_id: Uuid,
+) -> Result<(), ErrorResponse> {
+    pubsub
+        .send(CheckReadAccess {
+            client_id,
+            subscription_id,
+        })
+        .await?
+        .map_err(ErrorResponse::from)
+}
+
+/// Sends a pull message to the `DataLogger` and returns the reply it sends to the message's
+/// client, or `None` if it sent none.
+async fn collect<M, P, F>(datalog: &Addr<DataLogger>, pull: F) -> Result<Option<M>, ErrorResponse>
+where
+    M: Message<Result = Result<(), DataLogError>> + Unpin + Send + 'static,
+    P: Message<Result = Result<(), DataLogError>> + Send + 'static,
+    F: FnOnce(Recipient<M>) -> P,
+    DataLogger: Handler<P>,
+{
+    let collector = Collector { reply: None }.start();
+    datalog.send(pull(collector.clone().recipient())).await??;
+    // The reply was queued before the pull was answered, so it is collected first
+    Ok(collector.send(Take(PhantomData)).await?)
+}
+
+/// Keeps the reply a `DataLogger` sends to the client of a pull message
+struct Collector<M> {
+    reply: Option<M>,
+}
+
+impl<M: Unpin + 'static> Actor for Collector<M> {
+    type Context = Context<Self>;
+}
+
+impl<M> Handler<M> for Collector<M>
+where
+    M: Message<Result = Result<(), DataLogError>> + Unpin + 'static,
+{
+    type Result = Result<(), DataLogError>;
+
+    fn handle(&mut self, msg: M, _: &mut Context<Self>) -> Self::Result {
+        self.reply = Some(msg);
+        Ok(())
+    }
+}
+
+/// Hands out the reply a `Collector` received and stops it
+struct Take<M>(PhantomData<M>);
+
+impl<M: 'static> Message for Take<M> {
+    type Result = Option<M>;
+}
+
+impl<M: Unpin + 'static> Handler<Take<M>> for Collector<M> {
+    type Result = Option<M>;
+
+    fn handle(&mut self, _: Take<M>, ctx: &mut Context<Self>) -> Self::Result {
+        ctx.stop();
+        self.reply.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::{test, App};
+
+    use crate::pubsub::ManageSubscription;
+    use crate::test_utils::{bearer, id_token_authenticator};
+
+    #[actix_rt::test]
+    async fn test_rest_api() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub = PubSubService::new(&data_log).start();
+        let (client_id, subscription_id) = (Uuid::new_v4(), Uuid::new_v4());
+        pubsub
+            .send(ManageSubscription::Add {
+                client_id,
+                subscription_id,
+                kind: SubscriptionKind::Broadcast,
+                durable: false,
+                topic: None,
+                create: true,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(pubsub.clone())
+                .data(data_log.clone())
+                .app_data(id_token_authenticator(&[client_id]))
+                .configure(configure),
+        )
+        .await;
+        let publications = format!("/subscriptions/{}/publications", subscription_id);
+        let req = test::TestRequest::post()
+            .uri(&publications)
+            .set_payload("anonymous")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::post()
+            .uri(&publications)
+            .header(header::AUTHORIZATION, bearer(&client_id))
+            .header(CLIENT_ID_HEADER, Uuid::new_v4().to_string())
+            .set_payload("impostor")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let req = test::TestRequest::post()
+            .uri(&format!("/subscriptions/{}/publications", Uuid::new_v4()))
+            .header(header::AUTHORIZATION, bearer(&client_id))
+            .set_payload("lost")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let req = test::TestRequest::post()
+            .uri(&format!("{}?retain=true", publications))
+            .header(header::AUTHORIZATION, bearer(&client_id))
+            .header(header::CONTENT_TYPE, "text/plain")
+            .set_payload("Test Publication")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let publication_id = match test::read_body_json(resp).await {
+            CommandOutcome::Published { publication_id } => publication_id,
+            o => panic!("Unexpected outcome: {:?}", o),
+        };
+        let req = test::TestRequest::get()
+            .uri(&format!("/subscriptions/{}/index", subscription_id))
+            .header(header::AUTHORIZATION, bearer(&client_id))
+            .to_request();
+        let index: BTreeMap<u64, Uuid> = test::read_response_json(&mut app, req).await;
+        assert_eq!(index.get(&1), Some(&publication_id));
+        let req = test::TestRequest::get()
+            .uri(&format!("{}/{}", publications, publication_id))
+            .header(header::AUTHORIZATION, bearer(&client_id))
+            .to_request();
+        let publication: Publication = test::read_response_json(&mut app, req).await;
+        assert_eq!(publication.data, b"Test Publication");
+        assert_eq!(publication.publisher, Some(client_id));
+        assert_eq!(
+            publication
+                .headers
+                .get(CONTENT_TYPE_HEADER)
+                .map(String::as_str),
+            Some("text/plain")
+        );
+        let req = test::TestRequest::get()
+            .uri(&format!("{}/{}", publications, Uuid::new_v4()))
+            .header(header::AUTHORIZATION, bearer(&client_id))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "{}?sequence=1&limit=10&direction=Forward",
+                publications
+            ))
+            .header(header::AUTHORIZATION, bearer(&client_id))
+            .to_request();
+        let range: LogRangePut = test::read_response_json(&mut app, req).await;
+        assert_eq!(range.entries, vec![publication]);
+        assert_eq!(range.next, None);
+        let req = test::TestRequest::get()
+            .uri("/subscriptions")
+            .header(header::AUTHORIZATION, bearer(&client_id))
+            .to_request();
+        let info = SubscriptionInfo {
+            id: subscription_id,
+            kind: SubscriptionKind::Broadcast,
+            topic: None,
+            retention: RetentionPolicy::default(),
+        };
+        let subscriptions: Vec<SubscriptionInfo> = test::read_response_json(&mut app, req).await;
+        assert_eq!(subscriptions, vec![info]);
+        // Subscribers, access control and cursors are left out
+        let req = test::TestRequest::get()
+            .uri(&format!("/subscriptions/{}", subscription_id))
+            .header(header::AUTHORIZATION, bearer(&client_id))
+            .to_request();
+        let subscription: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(
+            subscription,
+            serde_json::json!({
+                "id": subscription_id,
+                "kind": "Broadcast",
+                "topic": null,
+                "retention": RetentionPolicy::default(),
+            })
+        );
+        let req = test::TestRequest::get()
+            .uri(&format!("/subscriptions/{}", Uuid::new_v4()))
+            .header(header::AUTHORIZATION, bearer(&client_id))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_reading_requires_subscriber_access() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub = PubSubService::new(&data_log).start();
+        let (owner, stranger) = (Uuid::new_v4(), Uuid::new_v4());
+        let (public_id, private_id) = (Uuid::new_v4(), Uuid::new_v4());
+        for subscription_id in [public_id, private_id] {
+            pubsub
+                .send(ManageSubscription::Add {
+                    client_id: owner,
+                    subscription_id,
+                    kind: SubscriptionKind::Broadcast,
+                    durable: false,
+                    topic: None,
+                    create: true,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        pubsub
+            .send(ManageSubscription::SetAccess {
+                client_id: owner,
+                subscription_id: private_id,
+                publishers: None,
+                subscribers: Some(Vec::new()),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let publication_id = pubsub
+            .send(SubmitCommand::new(&owner, &private_id, b"Private"))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(pubsub.clone())
+                .data(data_log.clone())
+                .app_data(id_token_authenticator(&[owner, stranger]))
+                .configure(configure),
+        )
+        .await;
+        let routes = [
+            String::from("/subscriptions"),
+            format!("/subscriptions/{}", private_id),
+            format!("/subscriptions/{}/publications", private_id),
+            format!(
+                "/subscriptions/{}/publications/{}",
+                private_id, publication_id
+            ),
+            format!("/subscriptions/{}/index", private_id),
+        ];
+        for route in routes.iter() {
+            let req = test::TestRequest::get().uri(route).to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        for route in routes.iter().skip(1) {
+            let req = test::TestRequest::get()
+                .uri(route)
+                .header(header::AUTHORIZATION, bearer(&stranger))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+            let req = test::TestRequest::get()
+                .uri(route)
+                .header(header::AUTHORIZATION, bearer(&owner))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        // Listing only includes the subscriptions the client may read
+        for (client_id, readable) in [(stranger, 1), (owner, 2)] {
+            let req = test::TestRequest::get()
+                .uri("/subscriptions")
+                .header(header::AUTHORIZATION, bearer(&client_id))
+                .to_request();
+            let subscriptions: Vec<SubscriptionInfo> =
+                test::read_response_json(&mut app, req).await;
+            assert_eq!(subscriptions.len(), readable);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_ignoring_client_id_without_authenticator() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub = PubSubService::new(&data_log).start();
+        let (owner, subscription_id) = (Uuid::new_v4(), Uuid::new_v4());
+        pubsub
+            .send(ManageSubscription::Add {
+                client_id: owner,
+                subscription_id,
+                kind: SubscriptionKind::Broadcast,
+                durable: false,
+                topic: None,
+                create: true,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        pubsub
+            .send(ManageSubscription::SetAccess {
+                client_id: owner,
+                subscription_id,
+                publishers: Some(Vec::new()),
+                subscribers: Some(Vec::new()),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(pubsub.clone())
+                .data(data_log.clone())
+                .configure(configure),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/subscriptions/{}", subscription_id))
+            .header(CLIENT_ID_HEADER, owner.to_string())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let req = test::TestRequest::post()
+            .uri(&format!("/subscriptions/{}/publications", subscription_id))
+            .header(CLIENT_ID_HEADER, owner.to_string())
+            .set_payload("Anonymous")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+}
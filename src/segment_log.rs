@@ -0,0 +1,904 @@
+use std::convert::TryInto;
+use std::fs::{create_dir_all, read_dir, remove_dir_all, remove_file, rename, File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::data_log::DataLogError;
+
+const LOG_FILE_EXTENSION: &str = "log";
+const INDEX_FILE_EXTENSION: &str = "index";
+const REWRITE_DIR_EXTENSION: &str = "rewrite";
+const REPLACED_DIR_EXTENSION: &str = "replaced";
+const RECORD_HEADER_LEN: u64 = 8;
+const INDEX_ENTRY_LEN: usize = 16;
+
+/// Writes `data` to `path` atomically: it is written to a temporary file first,
+/// which is synced to disk and renamed to `path`. The directory is synced afterwards,
+/// so the rename survives a crash as well.
+pub(crate) fn write_atomically(path: &Path, data: &[u8]) -> Result<(), DataLogError> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    rename(&tmp_path, path)?;
+    if let Some(dir) = path.parent() {
+        sync_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Syncs a directory, persisting the creation, removal and renaming of its entries
+pub(crate) fn sync_dir(dir: &Path) -> Result<(), DataLogError> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// The operations appending to a segment file relies on
+trait SegmentFile: Write {
+    fn sync_data(&self) -> std::io::Result<()>;
+    fn set_len(&self, len: u64) -> std::io::Result<()>;
+}
+
+impl SegmentFile for File {
+    fn sync_data(&self) -> std::io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+/// Appends `data` to a file of `len` bytes, syncing it to disk if `sync` is set.
+/// If the write fails, the file is cut back to `len`, so no torn data is left behind
+/// for later appends to follow.
+fn append_durably<F: SegmentFile>(
+    file: &mut F,
+    len: u64,
+    data: &[u8],
+    sync: bool,
+) -> Result<(), DataLogError> {
+    let result = file
+        .write_all(data)
+        .and_then(|_| if sync { file.sync_data() } else { Ok(()) });
+    if let Err(e) = result {
+        if let Err(truncate_error) = file.set_len(len) {
+            warn!("Could not remove a failed write: {}", truncate_error);
+        }
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// The length prefix of a record, along with its checksum
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RecordHeader {
+    len: u64,
+    checksum: u32,
+}
+
+impl RecordHeader {
+    fn verify(&self, record: &[u8]) -> bool {
+        crc32fast::hash(record) == self.checksum
+    }
+}
+
+/// Configures how a `SegmentedLog` splits its records across files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmentConfig {
+    /// Size in bytes after which the active segment is closed and a new one is started
+    pub max_segment_bytes: u64,
+    /// Number of bytes written to a segment between two entries of its sparse offset index
+    pub index_interval_bytes: u64,
+    /// Whether every appended record is synced to disk before `append` returns
+    pub sync_writes: bool,
+}
+
+impl Default for SegmentConfig {
+    fn default() -> Self {
+        SegmentConfig {
+            max_segment_bytes: 8 * 1024 * 1024,
+            index_interval_bytes: 4 * 1024,
+            sync_writes: true,
+        }
+    }
+}
+
+/// A single segment of a `SegmentedLog`, holding the records starting at `base_offset`
+#[derive(Debug)]
+struct Segment {
+    base_offset: u64,
+    next_offset: u64,
+    size: u64,
+    bytes_since_index: u64,
+    index: Vec<(u64, u64)>,
+}
+
+impl Segment {
+    fn new(base_offset: u64) -> Segment {
+        Segment {
+            base_offset,
+            next_offset: base_offset,
+            size: 0,
+            bytes_since_index: 0,
+            index: Vec::new(),
+        }
+    }
+
+    fn file_path(dir: &Path, base_offset: u64, extension: &str) -> PathBuf {
+        dir.join(format!("{:020}.{}", base_offset, extension))
+    }
+
+    /// Encodes a record to be appended to the segment, returning the bytes to append
+    /// to its log file and, if `index_interval_bytes` were written since the last entry
+    /// of its sparse index, the entry to append to its index file.
+    /// The segment itself is only updated by `advance`, once the bytes were written.
+    fn encode(
+        &self,
+        config: &SegmentConfig,
+        record: &[u8],
+    ) -> Result<(Vec<u8>, Option<Vec<u8>>), DataLogError> {
+        if record.is_empty() {
+            return Err(DataLogError::FileSystem(String::from(
+                "Empty records can't be appended",
+            )));
+        }
+        let record_len: u32 = record.len().try_into().map_err(|_| {
+            DataLogError::FileSystem(format!("Record of {} bytes is too large", record.len()))
+        })?;
+        let entry =
+            if self.index.is_empty() || self.bytes_since_index >= config.index_interval_bytes {
+                let mut entry = Vec::with_capacity(INDEX_ENTRY_LEN);
+                entry.extend_from_slice(&self.next_offset.to_be_bytes());
+                entry.extend_from_slice(&self.size.to_be_bytes());
+                Some(entry)
+            } else {
+                None
+            };
+        let mut buf = Vec::with_capacity(record.len() + RECORD_HEADER_LEN as usize);
+        buf.extend_from_slice(&record_len.to_be_bytes());
+        buf.extend_from_slice(&crc32fast::hash(record).to_be_bytes());
+        buf.extend_from_slice(record);
+        Ok((buf, entry))
+    }
+
+    /// Accounts for an encoded record of `len` bytes written to the segment,
+    /// along with an index entry if `indexed`. Returns the offset of the record.
+    fn advance(&mut self, len: u64, indexed: bool) -> u64 {
+        let offset = self.next_offset;
+        if indexed {
+            self.index.push((offset, self.size));
+            self.bytes_since_index = 0;
+        }
+        self.size += len;
+        self.bytes_since_index += len;
+        self.next_offset += 1;
+        offset
+    }
+
+    /// Appends a record to the segment files, adding an entry to the sparse index
+    /// if `index_interval_bytes` were written since the last one.
+    /// The index entry is only written once the record is durable, so it never points
+    /// past the end of the log.
+    fn append(
+        &mut self,
+        dir: &Path,
+        config: &SegmentConfig,
+        record: &[u8],
+    ) -> Result<u64, DataLogError> {
+        let (buf, entry) = self.encode(config, record)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Segment::file_path(
+                dir,
+                self.base_offset,
+                LOG_FILE_EXTENSION,
+            ))?;
+        append_durably(&mut file, self.size, &buf, config.sync_writes)?;
+        if config.sync_writes && self.size == 0 {
+            if let Err(e) = sync_dir(dir) {
+                file.set_len(self.size)?;
+                return Err(e);
+            }
+        }
+        let indexed = match &entry {
+            Some(entry) => self.append_index_entry(dir, config, entry),
+            None => false,
+        };
+        Ok(self.advance(buf.len() as u64, indexed))
+    }
+
+    /// Appends an entry to the sparse index file. The record it points to is already
+    /// durable, so a failure only leaves the index sparser and is not reported to the caller.
+    fn append_index_entry(&self, dir: &Path, config: &SegmentConfig, entry: &[u8]) -> bool {
+        let path = Segment::file_path(dir, self.base_offset, INDEX_FILE_EXTENSION);
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(DataLogError::from)
+            .and_then(|mut file| {
+                let len = file.metadata()?.len();
+                append_durably(&mut file, len, entry, config.sync_writes)
+            });
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                warn!(
+                    "Could not index offset {} in {:?}: {}",
+                    self.next_offset, path, e
+                );
+                false
+            }
+        }
+    }
+
+    /// Deletes the files of the segment
+    fn remove(&self, dir: &Path) -> Result<(), DataLogError> {
+        for extension in &[LOG_FILE_EXTENSION, INDEX_FILE_EXTENSION] {
+            match remove_file(Segment::file_path(dir, self.base_offset, extension)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the file position to start scanning from when looking for `offset`
+    fn seek_position(&self, offset: u64) -> (u64, u64) {
+        match self.index.binary_search_by_key(&offset, |(o, _)| *o) {
+            Ok(i) => self.index[i],
+            Err(0) => (self.base_offset, 0),
+            Err(i) => self.index[i - 1],
+        }
+    }
+}
+
+/// An append-only log made up of rolling segment files.
+/// Records are stored length-prefixed with a CRC32 checksum and are addressed by their offset,
+/// a number assigned to every record in the order it was appended.
+/// Each segment is accompanied by a sparse index mapping offsets to file positions.
+#[derive(Debug)]
+pub struct SegmentedLog {
+    dir: PathBuf,
+    config: SegmentConfig,
+    segments: Vec<Segment>,
+}
+
+impl SegmentedLog {
+    /// Opens the log stored in `dir`, creating the directory if necessary.
+    /// Incomplete records at the end of the active segment, e.g. caused by a crash
+    /// in the middle of a write, are truncated, as are leftovers of an interrupted rewrite.
+    pub fn open(dir: &Path, config: SegmentConfig) -> Result<SegmentedLog, DataLogError> {
+        SegmentedLog::recover_rewrite(dir)?;
+        create_dir_all(dir)?;
+        let mut base_offsets = Vec::new();
+        for entry in read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(LOG_FILE_EXTENSION) {
+                continue;
+            }
+            if let Some(base_offset) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                base_offsets.push(base_offset)
+            }
+        }
+        base_offsets.sort_unstable();
+        let mut segments = Vec::with_capacity(base_offsets.len());
+        for (i, base_offset) in base_offsets.iter().enumerate() {
+            let next_base_offset = base_offsets.get(i + 1).copied();
+            segments.push(SegmentedLog::load_segment(
+                dir,
+                *base_offset,
+                next_base_offset,
+            )?);
+        }
+        Ok(SegmentedLog {
+            dir: dir.to_path_buf(),
+            config,
+            segments,
+        })
+    }
+
+    /// Returns true if a log, or a complete rewrite of one, is stored in `dir`
+    pub fn exists(dir: &Path) -> bool {
+        dir.is_dir() || dir.with_extension(REWRITE_DIR_EXTENSION).is_dir()
+    }
+
+    /// Finishes or discards a rewrite of the log in `dir` that was interrupted.
+    /// A rewrite is only complete once the log it replaces has been moved aside.
+    fn recover_rewrite(dir: &Path) -> Result<(), DataLogError> {
+        let rewrite_dir = dir.with_extension(REWRITE_DIR_EXTENSION);
+        let replaced_dir = dir.with_extension(REPLACED_DIR_EXTENSION);
+        if !dir.exists() && rewrite_dir.is_dir() {
+            warn!("Completing interrupted rewrite of {:?}", dir);
+            rename(&rewrite_dir, dir)?;
+        }
+        for leftover in &[rewrite_dir, replaced_dir] {
+            if leftover.is_dir() {
+                remove_dir_all(leftover)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load_segment(
+        dir: &Path,
+        base_offset: u64,
+        next_base_offset: Option<u64>,
+    ) -> Result<Segment, DataLogError> {
+        let log_path = Segment::file_path(dir, base_offset, LOG_FILE_EXTENSION);
+        let file_size = log_path.metadata()?.len();
+        let mut index = SegmentedLog::read_index(dir, base_offset)?;
+        index.retain(|(_, position)| *position < file_size);
+        let mut segment = Segment {
+            base_offset,
+            next_offset: base_offset,
+            size: file_size,
+            bytes_since_index: 0,
+            index,
+        };
+        if let Some(next_base_offset) = next_base_offset {
+            segment.next_offset = next_base_offset;
+            return Ok(segment);
+        }
+        // The last segment is the active one. Scan it to find its end.
+        let (mut offset, mut position) = segment.index.last().copied().unwrap_or((base_offset, 0));
+        let last_index_position = position;
+        let mut reader = BufReader::new(File::open(&log_path)?);
+        reader.seek(SeekFrom::Start(position))?;
+        // Records are verified, as a crash may leave garbage rather than a short file.
+        // The log holds no empty records, so an empty one is taken for zeroed garbage.
+        while let Some(header) = SegmentedLog::read_record_header(&mut reader)? {
+            let record_end = position + RECORD_HEADER_LEN + header.len;
+            if record_end > file_size || header.len == 0 {
+                break;
+            }
+            let mut record = vec![0u8; header.len as usize];
+            reader.read_exact(&mut record)?;
+            if !header.verify(&record) {
+                break;
+            }
+            position = record_end;
+            offset += 1;
+        }
+        if position < file_size {
+            warn!(
+                "Truncating incomplete or corrupted record at position {} of {:?}",
+                position, log_path
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(&log_path)?
+                .set_len(position)?;
+        }
+        segment.next_offset = offset;
+        segment.size = position;
+        segment.bytes_since_index = position - last_index_position;
+        Ok(segment)
+    }
+
+    fn read_index(dir: &Path, base_offset: u64) -> Result<Vec<(u64, u64)>, DataLogError> {
+        let mut buf = Vec::new();
+        match File::open(Segment::file_path(dir, base_offset, INDEX_FILE_EXTENSION)) {
+            Ok(mut file) => file.read_to_end(&mut buf)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(buf
+            .chunks_exact(INDEX_ENTRY_LEN)
+            .map(|entry| {
+                (
+                    u64::from_be_bytes(entry[..8].try_into().unwrap()),
+                    u64::from_be_bytes(entry[8..].try_into().unwrap()),
+                )
+            })
+            .collect())
+    }
+
+    /// Reads the header of the next record, returning `None` at the end of the segment
+    fn read_record_header<R: Read>(reader: &mut R) -> Result<Option<RecordHeader>, DataLogError> {
+        let mut header = [0u8; RECORD_HEADER_LEN as usize];
+        match reader.read_exact(&mut header) {
+            Ok(()) => Ok(Some(RecordHeader {
+                len: u32::from_be_bytes(header[..4].try_into().unwrap()) as u64,
+                checksum: u32::from_be_bytes(header[4..].try_into().unwrap()),
+            })),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The offset of the oldest record still present in the log
+    pub fn first_offset(&self) -> u64 {
+        self.segments.first().map(|s| s.base_offset).unwrap_or(0)
+    }
+
+    /// The offset that will be assigned to the next appended record
+    pub fn next_offset(&self) -> u64 {
+        self.segments.last().map(|s| s.next_offset).unwrap_or(0)
+    }
+
+    /// Appends a record to the active segment, starting a new segment
+    /// if the active one is full. Returns the offset of the appended record.
+    pub fn append(&mut self, record: &[u8]) -> Result<u64, DataLogError> {
+        let roll = match self.segments.last() {
+            Some(s) => s.size > 0 && s.size + record.len() as u64 > self.config.max_segment_bytes,
+            None => true,
+        };
+        if roll {
+            self.segments.push(Segment::new(self.next_offset()));
+        }
+        let segment = self.segments.last_mut().unwrap();
+        segment.append(&self.dir, &self.config, record)
+    }
+
+    /// Reads the record stored at `offset`
+    pub fn read(&self, offset: u64) -> Result<Vec<u8>, DataLogError> {
+        self.read_from(offset, 1)?
+            .pop()
+            .filter(|(found, _)| *found == offset)
+            .map(|(_, record)| record)
+            .ok_or(DataLogError::RecordNotFound(offset))
+    }
+
+    /// Reads up to `limit` consecutive records, starting at `offset`.
+    /// Returns the records along with their offsets.
+    pub fn read_from(
+        &self,
+        offset: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, Vec<u8>)>, DataLogError> {
+        let mut records = Vec::new();
+        let start = match self
+            .segments
+            .binary_search_by_key(&offset, |s| s.base_offset)
+        {
+            Ok(i) => i,
+            Err(0) => 0,
+            Err(i) => i - 1,
+        };
+        for segment in &self.segments[start..] {
+            if records.len() >= limit {
+                break;
+            }
+            let mut segment_records = self.read_segment(segment, offset, limit - records.len())?;
+            records.append(&mut segment_records);
+        }
+        Ok(records)
+    }
+
+    /// Reads up to `limit` consecutive records of a single segment, starting at `offset`.
+    /// Fails with `DataLogError::ChecksumMismatch` if a record read does not match its checksum.
+    fn read_segment(
+        &self,
+        segment: &Segment,
+        offset: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, Vec<u8>)>, DataLogError> {
+        let mut records = Vec::new();
+        let (mut current, position) = segment.seek_position(offset);
+        let mut reader = BufReader::new(File::open(Segment::file_path(
+            &self.dir,
+            segment.base_offset,
+            LOG_FILE_EXTENSION,
+        ))?);
+        reader.seek(SeekFrom::Start(position))?;
+        while current < segment.next_offset && records.len() < limit {
+            let header = SegmentedLog::read_record_header(&mut reader)?.ok_or_else(|| {
+                DataLogError::FileSystem(format!("Unexpected end of segment at {}", current))
+            })?;
+            if current >= offset {
+                let mut record = vec![0u8; header.len as usize];
+                reader.read_exact(&mut record)?;
+                if !header.verify(&record) {
+                    return Err(DataLogError::ChecksumMismatch(format!(
+                        "record {} in {:?}",
+                        current, self.dir
+                    )));
+                }
+                records.push((current, record));
+            } else {
+                reader.seek_relative(header.len as i64)?;
+            }
+            current += 1;
+        }
+        Ok(records)
+    }
+
+    /// Reads every record of the log along with its offset. Records that don't match
+    /// their checksum are skipped with a warning. If a segment is damaged to the point
+    /// that the following records can't be found anymore, its remaining records are skipped.
+    pub fn scan(&self) -> Result<Vec<(u64, Vec<u8>)>, DataLogError> {
+        let mut records = Vec::new();
+        for segment in &self.segments {
+            records.append(&mut self.scan_segment(segment)?);
+        }
+        Ok(records)
+    }
+
+    fn scan_segment(&self, segment: &Segment) -> Result<Vec<(u64, Vec<u8>)>, DataLogError> {
+        let log_path = Segment::file_path(&self.dir, segment.base_offset, LOG_FILE_EXTENSION);
+        let file_size = log_path.metadata()?.len();
+        let mut reader = BufReader::new(File::open(&log_path)?);
+        let mut records = Vec::new();
+        let mut position = 0;
+        for offset in segment.base_offset..segment.next_offset {
+            let header = match SegmentedLog::read_record_header(&mut reader)? {
+                Some(header) if position + RECORD_HEADER_LEN + header.len <= file_size => header,
+                _ => {
+                    warn!(
+                        "Skipping records {} to {} of damaged segment {:?}",
+                        offset,
+                        segment.next_offset - 1,
+                        log_path
+                    );
+                    break;
+                }
+            };
+            let mut record = vec![0u8; header.len as usize];
+            reader.read_exact(&mut record)?;
+            position += RECORD_HEADER_LEN + header.len;
+            if header.verify(&record) {
+                records.push((offset, record));
+            } else {
+                warn!(
+                    "Skipping record {} of {:?}, it does not match its checksum",
+                    offset, log_path
+                );
+            }
+        }
+        Ok(records)
+    }
+
+    /// Removes all records with an offset lower than `offset`.
+    /// Segments holding only such records are deleted, the segment holding `offset`
+    /// is replaced by one starting at `offset`. Offsets of remaining records are unchanged.
+    /// The replacement is written atomically before the segment it replaces is deleted,
+    /// so a crash in between leaves both, which still hold every retained record.
+    pub fn truncate_before(&mut self, offset: u64) -> Result<(), DataLogError> {
+        let mut retained = Vec::with_capacity(self.segments.len());
+        for segment in std::mem::take(&mut self.segments) {
+            if segment.next_offset <= offset {
+                segment.remove(&self.dir)?;
+            } else if segment.base_offset >= offset {
+                retained.push(segment);
+            } else {
+                let mut replacement = Segment::new(offset);
+                let (mut log, mut index) = (Vec::new(), Vec::new());
+                let count = (segment.next_offset - offset) as usize;
+                for (_, record) in self.read_segment(&segment, offset, count)? {
+                    let (buf, entry) = replacement.encode(&self.config, &record)?;
+                    log.extend_from_slice(&buf);
+                    if let Some(entry) = &entry {
+                        index.extend_from_slice(entry);
+                    }
+                    replacement.advance(buf.len() as u64, entry.is_some());
+                }
+                // The index goes first, an index without its log file is never loaded
+                write_atomically(
+                    &Segment::file_path(&self.dir, offset, INDEX_FILE_EXTENSION),
+                    &index,
+                )?;
+                write_atomically(
+                    &Segment::file_path(&self.dir, offset, LOG_FILE_EXTENSION),
+                    &log,
+                )?;
+                segment.remove(&self.dir)?;
+                retained.push(replacement);
+            }
+        }
+        if retained.is_empty() {
+            // Keep an empty segment so offsets are not reused after reopening the log
+            File::create(Segment::file_path(&self.dir, offset, LOG_FILE_EXTENSION))?;
+            retained.push(Segment::new(offset));
+        }
+        if self.config.sync_writes {
+            sync_dir(&self.dir)?;
+        }
+        self.segments = retained;
+        Ok(())
+    }
+
+    /// Rewrites the log, passing every record through `f`. Records for which `f`
+    /// returns `None` are dropped, all others are replaced by the returned record.
+    /// The rewritten log is assembled next to the current one and swapped in once complete.
+    /// Offsets are reassigned, starting at 0. Damaged records are dropped, see `scan`.
+    pub fn rewrite<F>(&mut self, mut f: F) -> Result<(), DataLogError>
+    where
+        F: FnMut(u64, Vec<u8>) -> Result<Option<Vec<u8>>, DataLogError>,
+    {
+        let rewrite_dir = self.dir.with_extension(REWRITE_DIR_EXTENSION);
+        if rewrite_dir.exists() {
+            remove_dir_all(&rewrite_dir)?;
+        }
+        let mut rewritten = SegmentedLog::open(&rewrite_dir, self.config)?;
+        for (offset, record) in self.scan()? {
+            if let Some(record) = f(offset, record)? {
+                rewritten.append(&record)?;
+            }
+        }
+        let replaced_dir = self.dir.with_extension(REPLACED_DIR_EXTENSION);
+        rename(&self.dir, &replaced_dir)?;
+        rename(&rewrite_dir, &self.dir)?;
+        if let Some(parent) = self.dir.parent() {
+            sync_dir(parent)?;
+        }
+        remove_dir_all(&replaced_dir)?;
+        rewritten.dir = self.dir.clone();
+        *self = rewritten;
+        Ok(())
+    }
+
+    /// Deletes all segment files of the log
+    pub fn remove(self) -> Result<(), DataLogError> {
+        for segment in &self.segments {
+            segment.remove(&self.dir)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::{create_test_directory, remove_test_directory};
+
+    /// A segment file failing once `limit` bytes were written to it
+    struct FailingFile {
+        file: File,
+        limit: usize,
+    }
+
+    impl Write for FailingFile {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.limit == 0 {
+                return Err(std::io::Error::new(ErrorKind::Other, "No space left"));
+            }
+            let len = buf.len().min(self.limit);
+            self.limit -= len;
+            self.file.write(&buf[..len])
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl SegmentFile for FailingFile {
+        fn sync_data(&self) -> std::io::Result<()> {
+            self.file.sync_data()
+        }
+
+        fn set_len(&self, len: u64) -> std::io::Result<()> {
+            self.file.set_len(len)
+        }
+    }
+
+    fn small_segments() -> SegmentConfig {
+        SegmentConfig {
+            max_segment_bytes: 64,
+            index_interval_bytes: 16,
+            ..SegmentConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_appending_and_reading_records() {
+        let test_dir = create_test_directory();
+        let mut log = SegmentedLog::open(&test_dir, small_segments()).unwrap();
+        for i in 0..20u64 {
+            assert_eq!(log.append(format!("record {}", i).as_bytes()).unwrap(), i);
+        }
+        assert!(log.segments.len() > 1);
+        assert_eq!(log.read(13).unwrap(), b"record 13");
+        let records = log.read_from(5, 3).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                (5, b"record 5".to_vec()),
+                (6, b"record 6".to_vec()),
+                (7, b"record 7".to_vec())
+            ]
+        );
+        assert!(log.read(20).is_err());
+        remove_test_directory(&test_dir);
+    }
+
+    #[test]
+    fn test_reopening_log() {
+        let test_dir = create_test_directory();
+        let mut log = SegmentedLog::open(&test_dir, small_segments()).unwrap();
+        for i in 0..10u64 {
+            log.append(format!("record {}", i).as_bytes()).unwrap();
+        }
+        let last_segment = log.segments.last().unwrap().base_offset;
+        drop(log);
+        let mut torn_write = OpenOptions::new()
+            .append(true)
+            .open(Segment::file_path(
+                &test_dir,
+                last_segment,
+                LOG_FILE_EXTENSION,
+            ))
+            .unwrap();
+        torn_write.write_all(&[0, 0, 0, 42, 1, 2]).unwrap();
+        let mut log = SegmentedLog::open(&test_dir, small_segments()).unwrap();
+        assert_eq!(log.next_offset(), 10);
+        assert_eq!(log.append(b"record 10").unwrap(), 10);
+        let records: Vec<Vec<u8>> = log
+            .read_from(log.first_offset(), usize::MAX)
+            .unwrap()
+            .into_iter()
+            .map(|(_, r)| r)
+            .collect();
+        assert_eq!(records.len(), 11);
+        assert_eq!(records[10], b"record 10");
+        remove_test_directory(&test_dir);
+    }
+
+    #[test]
+    fn test_verifying_checksums() {
+        let test_dir = create_test_directory();
+        let mut log = SegmentedLog::open(&test_dir, small_segments()).unwrap();
+        for i in 0..6u64 {
+            log.append(format!("record {}", i).as_bytes()).unwrap();
+        }
+        let last_segment = log.segments.last().unwrap().base_offset;
+        assert!(last_segment > 0);
+        drop(log);
+        // Corrupt the payload of the first record in the closed segment
+        let closed_path = Segment::file_path(&test_dir, 0, LOG_FILE_EXTENSION);
+        let mut contents = std::fs::read(&closed_path).unwrap();
+        contents[RECORD_HEADER_LEN as usize] ^= 0xff;
+        std::fs::write(&closed_path, &contents).unwrap();
+        // Append zeroed garbage to the active segment, as left behind by a power loss
+        let active_path = Segment::file_path(&test_dir, last_segment, LOG_FILE_EXTENSION);
+        let mut contents = std::fs::read(&active_path).unwrap();
+        contents.extend_from_slice(&[0; 32]);
+        std::fs::write(&active_path, &contents).unwrap();
+        let log = SegmentedLog::open(&test_dir, small_segments()).unwrap();
+        assert_eq!(log.next_offset(), 6);
+        assert!(matches!(
+            log.read(0),
+            Err(DataLogError::ChecksumMismatch(_))
+        ));
+        assert_eq!(log.read(1).unwrap(), b"record 1");
+        assert_eq!(log.read(5).unwrap(), b"record 5");
+        let scanned: Vec<u64> = log.scan().unwrap().into_iter().map(|(o, _)| o).collect();
+        assert_eq!(scanned, vec![1, 2, 3, 4, 5]);
+        remove_test_directory(&test_dir);
+    }
+
+    #[test]
+    fn test_writing_atomically() {
+        let test_dir = create_test_directory();
+        let path = test_dir.join("data.cbor");
+        write_atomically(&path, b"first version").unwrap();
+        write_atomically(&path, b"second").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        assert!(!path.with_extension("tmp").exists());
+        remove_test_directory(&test_dir);
+    }
+
+    #[test]
+    fn test_truncating_log() {
+        let test_dir = create_test_directory();
+        let mut log = SegmentedLog::open(&test_dir, small_segments()).unwrap();
+        for i in 0..20u64 {
+            log.append(format!("record {}", i).as_bytes()).unwrap();
+        }
+        let segment_count = log.segments.len();
+        log.truncate_before(9).unwrap();
+        assert!(log.segments.len() < segment_count);
+        assert_eq!(log.first_offset(), 9);
+        assert_eq!(log.read(9).unwrap(), b"record 9");
+        assert!(log.read(8).is_err());
+        drop(log);
+        let mut log = SegmentedLog::open(&test_dir, small_segments()).unwrap();
+        assert_eq!(log.first_offset(), 9);
+        assert_eq!(log.read_from(9, usize::MAX).unwrap().len(), 11);
+        log.truncate_before(20).unwrap();
+        assert_eq!(log.read_from(0, usize::MAX).unwrap(), Vec::new());
+        drop(log);
+        let log = SegmentedLog::open(&test_dir, small_segments()).unwrap();
+        assert_eq!(log.next_offset(), 20);
+        remove_test_directory(&test_dir);
+    }
+
+    #[test]
+    fn test_recovering_interrupted_truncation() {
+        let test_dir = create_test_directory();
+        let mut log = SegmentedLog::open(&test_dir, small_segments()).unwrap();
+        for i in 0..20u64 {
+            log.append(format!("record {}", i).as_bytes()).unwrap();
+        }
+        let segment = log
+            .segments
+            .iter()
+            .find(|s| s.base_offset < 9 && s.next_offset > 10)
+            .unwrap();
+        let replaced_path = Segment::file_path(&test_dir, segment.base_offset, LOG_FILE_EXTENSION);
+        let replaced = std::fs::read(&replaced_path).unwrap();
+        log.truncate_before(9).unwrap();
+        drop(log);
+        // Crashed after the replacement was renamed into place, before the segment was deleted
+        std::fs::write(&replaced_path, &replaced).unwrap();
+        let mut log = SegmentedLog::open(&test_dir, small_segments()).unwrap();
+        assert_eq!(log.read(9).unwrap(), b"record 9");
+        assert_eq!(log.read(10).unwrap(), b"record 10");
+        assert_eq!(
+            log.read_from(log.first_offset(), usize::MAX).unwrap().len(),
+            20 - log.first_offset() as usize
+        );
+        assert_eq!(log.next_offset(), 20);
+        log.truncate_before(9).unwrap();
+        assert_eq!(log.first_offset(), 9);
+        assert_eq!(log.read_from(9, usize::MAX).unwrap().len(), 11);
+        remove_test_directory(&test_dir);
+    }
+
+    #[test]
+    fn test_rewriting_log() {
+        let test_dir = create_test_directory();
+        let log_dir = test_dir.join("log");
+        let mut log = SegmentedLog::open(&log_dir, small_segments()).unwrap();
+        for i in 0..20u64 {
+            log.append(format!("record {}", i).as_bytes()).unwrap();
+        }
+        log.rewrite(|offset, record| {
+            Ok(if offset % 2 == 0 {
+                Some([record, b"!".to_vec()].concat())
+            } else {
+                None
+            })
+        })
+        .unwrap();
+        assert_eq!(log.next_offset(), 10);
+        assert_eq!(log.read(3).unwrap(), b"record 6!");
+        assert!(!log_dir.with_extension(REWRITE_DIR_EXTENSION).exists());
+        assert!(!log_dir.with_extension(REPLACED_DIR_EXTENSION).exists());
+        drop(log);
+        rename(&log_dir, log_dir.with_extension(REWRITE_DIR_EXTENSION)).unwrap();
+        assert!(SegmentedLog::exists(&log_dir));
+        let log = SegmentedLog::open(&log_dir, small_segments()).unwrap();
+        assert_eq!(log.read(9).unwrap(), b"record 18!");
+        remove_test_directory(&test_dir);
+    }
+
+    #[test]
+    fn test_recovering_from_failed_writes() {
+        let test_dir = create_test_directory();
+        let mut log = SegmentedLog::open(&test_dir, small_segments()).unwrap();
+        log.append(b"first").unwrap();
+        // A write failing halfway through a record leaves nothing behind
+        let segment = log.segments.last().unwrap();
+        let path = Segment::file_path(&test_dir, segment.base_offset, LOG_FILE_EXTENSION);
+        let (buf, _) = segment.encode(&log.config, b"lost").unwrap();
+        let mut file = FailingFile {
+            file: OpenOptions::new().append(true).open(&path).unwrap(),
+            limit: 6,
+        };
+        assert!(append_durably(&mut file, segment.size, &buf, true).is_err());
+        assert_eq!(path.metadata().unwrap().len(), segment.size);
+        assert_eq!(log.append(b"second").unwrap(), 1);
+        drop(log);
+        let log = SegmentedLog::open(&test_dir, small_segments()).unwrap();
+        assert_eq!(
+            log.read_from(0, 10).unwrap(),
+            vec![(0, b"first".to_vec()), (1, b"second".to_vec())]
+        );
+        remove_test_directory(&test_dir);
+    }
+}
@@ -0,0 +1,448 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::str::FromStr;
+
+use faccess::{AccessMode, PathExt};
+use log::warn;
+use rusqlite::{params, Connection, OptionalExtension};
+use uuid::Uuid;
+
+use crate::data_log::{DataLogError, LogPosition, LogRange, RangeDirection};
+use crate::data_store::DataStore;
+use crate::pubsub::{Publication, Subscription};
+
+/// Name of the database file created in the application directory
+pub const DATABASE_FILE: &str = "infotainer.sqlite";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS publications (
+        subscription_id TEXT NOT NULL,
+        offset INTEGER NOT NULL,
+        publication_id TEXT NOT NULL,
+        sequence INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        record BLOB NOT NULL,
+        PRIMARY KEY (subscription_id, offset)
+    );
+    CREATE INDEX IF NOT EXISTS publications_by_sequence
+        ON publications (subscription_id, sequence);
+    CREATE INDEX IF NOT EXISTS publications_by_timestamp
+        ON publications (subscription_id, timestamp);
+    CREATE TABLE IF NOT EXISTS offsets (
+        subscription_id TEXT PRIMARY KEY NOT NULL,
+        next_offset INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS subscriptions (
+        id TEXT PRIMARY KEY NOT NULL,
+        record BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS sequence_marks (
+        subscription_id TEXT PRIMARY KEY NOT NULL,
+        sequence INTEGER NOT NULL
+    );
+";
+
+impl From<rusqlite::Error> for DataLogError {
+    fn from(e: rusqlite::Error) -> DataLogError {
+        DataLogError::Database(format!("{}", e))
+    }
+}
+
+/// A `DataStore` keeping publications and subscription metadata in a SQLite database.
+/// Publications are stored as CBOR records alongside their sequence number and timestamp,
+/// which are indexed to select log ranges in a single query.
+/// The next offset of each subscription is counted separately, so offsets of removed
+/// publications are never handed out again. Every write is a transaction of its own.
+#[derive(Debug)]
+pub struct SqliteStore {
+    connection: Connection,
+}
+
+impl SqliteStore {
+    ///Opens the database in `app_dir`, creating it if necessary.
+    ///## Arguments
+    ///* `app_dir` - The application base directory. Must exist and be accessible with rwx permissions.
+    pub fn open(app_dir: &Path) -> Result<SqliteStore, DataLogError> {
+        if app_dir
+            .access(AccessMode::EXISTS | AccessMode::READ | AccessMode::WRITE | AccessMode::EXECUTE)
+            .is_err()
+        {
+            return Err(DataLogError::FileSystem(String::from(
+                "Could not access application base directory with required permissions",
+            )));
+        }
+        SqliteStore::with_connection(Connection::open(app_dir.join(DATABASE_FILE))?)
+    }
+
+    ///Opens a database that only lives in memory
+    pub fn open_in_memory() -> Result<SqliteStore, DataLogError> {
+        SqliteStore::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<SqliteStore, DataLogError> {
+        connection.execute_batch(SCHEMA)?;
+        // Publications logged before sequence numbers were introduced are numbered by their offset
+        connection.execute(
+            "UPDATE publications SET sequence = offset + 1 WHERE sequence = 0",
+            [],
+        )?;
+        Ok(SqliteStore { connection })
+    }
+}
+
+impl DataStore for SqliteStore {
+    fn collections(&self) -> Result<Vec<Uuid>, DataLogError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT DISTINCT subscription_id FROM publications")?;
+        let ids = statement.query_map([], |row| row.get::<_, String>(0))?;
+        let mut collections = Vec::new();
+        for id in ids {
+            let id = id?;
+            match Uuid::from_str(&id) {
+                Ok(id) => collections.push(id),
+                Err(e) => warn!("Skipping collection {}: {}", id, e),
+            }
+        }
+        Ok(collections)
+    }
+
+    fn append(&mut self, publication: &Publication) -> Result<(u64, u64), DataLogError> {
+        let record = serde_cbor::to_vec(publication).map_err(DataLogError::WriteError)?;
+        let transaction = self.connection.transaction()?;
+        let offset: i64 = transaction.query_row(
+            "INSERT INTO offsets (subscription_id, next_offset) VALUES (?1, 1)
+                ON CONFLICT (subscription_id) DO UPDATE SET next_offset = next_offset + 1
+                RETURNING next_offset - 1",
+            params![publication.subscription_id.to_string()],
+            |row| row.get(0),
+        )?;
+        let sequence = match publication.sequence {
+            0 => offset + 1,
+            s => s as i64,
+        };
+        transaction.execute(
+            "INSERT INTO publications
+                (subscription_id, offset, publication_id, sequence, timestamp, record)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                publication.subscription_id.to_string(),
+                offset,
+                publication.publication_id.to_string(),
+                sequence,
+                publication.timestamp as i64,
+                record,
+            ],
+        )?;
+        transaction.commit()?;
+        Ok((offset as u64, record.len() as u64))
+    }
+
+    fn read(&self, data_log_id: &Uuid, offset: u64) -> Result<Publication, DataLogError> {
+        let record: Vec<u8> = self
+            .connection
+            .query_row(
+                "SELECT record FROM publications WHERE subscription_id = ?1 AND offset = ?2",
+                params![data_log_id.to_string(), offset as i64],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or(DataLogError::RecordNotFound(offset))?;
+        serde_cbor::from_slice(&record).map_err(DataLogError::ReadError)
+    }
+
+    fn scan(&self, data_log_id: &Uuid) -> Result<Vec<(u64, u64, Publication)>, DataLogError> {
+        let mut statement = self.connection.prepare(
+            "SELECT offset, record FROM publications WHERE subscription_id = ?1 ORDER BY offset",
+        )?;
+        let rows = statement.query_map(params![data_log_id.to_string()], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?;
+        let mut publications = Vec::new();
+        for row in rows {
+            let (offset, record) = row?;
+            match serde_cbor::from_slice(&record) {
+                Ok(publication) => {
+                    publications.push((offset as u64, record.len() as u64, publication))
+                }
+                Err(e) => warn!(
+                    "Skipping record {} of collection {}: {}",
+                    offset, data_log_id, e
+                ),
+            }
+        }
+        Ok(publications)
+    }
+
+    /// Selects the range by the indexed sequence numbers, a range starting at a timestamp
+    /// starts at the first (or last) publication accepted at or after (or before) it.
+    fn read_range(
+        &self,
+        data_log_id: &Uuid,
+        start: LogPosition,
+        limit: usize,
+        direction: RangeDirection,
+    ) -> Result<Option<LogRange>, DataLogError> {
+        let (bound, position) = match (start, direction) {
+            (LogPosition::Sequence(s), _) => ("?2", s),
+            (LogPosition::Timestamp(t), RangeDirection::Forward) => (
+                "(SELECT MIN(sequence) FROM publications
+                    WHERE subscription_id = ?1 AND timestamp >= ?2)",
+                t,
+            ),
+            (LogPosition::Timestamp(t), RangeDirection::Backward) => (
+                "(SELECT MAX(sequence) FROM publications
+                    WHERE subscription_id = ?1 AND timestamp <= ?2)",
+                t,
+            ),
+        };
+        let (range, order) = match direction {
+            RangeDirection::Forward => (format!("{} AND {}", bound, i64::MAX), "ASC"),
+            RangeDirection::Backward => (format!("0 AND {}", bound), "DESC"),
+        };
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT sequence, record FROM publications
+                WHERE subscription_id = ?1 AND sequence BETWEEN {}
+                ORDER BY sequence {} LIMIT ?3",
+            range, order
+        ))?;
+        let rows = statement.query_map(
+            params![
+                data_log_id.to_string(),
+                position.min(i64::MAX as u64) as i64,
+                limit.saturating_add(1).min(i64::MAX as usize) as i64,
+            ],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)),
+        )?;
+        let mut entries = Vec::new();
+        for row in rows {
+            let (sequence, record) = row?;
+            let mut publication: Publication =
+                serde_cbor::from_slice(&record).map_err(DataLogError::ReadError)?;
+            publication.sequence = sequence as u64;
+            entries.push(publication);
+        }
+        let next = if entries.len() > limit {
+            entries.pop().map(|p| LogPosition::Sequence(p.sequence))
+        } else {
+            None
+        };
+        Ok(Some((entries, next)))
+    }
+
+    fn remove(
+        &mut self,
+        data_log_id: &Uuid,
+        offsets: &BTreeSet<u64>,
+    ) -> Result<HashMap<u64, (u64, u64)>, DataLogError> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction
+                .prepare("DELETE FROM publications WHERE subscription_id = ?1 AND offset = ?2")?;
+            for offset in offsets {
+                statement.execute(params![data_log_id.to_string(), *offset as i64])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(HashMap::new())
+    }
+
+    fn read_metadata(&self, subscription_id: &Uuid) -> Result<Subscription, DataLogError> {
+        let record: Vec<u8> = self
+            .connection
+            .query_row(
+                "SELECT record FROM subscriptions WHERE id = ?1",
+                params![subscription_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or(DataLogError::MetadataNotFound(*subscription_id))?;
+        serde_cbor::from_slice(&record).map_err(DataLogError::ReadError)
+    }
+
+    /// Reads the metadata of every subscription, skipping records that can not be decoded
+    fn read_all_metadata(&self) -> Result<Vec<Subscription>, DataLogError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT id, record FROM subscriptions")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?;
+        let mut subscriptions = Vec::new();
+        for row in rows {
+            let (id, record) = row?;
+            match serde_cbor::from_slice(&record) {
+                Ok(subscription) => subscriptions.push(subscription),
+                Err(e) => warn!("Skipping metadata of subscription {}: {}", id, e),
+            }
+        }
+        Ok(subscriptions)
+    }
+
+    fn write_metadata(&mut self, subscription: &Subscription) -> Result<(), DataLogError> {
+        let record = serde_cbor::to_vec(subscription).map_err(DataLogError::WriteError)?;
+        self.connection.execute(
+            "INSERT INTO subscriptions (id, record) VALUES (?1, ?2)
+                ON CONFLICT (id) DO UPDATE SET record = excluded.record",
+            params![subscription.id.to_string(), record],
+        )?;
+        Ok(())
+    }
+
+    fn remove_metadata(&mut self, subscription_id: &Uuid) -> Result<(), DataLogError> {
+        self.connection.execute(
+            "DELETE FROM subscriptions WHERE id = ?1",
+            params![subscription_id.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn read_sequence_marks(&self) -> Result<HashMap<Uuid, u64>, DataLogError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT subscription_id, sequence FROM sequence_marks")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?;
+        let mut marks = HashMap::new();
+        for row in rows {
+            let (id, sequence) = row?;
+            match Uuid::from_str(&id) {
+                Ok(id) => {
+                    marks.insert(id, sequence as u64);
+                }
+                Err(e) => warn!("Skipping sequence mark of {}: {}", id, e),
+            }
+        }
+        Ok(marks)
+    }
+
+    fn write_sequence_mark(
+        &mut self,
+        data_log_id: &Uuid,
+        sequence: u64,
+    ) -> Result<(), DataLogError> {
+        self.connection.execute(
+            "INSERT INTO sequence_marks (subscription_id, sequence) VALUES (?1, ?2)
+                ON CONFLICT (subscription_id) DO UPDATE SET sequence = excluded.sequence",
+            params![data_log_id.to_string(), sequence as i64],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::data_log::DataLogger;
+    use crate::test_utils::{create_test_directory, remove_test_directory};
+
+    #[test]
+    fn test_sqlite_store() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let mut subscription = Subscription::new(&Uuid::new_v4(), "Test Subscription");
+        store.write_metadata(&subscription).unwrap();
+        subscription.append_subscriber(&Uuid::new_v4());
+        store.write_metadata(&subscription).unwrap();
+        assert_eq!(store.read_metadata(&subscription.id).unwrap(), subscription);
+        assert_eq!(
+            store.read_all_metadata().unwrap(),
+            vec![subscription.clone()]
+        );
+
+        let publications: Vec<Publication> = (1..=5)
+            .map(|sequence| Publication::new(&subscription.id, sequence, b"Test Publication"))
+            .collect();
+        for (offset, publication) in (0..).zip(&publications) {
+            assert_eq!(store.append(publication).unwrap().0, offset);
+        }
+        assert_eq!(store.collections().unwrap(), vec![subscription.id]);
+        assert_eq!(store.read(&subscription.id, 2).unwrap(), publications[2]);
+        store
+            .remove(&subscription.id, &[0, 3].iter().copied().collect())
+            .unwrap();
+        let sequences: Vec<u64> = store
+            .scan(&subscription.id)
+            .unwrap()
+            .into_iter()
+            .map(|(_, _, p)| p.sequence)
+            .collect();
+        assert_eq!(sequences, vec![2, 3, 5]);
+        assert!(store.read(&subscription.id, 0).is_err());
+        // Offsets of removed publications are not reused
+        store
+            .remove(&subscription.id, &[4].iter().copied().collect())
+            .unwrap();
+        assert_eq!(store.append(&publications[4]).unwrap().0, 5);
+        store.write_sequence_mark(&subscription.id, 4).unwrap();
+        store.write_sequence_mark(&subscription.id, 5).unwrap();
+        assert_eq!(
+            store.read_sequence_marks().unwrap(),
+            vec![(subscription.id, 5)].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_reading_ranges_from_sqlite() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let subscription_id = Uuid::new_v4();
+        for offset in 0..5u64 {
+            // The first publication was logged before sequence numbers were introduced
+            let sequence = if offset == 0 { 0 } else { offset + 1 };
+            let mut publication = Publication::new(&subscription_id, sequence, b"Publication");
+            publication.timestamp = (offset + 1) * 10;
+            store.append(&publication).unwrap();
+        }
+        let read_range = |start, limit, direction| {
+            let (entries, next) = store
+                .read_range(&subscription_id, start, limit, direction)
+                .unwrap()
+                .unwrap();
+            let sequences: Vec<u64> = entries.iter().map(|p| p.sequence).collect();
+            (sequences, next)
+        };
+        assert_eq!(
+            read_range(LogPosition::Sequence(2), 2, RangeDirection::Forward),
+            (vec![2, 3], Some(LogPosition::Sequence(4)))
+        );
+        assert_eq!(
+            read_range(LogPosition::Sequence(4), 10, RangeDirection::Backward),
+            (vec![4, 3, 2, 1], None)
+        );
+        assert_eq!(
+            read_range(LogPosition::Timestamp(25), 10, RangeDirection::Forward),
+            (vec![3, 4, 5], None)
+        );
+        assert_eq!(
+            read_range(LogPosition::Timestamp(25), 1, RangeDirection::Backward),
+            (vec![2], Some(LogPosition::Sequence(1)))
+        );
+        assert_eq!(
+            read_range(LogPosition::Timestamp(60), 10, RangeDirection::Forward),
+            (vec![], None)
+        );
+        assert_eq!(
+            read_range(LogPosition::Sequence(u64::MAX), 10, RangeDirection::Forward),
+            (vec![], None)
+        );
+    }
+
+    #[test]
+    fn test_recovering_data_logger_from_sqlite() {
+        let test_dir = create_test_directory();
+        let mut store = SqliteStore::open(&test_dir).unwrap();
+        let mut subscription = Subscription::new(&Uuid::new_v4(), "Test Subscription");
+        subscription.append_subscriber(&Uuid::new_v4());
+        store.write_metadata(&subscription).unwrap();
+        store
+            .append(&Publication::new(&subscription.id, 1, b"Test Publication"))
+            .unwrap();
+        drop(store);
+        let data_logger = DataLogger::with_store(SqliteStore::open(&test_dir).unwrap()).unwrap();
+        let recovered_state = data_logger.recover_state().unwrap();
+        assert_eq!(recovered_state.subscriptions, vec![subscription.clone()]);
+        assert_eq!(recovered_state.sequences.get(&subscription.id), Some(&1));
+        remove_test_directory(&test_dir);
+    }
+}
@@ -0,0 +1,588 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::time::Duration;
+
+use actix::prelude::{
+    Actor, ActorContext, ActorFuture, Addr, AsyncContext, Context, Handler, Message, Running,
+    WrapFuture,
+};
+use actix_web::{error, http::header, web, HttpRequest, HttpResponse};
+use futures_channel::mpsc::{unbounded, UnboundedSender};
+use futures_util::StreamExt;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    data_log::{DataLogger, ReplayPull, MAX_RANGE_LIMIT},
+    pubsub::{
+        AckCommand, DeliveryMode, Issue, Listen, ManageSession, ManageSubscription, PubSubService,
+        Publication, PublicationError, SessionHandle,
+    },
+    rest::identify_or_anonymous,
+    websocket::{ClientError, ErrorResponse},
+};
+
+/// Header a reconnecting `EventSource` sends with the id of the last event it received
+pub const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+
+/// Interval at which comments are sent to keep proxies from closing idle streams
+/// and to notice disconnected clients
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Selects what an event stream carries
+#[derive(Debug, Deserialize)]
+pub struct EventStreamQuery {
+    /// Comma separated ids of the subscriptions to stream
+    pub subscriptions: String,
+    /// Whether events carry whole publications (`publication` events)
+    /// or only their ids (`issue` events)
+    #[serde(default)]
+    pub delivery: DeliveryMode,
+}
+
+/// Encodes the position of an event stream in each of its subscriptions as event id,
+/// `<subscription_id>:<sequence>` pairs separated by commas
+pub fn encode_event_id(cursors: &BTreeMap<Uuid, u64>) -> String {
+    cursors
+        .iter()
+        .map(|(subscription_id, sequence)| format!("{}:{}", subscription_id, sequence))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Decodes an event id created by `encode_event_id`
+pub fn decode_event_id(id: &str) -> Result<BTreeMap<Uuid, u64>, ClientError> {
+    id.split(',')
+        .filter(|cursor| !cursor.trim().is_empty())
+        .map(|cursor| {
+            let (subscription_id, sequence) = cursor
+                .trim()
+                .split_once(':')
+                .ok_or_else(|| ClientError::InvalidInput(format!("Malformed event id {}", id)))?;
+            let sequence = u64::from_str(sequence)
+                .map_err(|e| ClientError::InvalidInput(format!("{}: {}", id, e)))?;
+            Ok((Uuid::from_str(subscription_id)?, sequence))
+        })
+        .collect()
+}
+
+/// Streams the publications of one or more subscriptions as server-sent events, for clients
+/// that only listen. The subscriptions are passed as `?subscriptions=<id>,<id>`.
+/// Each event's id holds the stream's position in every subscription; a client reconnecting
+/// with it as `Last-Event-ID` first receives the publications it missed from the data log.
+/// Subscriptions that had no event yet are streamed from the time of the reconnect.
+///
+/// Clients are identified like requests to the HTTP API, anonymous clients are allowed
+/// if no `Authenticator` is registered. Each stream is a session of its client,
+/// replacing any other session the client has. The subscriptions have to exist, streams
+/// join those their client did not subscribe to yet and only leave these when they close.
+pub async fn event_stream_handler(
+    req: HttpRequest,
+    query: web::Query<EventStreamQuery>,
+    pubsub: web::Data<Addr<PubSubService>>,
+    datalog: web::Data<Addr<DataLogger>>,
+) -> Result<HttpResponse, error::Error> {
+    let client_id = identify_or_anonymous(&req)?;
+    let subscriptions = query
+        .subscriptions
+        .split(',')
+        .map(|id| Uuid::from_str(id.trim()))
+        .collect::<Result<Vec<Uuid>, uuid::Error>>()
+        .map_err(|e| ErrorResponse::from(ClientError::from(e)))?;
+    let cursors = match req.headers().get(LAST_EVENT_ID_HEADER) {
+        Some(id) => id
+            .to_str()
+            .map_err(|e| ClientError::InvalidInput(e.to_string()))
+            .and_then(decode_event_id)
+            .map_err(ErrorResponse::from)?,
+        None => BTreeMap::new(),
+    };
+    // Subscriptions the client already has, e.g. through a durable subscription, are left alone
+    let mut joined = Vec::new();
+    for subscription_id in subscriptions.iter() {
+        let result = pubsub
+            .send(Listen {
+                client_id,
+                subscription_id: *subscription_id,
+            })
+            .await
+            .map_err(ErrorResponse::from)
+            .and_then(|r| r.map_err(ErrorResponse::from));
+        match result {
+            Ok(true) => joined.push(*subscription_id),
+            Ok(false) => (),
+            Err(e) => {
+                for subscription_id in joined {
+                    pubsub.do_send(ManageSubscription::Remove {
+                        client_id,
+                        subscription_id,
+                    });
+                }
+                return Err(e.into());
+            }
+        }
+    }
+    info!(
+        "Streaming events of {} subscriptions to {}",
+        subscriptions.len(),
+        client_id
+    );
+    let (sender, receiver) = unbounded();
+    let session = EventStreamSession {
+        id: client_id,
+        subscriptions,
+        joined,
+        delivery: query.delivery,
+        cursors: BTreeMap::new(),
+        replaying: HashMap::new(),
+        sender,
+        pubsub: pubsub.get_ref().clone(),
+        datalog: datalog.get_ref().clone(),
+    }
+    .start();
+    // Registered before replaying, so no publication falls in between. The delivery mode
+    // belongs to this session, so it covers publications replayed by the `PubSubService`
+    // and ends with the stream.
+    pubsub
+        .send(ManageSession::Add {
+            client_id,
+            session: SessionHandle::new(&session),
+            delivery: Some(DeliveryMode::Full),
+        })
+        .await
+        .map_err(ErrorResponse::from)?
+        .map_err(ErrorResponse::from)?;
+    session.do_send(Resume(cursors));
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .streaming(receiver.map(Ok::<_, error::Error>)))
+}
+
+/// The actor writing the events of a server-sent event stream.
+/// Receives publications in full, to keep track of the stream's position.
+#[derive(Debug)]
+pub struct EventStreamSession {
+    id: Uuid,
+    subscriptions: Vec<Uuid>,
+    /// Subscriptions the stream added its client to, which it leaves again when it stops
+    joined: Vec<Uuid>,
+    delivery: DeliveryMode,
+    /// Sequence number of the last publication streamed, per subscription
+    cursors: BTreeMap<Uuid, u64>,
+    /// Publications received while the ones a reconnecting client missed are fetched,
+    /// per subscription
+    replaying: HashMap<Uuid, Vec<Publication>>,
+    sender: UnboundedSender<web::Bytes>,
+    pubsub: Addr<PubSubService>,
+    datalog: Addr<DataLogger>,
+}
+
+impl EventStreamSession {
+    /// Writes to the response stream, stopping the session if the client went away.
+    /// Returns whether the data was written.
+    fn write(&mut self, data: String, ctx: &mut Context<Self>) -> bool {
+        if self.sender.unbounded_send(web::Bytes::from(data)).is_err() {
+            debug!("Event stream of {} was closed", self.id);
+            ctx.stop();
+            return false;
+        }
+        true
+    }
+
+    /// Writes an event carrying JSON encoded data, identified by the stream's position.
+    /// Returns whether the event was written.
+    fn write_event<T: Serialize>(
+        &mut self,
+        event: &str,
+        data: &T,
+        ctx: &mut Context<Self>,
+    ) -> bool {
+        let data = match serde_json::to_string(data) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Could not encode {} event: {}", event, e);
+                return false;
+            }
+        };
+        let id = encode_event_id(&self.cursors);
+        self.write(
+            format!("id: {}\nevent: {}\ndata: {}\n\n", id, event, data),
+            ctx,
+        )
+    }
+
+    /// Acknowledges a publication once it was written, so publications of durable
+    /// and queue-type subscriptions are not redelivered
+    fn acknowledge(&self, subscription_id: Uuid, publication_id: Uuid) {
+        self.pubsub
+            .do_send(AckCommand::new(&self.id, &subscription_id, &publication_id));
+    }
+
+    /// Streams a publication unless it was streamed before
+    fn stream_publication(&mut self, publication: Publication, ctx: &mut Context<Self>) {
+        let cursor = self.cursors.entry(publication.subscription_id).or_insert(0);
+        if publication.sequence <= *cursor {
+            return;
+        }
+        *cursor = publication.sequence;
+        let written = match self.delivery {
+            DeliveryMode::Full => self.write_event("publication", &publication, ctx),
+            DeliveryMode::Notify => {
+                let issue = Issue(publication.subscription_id, publication.publication_id);
+                self.write_event("issue", &issue, ctx)
+            }
+        };
+        if written {
+            self.acknowledge(publication.subscription_id, publication.publication_id);
+        }
+    }
+
+    /// Fetches the publications of a subscription after `since` from the data log and streams
+    /// them page by page, followed by the publications received in the meantime
+    fn replay(&mut self, subscription_id: Uuid, since: u64, ctx: &mut Context<Self>) {
+        self.replaying.entry(subscription_id).or_default();
+        let request = self.datalog.send(ReplayPull {
+            data_log_id: subscription_id,
+            since,
+            limit: MAX_RANGE_LIMIT,
+        });
+        ctx.spawn(request.into_actor(self).map(move |result, act, ctx| {
+            let missed = match result {
+                Ok(Ok(publications)) => publications,
+                Ok(Err(e)) => {
+                    warn!("Could not replay {}: {}", subscription_id, e);
+                    Vec::new()
+                }
+                Err(e) => {
+                    warn!("Could not replay {}: {}", subscription_id, e);
+                    Vec::new()
+                }
+            };
+            let next_page = match missed.last() {
+                Some(last) if missed.len() == MAX_RANGE_LIMIT => Some(last.sequence),
+                _ => None,
+            };
+            for publication in missed {
+                act.stream_publication(publication, ctx);
+            }
+            match next_page {
+                Some(since) => act.replay(subscription_id, since, ctx),
+                None => {
+                    let buffered = act.replaying.remove(&subscription_id).unwrap_or_default();
+                    for publication in buffered {
+                        act.stream_publication(publication, ctx);
+                    }
+                }
+            }
+        }));
+    }
+}
+
+impl Actor for EventStreamSession {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(KEEP_ALIVE_INTERVAL, |act, ctx| {
+            act.write(String::from(": keep-alive\n\n"), ctx);
+        });
+    }
+
+    fn stopping(&mut self, ctx: &mut Context<Self>) -> Running {
+        info!("Stopping event stream of {}", self.id);
+        self.pubsub.do_send(ManageSession::Remove {
+            client_id: self.id,
+            session: SessionHandle::new(&ctx.address()),
+        });
+        for subscription_id in &self.joined {
+            self.pubsub.do_send(ManageSubscription::Remove {
+                client_id: self.id,
+                subscription_id: *subscription_id,
+            });
+        }
+        Running::Stop
+    }
+}
+
+/// Starts streaming, replaying what the client missed since the positions it reconnected with
+#[derive(Debug, Message)]
+#[rtype("()")]
+struct Resume(BTreeMap<Uuid, u64>);
+
+impl Handler<Resume> for EventStreamSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Resume, ctx: &mut Context<Self>) {
+        for (subscription_id, since) in msg.0 {
+            if self.subscriptions.contains(&subscription_id) {
+                self.cursors.insert(subscription_id, since);
+                self.replay(subscription_id, since, ctx);
+            }
+        }
+        // Flushes the response headers
+        self.write(String::from(": connected\n\n"), ctx);
+    }
+}
+
+impl Handler<Publication> for EventStreamSession {
+    type Result = Result<(), PublicationError>;
+
+    fn handle(&mut self, msg: Publication, ctx: &mut Context<Self>) -> Self::Result {
+        match self.replaying.get_mut(&msg.subscription_id) {
+            Some(buffered) => buffered.push(msg),
+            None => self.stream_publication(msg, ctx),
+        }
+        Ok(())
+    }
+}
+
+// Subscriptions delivering notifications only don't reveal the publication's sequence number,
+// so the stream's position is not advanced by these
+impl Handler<Issue> for EventStreamSession {
+    type Result = Result<(), PublicationError>;
+
+    fn handle(&mut self, msg: Issue, ctx: &mut Context<Self>) -> Self::Result {
+        if self.write_event("issue", &msg, ctx) {
+            self.acknowledge(msg.0, msg.1);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::{body::ResponseBody, test, App};
+
+    use crate::pubsub::{SubmitCommand, SubscriptionKind};
+    use crate::test_utils::{bearer, id_token_authenticator};
+
+    /// Reads the next chunk of an event stream
+    async fn next_event(body: &mut ResponseBody<actix_web::dev::Body>) -> String {
+        let chunk = body.next().await.unwrap().unwrap();
+        String::from_utf8(chunk.to_vec()).unwrap()
+    }
+
+    /// Splits an event into its id, type and data
+    fn parse_event(event: &str) -> (String, String, String) {
+        let field = |name: &str| {
+            event
+                .lines()
+                .find_map(|l| l.strip_prefix(name))
+                .unwrap()
+                .to_owned()
+        };
+        (field("id: "), field("event: "), field("data: "))
+    }
+
+    #[test]
+    fn test_event_ids() {
+        let cursors: BTreeMap<Uuid, u64> = vec![(Uuid::new_v4(), 3), (Uuid::new_v4(), 12)]
+            .into_iter()
+            .collect();
+        let id = encode_event_id(&cursors);
+        assert_eq!(decode_event_id(&id).unwrap(), cursors);
+        assert_eq!(decode_event_id("").unwrap(), BTreeMap::new());
+        assert!(decode_event_id("3").is_err());
+        assert!(decode_event_id(&format!("{}:x", Uuid::new_v4())).is_err());
+        assert!(decode_event_id("nope:3").is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_event_stream() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub = PubSubService::new(&data_log).start();
+        let (publisher, subscription_id) = (Uuid::new_v4(), Uuid::new_v4());
+        pubsub
+            .send(ManageSubscription::Add {
+                client_id: publisher,
+                subscription_id,
+                kind: SubscriptionKind::Broadcast,
+                durable: false,
+                topic: None,
+                create: true,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(pubsub.clone())
+                .data(data_log.clone())
+                .route("/events", web::get().to(event_stream_handler)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/events?subscriptions=invalid")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/events?subscriptions={}&delivery=Full",
+                subscription_id
+            ))
+            .to_request();
+        let mut resp = test::call_service(&mut app, req).await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/event-stream"
+        );
+        let mut body = resp.take_body();
+        assert_eq!(next_event(&mut body).await, ": connected\n\n");
+        let mut published = Vec::new();
+        for data in &[b"first", b"other"] {
+            let submit = SubmitCommand::new(&publisher, &subscription_id, *data);
+            published.push(pubsub.send(submit).await.unwrap().unwrap());
+        }
+        let (first_id, event, data) = parse_event(&next_event(&mut body).await);
+        assert_eq!(first_id, format!("{}:1", subscription_id));
+        assert_eq!(event, "publication");
+        let publication: Publication = serde_json::from_str(&data).unwrap();
+        assert_eq!(publication.data, b"first");
+        let (id, _, _) = parse_event(&next_event(&mut body).await);
+        assert_eq!(id, format!("{}:2", subscription_id));
+        drop(body);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/events?subscriptions={}", subscription_id))
+            .header(LAST_EVENT_ID_HEADER, first_id)
+            .to_request();
+        let mut resp = test::call_service(&mut app, req).await;
+        let mut body = resp.take_body();
+        assert_eq!(next_event(&mut body).await, ": connected\n\n");
+        let (id, event, data) = parse_event(&next_event(&mut body).await);
+        assert_eq!(id, format!("{}:2", subscription_id));
+        assert_eq!(event, "issue");
+        let Issue(issued_for, publication_id) = serde_json::from_str(&data).unwrap();
+        assert_eq!(
+            (issued_for, publication_id),
+            (subscription_id, published[1])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_leaving_only_joined_subscriptions() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub = PubSubService::new(&data_log).start();
+        let (client_id, owner) = (Uuid::new_v4(), Uuid::new_v4());
+        let (durable_id, joined_id) = (Uuid::new_v4(), Uuid::new_v4());
+        for (subscriber, subscription_id, durable) in
+            [(client_id, durable_id, true), (owner, joined_id, false)]
+        {
+            pubsub
+                .send(ManageSubscription::Add {
+                    client_id: subscriber,
+                    subscription_id,
+                    kind: SubscriptionKind::Broadcast,
+                    durable,
+                    topic: None,
+                    create: true,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        let mut app = test::init_service(
+            App::new()
+                .data(pubsub.clone())
+                .data(data_log.clone())
+                .app_data(id_token_authenticator(&[client_id]))
+                .route("/events", web::get().to(event_stream_handler)),
+        )
+        .await;
+        // Streams don't create subscriptions
+        let req = test::TestRequest::get()
+            .uri(&format!("/events?subscriptions={}", Uuid::new_v4()))
+            .header(header::AUTHORIZATION, bearer(&client_id))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/events?subscriptions={},{}",
+                durable_id, joined_id
+            ))
+            .header(header::AUTHORIZATION, bearer(&client_id))
+            .to_request();
+        let mut resp = test::call_service(&mut app, req).await;
+        let mut body = resp.take_body();
+        assert_eq!(next_event(&mut body).await, ": connected\n\n");
+        drop(body);
+        // The stream notices its client went away when it writes the next event
+        let submit = SubmitCommand::new(&owner, &joined_id, b"Test");
+        pubsub.send(submit).await.unwrap().unwrap();
+        let listen = |subscription_id| Listen {
+            client_id,
+            subscription_id,
+        };
+        // Listening doesn't change subscriptions the client is still part of,
+        // so it can be repeated until the stream left the one it joined
+        let left = async { while pubsub.send(listen(joined_id)).await.unwrap() != Ok(true) {} };
+        actix_rt::time::timeout(Duration::from_secs(1), left)
+            .await
+            .unwrap();
+        assert_eq!(pubsub.send(listen(durable_id)).await.unwrap(), Ok(false));
+    }
+
+    #[actix_rt::test]
+    async fn test_acknowledging_streamed_publications() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub = PubSubService::new(&data_log).start();
+        let (client_id, subscription_id) = (Uuid::new_v4(), Uuid::new_v4());
+        pubsub
+            .send(ManageSubscription::Add {
+                client_id,
+                subscription_id,
+                kind: SubscriptionKind::Broadcast,
+                durable: true,
+                topic: None,
+                create: true,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(pubsub.clone())
+                .data(data_log.clone())
+                .app_data(id_token_authenticator(&[client_id]))
+                .route("/events", web::get().to(event_stream_handler)),
+        )
+        .await;
+        let stream = || {
+            test::TestRequest::get()
+                .uri(&format!(
+                    "/events?subscriptions={}&delivery=Full",
+                    subscription_id
+                ))
+                .header(header::AUTHORIZATION, bearer(&client_id))
+                .to_request()
+        };
+        let mut resp = test::call_service(&mut app, stream()).await;
+        let mut body = resp.take_body();
+        assert_eq!(next_event(&mut body).await, ": connected\n\n");
+        let submit = SubmitCommand::new(&client_id, &subscription_id, b"first");
+        pubsub.send(submit).await.unwrap().unwrap();
+        let (_, _, data) = parse_event(&next_event(&mut body).await);
+        let publication: Publication = serde_json::from_str(&data).unwrap();
+        assert_eq!(publication.data, b"first");
+        drop(body);
+        // Written to a closed stream, so it is not acknowledged
+        let submit = SubmitCommand::new(&client_id, &subscription_id, b"second");
+        pubsub.send(submit).await.unwrap().unwrap();
+        // The durable cursor advanced past the acknowledged publication only
+        let mut resp = test::call_service(&mut app, stream()).await;
+        let mut body = resp.take_body();
+        let mut event = next_event(&mut body).await;
+        // Replayed publications may precede the comment flushing the headers
+        if event.starts_with(':') {
+            event = next_event(&mut body).await;
+        }
+        let (_, _, data) = parse_event(&event);
+        let publication: Publication = serde_json::from_str(&data).unwrap();
+        assert_eq!(publication.data, b"second");
+    }
+}
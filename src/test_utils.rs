@@ -0,0 +1,35 @@
+//! Fixtures shared by the test modules of the crate
+
+use std::collections::HashMap;
+use std::env::temp_dir;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use actix_web::web;
+use uuid::Uuid;
+
+use crate::auth::{Authenticator, StaticTokenAuthenticator};
+
+/// Creates an empty, uniquely named directory under the system's temporary directory
+pub fn create_test_directory() -> PathBuf {
+    let mut p = temp_dir();
+    p.push(format!("infotainer-{}", Uuid::new_v4().to_hyphenated()));
+    std::fs::create_dir(&p).unwrap();
+    p
+}
+
+pub fn remove_test_directory(p: &Path) {
+    std::fs::remove_dir_all(p).unwrap();
+}
+
+/// An `Authenticator` accepting the id of each of `clients` as its bearer token
+pub fn id_token_authenticator(clients: &[Uuid]) -> web::Data<dyn Authenticator> {
+    let tokens: HashMap<String, Uuid> = clients.iter().map(|id| (id.to_string(), *id)).collect();
+    let authenticator: Arc<dyn Authenticator> = Arc::new(StaticTokenAuthenticator::new(tokens));
+    web::Data::from(authenticator)
+}
+
+/// The `Authorization` header value authenticating a client with `id_token_authenticator`
+pub fn bearer(client_id: &Uuid) -> String {
+    format!("Bearer {}", client_id)
+}
@@ -0,0 +1,147 @@
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Separates the levels of a topic, e.g. `sensors/building-a/temp`
+pub const LEVEL_SEPARATOR: char = '/';
+/// Matches exactly one topic level
+pub const SINGLE_LEVEL_WILDCARD: &str = "+";
+/// Matches any number of topic levels, including none. Only allowed as the last level.
+pub const MULTI_LEVEL_WILDCARD: &str = "#";
+
+/// Namespace of the name-based UUIDs identifying the subscription of a topic
+const TOPIC_NAMESPACE: Uuid = Uuid::from_bytes([
+    0x3c, 0x1b, 0x5e, 0x0a, 0x52, 0x8f, 0x4d, 0x2e, 0x9a, 0x41, 0x6b, 0x0d, 0xe2, 0x77, 0x19, 0xc4,
+]);
+
+/// Represents errors caused by malformed topics or topic patterns
+#[derive(Debug, Error, PartialEq, Clone)]
+pub enum TopicError {
+    #[error("Topic is empty")]
+    Empty,
+    #[error("Topic {0} contains an empty level")]
+    EmptyLevel(String),
+    #[error("Topic {0} contains wildcards")]
+    Wildcard(String),
+    #[error("Pattern {0} contains a misplaced wildcard")]
+    MisplacedWildcard(String),
+}
+
+/// The id of the subscription a topic is mapped to. Every topic maps to the same id,
+/// so clients can address topic subscriptions by id as well.
+pub fn topic_id(topic: &str) -> Uuid {
+    Uuid::new_v5(&TOPIC_NAMESPACE, topic.as_bytes())
+}
+
+/// Returns true if the topic contains wildcards, making it a pattern
+pub fn is_pattern(topic: &str) -> bool {
+    topic
+        .split(LEVEL_SEPARATOR)
+        .any(|level| level == SINGLE_LEVEL_WILDCARD || level == MULTI_LEVEL_WILDCARD)
+}
+
+/// Checks that a topic publications can be submitted to is well-formed
+pub fn validate_topic(topic: &str) -> Result<(), TopicError> {
+    validate_pattern(topic)?;
+    if is_pattern(topic) {
+        return Err(TopicError::Wildcard(topic.to_owned()));
+    }
+    Ok(())
+}
+
+/// Checks that a topic pattern is well-formed. Wildcards have to span a whole level,
+/// a multi-level wildcard is only allowed as the last level.
+pub fn validate_pattern(pattern: &str) -> Result<(), TopicError> {
+    if pattern.is_empty() {
+        return Err(TopicError::Empty);
+    }
+    let levels: Vec<&str> = pattern.split(LEVEL_SEPARATOR).collect();
+    for (position, level) in levels.iter().enumerate() {
+        if level.is_empty() {
+            return Err(TopicError::EmptyLevel(pattern.to_owned()));
+        }
+        let misplaced = (level.len() > 1
+            && (level.contains(SINGLE_LEVEL_WILDCARD) || level.contains(MULTI_LEVEL_WILDCARD)))
+            || (*level == MULTI_LEVEL_WILDCARD && position + 1 != levels.len());
+        if misplaced {
+            return Err(TopicError::MisplacedWildcard(pattern.to_owned()));
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if `topic` matches `pattern`
+pub fn matches(pattern: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split(LEVEL_SEPARATOR);
+    for level in pattern.split(LEVEL_SEPARATOR) {
+        if level == MULTI_LEVEL_WILDCARD {
+            return true;
+        }
+        match topic_levels.next() {
+            Some(t) if level == SINGLE_LEVEL_WILDCARD || level == t => (),
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matching_topics() {
+        assert!(matches(
+            "sensors/building-a/temp",
+            "sensors/building-a/temp"
+        ));
+        assert!(matches("sensors/+/temp", "sensors/building-a/temp"));
+        assert!(!matches("sensors/+/temp", "sensors/building-a/humidity"));
+        assert!(!matches("sensors/+", "sensors/building-a/temp"));
+        assert!(matches("sensors/#", "sensors/building-a/temp"));
+        assert!(matches("sensors/#", "sensors"));
+        assert!(matches("#", "sensors/building-a/temp"));
+        assert!(!matches("sensors/building-a", "sensors/building-a/temp"));
+        assert!(!matches(
+            "sensors/building-a/temp/raw",
+            "sensors/building-a/temp"
+        ));
+    }
+
+    #[test]
+    fn test_validating_topics() {
+        assert_eq!(validate_topic("sensors/building-a/temp"), Ok(()));
+        assert_eq!(validate_pattern("sensors/+/temp"), Ok(()));
+        assert_eq!(validate_pattern("sensors/#"), Ok(()));
+        assert_eq!(validate_topic(""), Err(TopicError::Empty));
+        assert_eq!(
+            validate_topic("sensors/+/temp"),
+            Err(TopicError::Wildcard(String::from("sensors/+/temp")))
+        );
+        assert_eq!(
+            validate_pattern("sensors//temp"),
+            Err(TopicError::EmptyLevel(String::from("sensors//temp")))
+        );
+        assert_eq!(
+            validate_pattern("sensors/#/temp"),
+            Err(TopicError::MisplacedWildcard(String::from(
+                "sensors/#/temp"
+            )))
+        );
+        assert_eq!(
+            validate_pattern("sensors/temp+"),
+            Err(TopicError::MisplacedWildcard(String::from("sensors/temp+")))
+        );
+    }
+
+    #[test]
+    fn test_topic_ids() {
+        assert_eq!(
+            topic_id("sensors/building-a/temp"),
+            topic_id("sensors/building-a/temp")
+        );
+        assert_ne!(
+            topic_id("sensors/building-a/temp"),
+            topic_id("sensors/building-b/temp")
+        );
+    }
+}
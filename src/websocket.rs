@@ -1,29 +1,237 @@
+use std::collections::HashMap;
 use std::time::{Duration, Instant};
 
-use actix::prelude::{Actor, ActorContext, Addr, AsyncContext, Handler, Running, StreamHandler};
-use actix_web::{error, web};
+use std::future::Future;
+
+use actix::prelude::{
+    Actor, ActorContext, ActorFuture, Addr, AsyncContext, Handler, MailboxError, Message, Running,
+    StreamHandler, WrapFuture,
+};
+use actix_web::{error, http::header, web};
 use actix_web_actors::ws;
 use log::{debug, error, info, trace, warn};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use uuid::Uuid;
 
-use crate::data_log::LogIndexPut;
-use crate::pubsub::ManageSession;
 use crate::{
-    data_log::{DataLogError, DataLogPull, DataLogPut, DataLogger, LogIndexPull},
+    auth::{AuthError, Authenticator},
+    data_log::{
+        DataLogError, DataLogPull, DataLogPut, DataLogger, LogIndexPull, LogIndexPut, LogPosition,
+        LogRangePull, LogRangePut, MetadataPull, MetadataPut, RangeDirection, RetentionPolicy,
+    },
+    protocol::{
+        Agreement, Capability, ProtocolError, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+        SERVER_CAPABILITIES,
+    },
     pubsub::{
-        Issue, ManageSubscription, PubSubService, Publication, PublicationError, SubmitCommand,
-    }
+        AckCommand, CheckReadAccess, DeliveryMode, Issue, ManageSession, ManageSubscription,
+        PubSubService, Publication, PublicationError, SessionHandle, SubmitCommand, Subscription,
+        SubscriptionKind, TopicPull,
+    },
+    topic::topic_id,
 };
 
-
 /// Represents a message sent by the server to a connected client
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ServerMessage {
     Issue(Issue),
+    Publication(Publication),
     LogIndex(LogIndexPut),
     LogEntry(Vec<Publication>),
+    LogRange(LogRangePut),
+    SubscriptionInfo(Vec<Subscription>),
+    /// Sent when a session starts: the protocol versions and capabilities the server supports.
+    /// Until the client agrees on them with `ClientCommand::Hello`, the session speaks
+    /// `MIN_PROTOCOL_VERSION` with `BASELINE_CAPABILITIES`.
+    Offer {
+        min_version: u32,
+        max_version: u32,
+        capabilities: Vec<Capability>,
+    },
+    /// Answers `ClientCommand::Hello` with the protocol version and capabilities
+    /// the session agreed upon
+    Welcome {
+        version: u32,
+        capabilities: Vec<Capability>,
+    },
+    /// A command sent with a request id was handled. Data requested by the command
+    /// is sent before its acknowledgement.
+    Ack {
+        request_id: u64,
+        result: Result<CommandOutcome, ErrorResponse>,
+    },
+    /// A command failed. `command` names the `ClientCommand` variant that caused the error,
+    /// it is `None` if the command could not be decoded.
+    /// Failures of commands sent with a request id are reported by `Ack` instead.
+    Error {
+        code: ErrorCode,
+        message: String,
+        command: Option<String>,
+    },
+}
+
+/// The result of a successfully handled command, see `ServerMessage::Ack`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CommandOutcome {
+    /// The command was carried out
+    Done,
+    /// The submission was accepted and logged as the publication with the given id
+    Published { publication_id: Uuid },
+}
+
+impl From<()> for CommandOutcome {
+    fn from(_: ()) -> CommandOutcome {
+        CommandOutcome::Done
+    }
+}
+
+impl From<Uuid> for CommandOutcome {
+    fn from(publication_id: Uuid) -> CommandOutcome {
+        CommandOutcome::Published { publication_id }
+    }
+}
+
+/// Classifies the errors reported by `ServerMessage::Error`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The command could not be decoded or is invalid
+    InvalidInput,
+    /// The addressed subscription does not exist
+    SubscriptionNotFound,
+    /// The addressed publication does not exist
+    PublicationNotFound,
+    /// The publication could not be distributed to subscribers
+    PublishingFailed,
+    /// Stored data failed verification
+    DataCorrupted,
+    /// Data could not be read from or written to the data log
+    DataLogFailure,
+    /// The client is not allowed to access the subscription
+    AccessDenied,
+    /// The client's protocol version is not supported, the session is closed
+    UnsupportedVersion,
+    /// The server could not process the command
+    Unavailable,
+}
+
+/// An error to be reported to a client, see `ServerMessage::Error`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorResponse {
+    fn new(code: ErrorCode, error: &dyn std::fmt::Display) -> ErrorResponse {
+        ErrorResponse {
+            code,
+            message: error.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl From<ClientError> for ErrorResponse {
+    fn from(e: ClientError) -> ErrorResponse {
+        match e {
+            ClientError::InvalidInput(_) => ErrorResponse::new(ErrorCode::InvalidInput, &e),
+        }
+    }
+}
+
+impl From<PublicationError> for ErrorResponse {
+    fn from(e: PublicationError) -> ErrorResponse {
+        let code = match e {
+            PublicationError::Subscriptions(_) => ErrorCode::SubscriptionNotFound,
+            PublicationError::Publishing(_) => ErrorCode::PublishingFailed,
+            PublicationError::DataLoggingError(_) => ErrorCode::DataLogFailure,
+            PublicationError::SessionService(_) => ErrorCode::Unavailable,
+            PublicationError::AccessDenied(_) => ErrorCode::AccessDenied,
+            PublicationError::InvalidTopic(_) => ErrorCode::InvalidInput,
+        };
+        ErrorResponse::new(code, &e)
+    }
+}
+
+impl From<DataLogError> for ErrorResponse {
+    fn from(e: DataLogError) -> ErrorResponse {
+        let code = match e {
+            DataLogError::EntryNotFound(_) | DataLogError::RecordNotFound(_) => {
+                ErrorCode::PublicationNotFound
+            }
+            DataLogError::MetadataNotFound(_) => ErrorCode::SubscriptionNotFound,
+            DataLogError::ChecksumMismatch(_) => ErrorCode::DataCorrupted,
+            _ => ErrorCode::DataLogFailure,
+        };
+        ErrorResponse::new(code, &e)
+    }
+}
+
+impl From<ProtocolError> for ErrorResponse {
+    fn from(e: ProtocolError) -> ErrorResponse {
+        let code = match e {
+            ProtocolError::UnsupportedVersion(_) => ErrorCode::UnsupportedVersion,
+            ProtocolError::RepeatedHandshake => ErrorCode::InvalidInput,
+        };
+        ErrorResponse::new(code, &e)
+    }
+}
+
+impl From<MailboxError> for ErrorResponse {
+    fn from(e: MailboxError) -> ErrorResponse {
+        ErrorResponse::new(ErrorCode::Unavailable, &e)
+    }
+}
+
+/// Websocket subprotocol of sessions exchanging CBOR encoded messages in binary frames
+pub const CBOR_SUBPROTOCOL: &str = "infotainer.cbor";
+/// Websocket subprotocol of sessions exchanging JSON encoded messages in text frames
+pub const JSON_SUBPROTOCOL: &str = "infotainer.json";
+
+/// How `ClientCommand`s and `ServerMessage`s are encoded on a websocket session
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Encoding {
+    /// CBOR, carried in binary frames
+    #[default]
+    Cbor,
+    /// JSON, carried in text frames
+    Json,
+}
+
+impl Encoding {
+    /// The encoding a websocket subprotocol stands for
+    pub fn from_subprotocol(protocol: &str) -> Option<Encoding> {
+        match protocol {
+            CBOR_SUBPROTOCOL => Some(Encoding::Cbor),
+            JSON_SUBPROTOCOL => Some(Encoding::Json),
+            _ => None,
+        }
+    }
+
+    /// The websocket subprotocol standing for the encoding
+    pub fn subprotocol(&self) -> &'static str {
+        match self {
+            Encoding::Cbor => CBOR_SUBPROTOCOL,
+            Encoding::Json => JSON_SUBPROTOCOL,
+        }
+    }
+}
+
+/// Picks the encoding of the first subprotocol requested by the client that the server knows.
+/// This is the subprotocol `ws::start_with_protocols` confirms to the client.
+fn negotiate_encoding(req: &web::HttpRequest) -> Option<Encoding> {
+    req.headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .find_map(|protocol| Encoding::from_subprotocol(protocol.trim()))
 }
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
@@ -42,6 +250,12 @@ impl From<serde_cbor::Error> for ClientError {
     }
 }
 
+impl From<serde_json::Error> for ClientError {
+    fn from(e: serde_json::Error) -> ClientError {
+        ClientError::InvalidInput(format!("{}", e))
+    }
+}
+
 impl From<uuid::Error> for ClientError {
     fn from(e: uuid::Error) -> ClientError {
         ClientError::InvalidInput(format!("{}", e))
@@ -49,6 +263,11 @@ impl From<uuid::Error> for ClientError {
 }
 
 /// Start a new WebSocketSession for the requesting client and start the actor.
+/// If an `Authenticator` is registered as app data, the upgrade is rejected unless the request
+/// authenticates the client identified by `session_id`.
+/// Clients may choose the encoding of the session through the websocket subprotocol
+/// (`infotainer.cbor` or `infotainer.json`), otherwise the server replies in the encoding
+/// of the last message it received, CBOR until the client sent one.
 pub async fn websocket_handler(
     req: web::HttpRequest,
     stream: web::Payload,
@@ -56,12 +275,28 @@ pub async fn websocket_handler(
     pubsub: web::Data<Addr<PubSubService>>,
     datalog: web::Data<Addr<DataLogger>>,
 ) -> Result<web::HttpResponse, error::Error> {
-    let websocket_session = WebSocketSession::new(
-        pubsub.get_ref(),
-        datalog.get_ref(),
-        &session_id,
-    );
-    ws::start(websocket_session, &req, stream)
+    if let Some(authenticator) = req.app_data::<web::Data<dyn Authenticator>>() {
+        let client_id = authenticator.authenticate(&req).map_err(|e| {
+            warn!("Rejecting session {}: {}", *session_id, e);
+            e
+        })?;
+        if client_id != *session_id {
+            warn!("Rejecting session {} for client {}", *session_id, client_id);
+            return Err(AuthError::IdentityMismatch(client_id, *session_id).into());
+        }
+    }
+    let mut websocket_session =
+        WebSocketSession::new(pubsub.get_ref(), datalog.get_ref(), &session_id);
+    if let Some(encoding) = negotiate_encoding(&req) {
+        websocket_session.encoding = encoding;
+        websocket_session.negotiated = true;
+    }
+    ws::start_with_protocols(
+        websocket_session,
+        &[CBOR_SUBPROTOCOL, JSON_SUBPROTOCOL],
+        &req,
+        stream,
+    )
 }
 
 /// The actor responsible handling client-server communication.
@@ -71,6 +306,15 @@ pub struct WebSocketSession {
     hb: Instant,
     pubsub: Addr<PubSubService>,
     datalog: Addr<DataLogger>,
+    /// Encoding of the messages sent to the client
+    encoding: Encoding,
+    /// Whether the encoding was negotiated through the websocket subprotocol.
+    /// Otherwise it follows the encoding of the messages the client sends.
+    negotiated: bool,
+    /// Protocol version and capabilities agreed upon with the client
+    protocol: Agreement,
+    /// Whether the client sent `ClientCommand::Hello`
+    handshaken: bool,
 }
 
 impl WebSocketSession {
@@ -84,6 +328,385 @@ impl WebSocketSession {
             hb: Instant::now(),
             pubsub: pubsub.clone(),
             datalog: datalog.clone(),
+            encoding: Encoding::default(),
+            negotiated: false,
+            protocol: Agreement::default(),
+            handshaken: false,
+        }
+    }
+
+    /// Forwards a client command to the actor responsible for it
+    fn handle_command(&mut self, request: ClientRequest, ctx: &mut <Self as Actor>::Context) {
+        let ClientRequest {
+            request_id,
+            command: cmd,
+        } = request;
+        let command = cmd.name();
+        match cmd {
+            ClientCommand::Hello {
+                version,
+                capabilities,
+            } => self.handshake(request_id, version, &capabilities, ctx),
+            ClientCommand::GetLogEntries { log_id, entries } => {
+                let request = DataLogPull {
+                    client: ctx.address().recipient(),
+                    data_log_id: log_id,
+                    selection: entries,
+                };
+                self.forward_read(command, request_id, log_id, request, ctx)
+            }
+            ClientCommand::GetLogIndex { log_id, since } => {
+                let request = LogIndexPull {
+                    client: ctx.address().recipient(),
+                    data_log_id: log_id,
+                    since,
+                };
+                self.forward_read(command, request_id, log_id, request, ctx)
+            }
+            ClientCommand::GetLogRange {
+                log_id,
+                start,
+                limit,
+                direction,
+            } => {
+                let request = LogRangePull {
+                    client: ctx.address().recipient(),
+                    data_log_id: log_id,
+                    start,
+                    limit,
+                    direction,
+                };
+                self.forward_read(command, request_id, log_id, request, ctx)
+            }
+            ClientCommand::GetSubscriptionInfo { subscription_id } => {
+                let request = MetadataPull::Single {
+                    client: ctx.address().recipient(),
+                    data_log_id: subscription_id,
+                };
+                self.forward_read(command, request_id, subscription_id, request, ctx)
+            }
+            ClientCommand::SubmitPublication {
+                subscription_id,
+                submission,
+                key,
+                retain,
+                headers,
+            } => {
+                let request = self.pubsub.send(
+                    SubmitCommand::new(&self.id, &subscription_id, &submission)
+                        .with_key(key)
+                        .with_retain(retain)
+                        .with_headers(headers),
+                );
+                self.forward(command, request_id, request, ctx)
+            }
+            ClientCommand::Subscribe {
+                subscription_id,
+                kind,
+                durable,
+                create,
+            } => {
+                let request = self.pubsub.send(ManageSubscription::Add {
+                    client_id: self.id,
+                    subscription_id,
+                    kind,
+                    durable,
+                    topic: None,
+                    create,
+                });
+                self.forward(command, request_id, request, ctx)
+            }
+            ClientCommand::SubscribeTopic {
+                topic,
+                kind,
+                durable,
+                create,
+            } => {
+                let request = self.pubsub.send(ManageSubscription::Add {
+                    client_id: self.id,
+                    subscription_id: topic_id(&topic),
+                    kind,
+                    durable,
+                    topic: Some(topic),
+                    create,
+                });
+                self.forward(command, request_id, request, ctx)
+            }
+            ClientCommand::UnsubscribeTopic { topic } => {
+                let request = self.pubsub.send(ManageSubscription::Remove {
+                    client_id: self.id,
+                    subscription_id: topic_id(&topic),
+                });
+                self.forward(command, request_id, request, ctx)
+            }
+            ClientCommand::PublishTopic {
+                topic,
+                submission,
+                key,
+                retain,
+                headers,
+            } => {
+                let request = self.pubsub.send(
+                    SubmitCommand::new(&self.id, &topic_id(&topic), &submission)
+                        .with_key(key)
+                        .with_retain(retain)
+                        .with_headers(headers)
+                        .with_topic(topic),
+                );
+                self.forward(command, request_id, request, ctx)
+            }
+            ClientCommand::ListTopics { pattern } => {
+                let request = self.pubsub.send(TopicPull {
+                    client_id: self.id,
+                    client: ctx.address().recipient(),
+                    pattern,
+                });
+                self.forward(command, request_id, request, ctx)
+            }
+            ClientCommand::Unsubscribe { subscription_id } => {
+                let request = self.pubsub.send(ManageSubscription::Remove {
+                    client_id: self.id,
+                    subscription_id,
+                });
+                self.forward(command, request_id, request, ctx)
+            }
+            ClientCommand::SetDeliveryMode {
+                subscription_id,
+                mode,
+            } => {
+                let request = self.pubsub.send(ManageSubscription::SetDelivery {
+                    client_id: self.id,
+                    subscription_id,
+                    mode,
+                });
+                self.forward(command, request_id, request, ctx)
+            }
+            ClientCommand::SetSessionDeliveryMode { mode } => {
+                let request = self.pubsub.send(ManageSession::SetDelivery {
+                    client_id: self.id,
+                    mode,
+                });
+                self.forward(command, request_id, request, ctx)
+            }
+            ClientCommand::SetRetentionPolicy {
+                subscription_id,
+                policy,
+            } => {
+                let request = self.pubsub.send(ManageSubscription::SetRetention {
+                    client_id: self.id,
+                    subscription_id,
+                    policy,
+                });
+                self.forward(command, request_id, request, ctx)
+            }
+            ClientCommand::SetAccessControl {
+                subscription_id,
+                publishers,
+                subscribers,
+            } => {
+                let request = self.pubsub.send(ManageSubscription::SetAccess {
+                    client_id: self.id,
+                    subscription_id,
+                    publishers,
+                    subscribers,
+                });
+                self.forward(command, request_id, request, ctx)
+            }
+            ClientCommand::ClearRetained { subscription_id } => {
+                let request = self.pubsub.send(ManageSubscription::ClearRetained {
+                    client_id: self.id,
+                    subscription_id,
+                });
+                self.forward(command, request_id, request, ctx)
+            }
+            ClientCommand::Ack {
+                subscription_id,
+                publication_id,
+            } => {
+                let request =
+                    self.pubsub
+                        .send(AckCommand::new(&self.id, &subscription_id, &publication_id));
+                self.forward(command, request_id, request, ctx)
+            }
+        }
+    }
+
+    /// Agrees on a protocol version and capabilities with the client and answers with
+    /// `ServerMessage::Welcome`. The session is closed if the client's version is not supported.
+    fn handshake(
+        &mut self,
+        request_id: Option<u64>,
+        version: u32,
+        capabilities: &[Capability],
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let agreement = if self.handshaken {
+            Err(ProtocolError::RepeatedHandshake)
+        } else {
+            Agreement::negotiate(version, capabilities)
+        };
+        let agreement = match agreement {
+            Ok(agreement) => agreement,
+            Err(e) => {
+                warn!("Handshake of {} failed: {}", self.id, e);
+                let refused = matches!(e, ProtocolError::UnsupportedVersion(_));
+                let description = e.to_string();
+                self.reply("Hello", request_id, Err(e.into()), ctx);
+                if refused {
+                    ctx.close(Some(ws::CloseReason {
+                        code: ws::CloseCode::Protocol,
+                        description: Some(description),
+                    }));
+                    ctx.stop();
+                }
+                return;
+            }
+        };
+        info!(
+            "{} speaks protocol version {} with {:?}",
+            self.id, agreement.version, agreement.capabilities
+        );
+        // Sessions start out registered, see `Agreement::default`
+        if !agreement.grants(Capability::PushDelivery) {
+            self.pubsub.do_send(ManageSession::Remove {
+                client_id: self.id,
+                session: SessionHandle::new(&ctx.address()),
+            });
+        }
+        self.handshaken = true;
+        self.protocol = agreement;
+        let welcome = ServerMessage::Welcome {
+            version: self.protocol.version,
+            capabilities: self.protocol.capabilities.clone(),
+        };
+        self.send_message(&welcome, ctx);
+        self.reply("Hello", request_id, Ok(CommandOutcome::Done), ctx);
+    }
+
+    /// Reports the outcome of a command to the client, as `ServerMessage::Ack`
+    /// if it carried a request id and acks were agreed upon, otherwise only if it failed
+    fn reply(
+        &self,
+        command: &str,
+        request_id: Option<u64>,
+        result: Result<CommandOutcome, ErrorResponse>,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        if let Err(error) = &result {
+            error!("{} of {} failed: {}", command, self.id, error.message);
+        }
+        let request_id = request_id.filter(|_| self.protocol.grants(Capability::Acks));
+        match (request_id, result) {
+            (Some(request_id), result) => {
+                self.send_message(&ServerMessage::Ack { request_id, result }, ctx)
+            }
+            (None, Err(error)) => self.send_error(error, Some(command), ctx),
+            (None, Ok(_)) => (),
+        }
+    }
+
+    /// Waits for the outcome of a forwarded command without blocking the session.
+    /// The outcome is acknowledged if the command carried a request id,
+    /// otherwise only failures are reported to the client.
+    fn forward<F, T, E>(
+        &self,
+        command: &'static str,
+        request_id: Option<u64>,
+        request: F,
+        ctx: &mut <Self as Actor>::Context,
+    ) where
+        F: Future<Output = Result<Result<T, E>, MailboxError>> + 'static,
+        T: Into<CommandOutcome>,
+        E: Into<ErrorResponse>,
+    {
+        ctx.spawn(request.into_actor(self).map(move |result, act, ctx| {
+            let result: Result<CommandOutcome, ErrorResponse> = match result {
+                Ok(Ok(outcome)) => Ok(outcome.into()),
+                Ok(Err(e)) => Err(e.into()),
+                Err(e) => Err(e.into()),
+            };
+            act.reply(command, request_id, result, ctx);
+        }));
+    }
+
+    /// Forwards a request reading the log or metadata of a subscription to the `DataLogger`,
+    /// once the `PubSubService` confirmed that the client may subscribe to it
+    fn forward_read<M>(
+        &self,
+        command: &'static str,
+        request_id: Option<u64>,
+        subscription_id: Uuid,
+        request: M,
+        ctx: &mut <Self as Actor>::Context,
+    ) where
+        M: Message<Result = Result<(), DataLogError>> + Send + 'static,
+        DataLogger: Handler<M>,
+    {
+        let check = self.pubsub.send(CheckReadAccess {
+            client_id: self.id,
+            subscription_id,
+        });
+        let datalog = self.datalog.clone();
+        let request = async move {
+            if let Err(e) = check.await? {
+                return Ok(Err(ErrorResponse::from(e)));
+            }
+            let result = datalog.send(request).await?;
+            Ok(result.map_err(ErrorResponse::from))
+        };
+        self.forward(command, request_id, request, ctx)
+    }
+
+    /// Sends an error to the client as `ServerMessage::Error`
+    fn send_error<E: Into<ErrorResponse>>(
+        &self,
+        error: E,
+        command: Option<&str>,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let error = error.into();
+        let msg = ServerMessage::Error {
+            code: error.code,
+            message: error.message,
+            command: command.map(String::from),
+        };
+        self.send_message(&msg, ctx)
+    }
+
+    /// Sends a message to the client in the session's encoding
+    fn send_message(&self, msg: &ServerMessage, ctx: &mut <Self as Actor>::Context) {
+        let sent = match self.encoding {
+            Encoding::Cbor => serde_cbor::to_vec(msg)
+                .map(|msg| ctx.binary(msg))
+                .map_err(|e| e.to_string()),
+            Encoding::Json => serde_json::to_string(msg)
+                .map(|msg| ctx.text(msg))
+                .map_err(|e| e.to_string()),
+        };
+        if let Err(e) = sent {
+            error!("Could not encode server message: {}", e);
+        }
+    }
+
+    /// Decodes a client request received in `encoding`. Unless the session negotiated its
+    /// encoding, further messages are sent to the client in the same encoding.
+    fn receive_request(
+        &mut self,
+        encoding: Encoding,
+        request: Result<ClientRequest, ClientError>,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        self.hb = Instant::now();
+        info!("Received {:?} message from {}", encoding, self.id);
+        if !self.negotiated {
+            self.encoding = encoding;
+        }
+        match request {
+            Ok(request) => self.handle_command(request, ctx),
+            Err(e) => {
+                error!("{}", &e);
+                self.send_error(e, None, ctx)
+            }
         }
     }
 
@@ -107,9 +730,16 @@ impl Actor for WebSocketSession {
     fn started(&mut self, ctx: &mut Self::Context) {
         info!("Starting WebSocketSession for {}", self.id);
         self.beat(ctx);
+        let offer = ServerMessage::Offer {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: SERVER_CAPABILITIES.to_vec(),
+        };
+        self.send_message(&offer, ctx);
         if let Err(e) = self.pubsub.try_send(ManageSession::Add {
             client_id: self.id,
-            addr: ctx.address(),
+            session: SessionHandle::new(&ctx.address()),
+            delivery: None,
         }) {
             error!("{}", e);
             ctx.stop()
@@ -117,24 +747,48 @@ impl Actor for WebSocketSession {
     }
 
     // Unregister with SessionService when stopping the actor
-    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
         info!("Stopping WebSocketSession for {}", self.id);
-        self.pubsub
-            .do_send(ManageSession::Remove { client_id: self.id });
+        if self.protocol.grants(Capability::PushDelivery) {
+            self.pubsub.do_send(ManageSession::Remove {
+                client_id: self.id,
+                session: SessionHandle::new(&ctx.address()),
+            });
+        }
         Running::Stop
     }
 }
 
+// Handles publications sent by the server in full
+impl Handler<Publication> for WebSocketSession {
+    type Result = Result<(), PublicationError>;
+
+    fn handle(&mut self, msg: Publication, ctx: &mut Self::Context) -> Self::Result {
+        debug!(
+            "Received publication {} for {}",
+            msg.publication_id, self.id
+        );
+        if !self.protocol.grants(Capability::PushDelivery) {
+            return Ok(());
+        }
+        let msg = ServerMessage::Publication(msg);
+        self.send_message(&msg, ctx);
+        Ok(())
+    }
+}
+
 // Handles publication messages sent by the server
 impl Handler<Issue> for WebSocketSession {
     type Result = Result<(), PublicationError>;
 
     fn handle(&mut self, msg: Issue, ctx: &mut Self::Context) -> Self::Result {
         debug!("Received {:?} for {}", msg, self.id);
+        if !self.protocol.grants(Capability::PushDelivery) {
+            return Ok(());
+        }
         let msg = ServerMessage::Issue(msg);
-        Ok(ctx.binary(
-            serde_cbor::to_vec(&msg).map_err(|e| PublicationError::Publishing(e.to_string()))?,
-        ))
+        self.send_message(&msg, ctx);
+        Ok(())
     }
 }
 
@@ -144,7 +798,8 @@ impl Handler<LogIndexPut> for WebSocketSession {
 
     fn handle(&mut self, msg: LogIndexPut, ctx: &mut Self::Context) -> Self::Result {
         let msg = ServerMessage::LogIndex(msg);
-        Ok(ctx.binary(serde_cbor::to_vec(&msg).map_err(|e| DataLogError::WriteError(e))?))
+        self.send_message(&msg, ctx);
+        Ok(())
     }
 }
 
@@ -154,7 +809,30 @@ impl Handler<DataLogPut<Publication>> for WebSocketSession {
 
     fn handle(&mut self, msg: DataLogPut<Publication>, ctx: &mut Self::Context) -> Self::Result {
         let msg = ServerMessage::LogEntry(msg.0);
-        Ok(ctx.binary(serde_cbor::to_vec(&msg).map_err(|e| DataLogError::PutDataLogEntry(e))?))
+        self.send_message(&msg, ctx);
+        Ok(())
+    }
+}
+
+// Handles pages of log entries sent by the server
+impl Handler<LogRangePut> for WebSocketSession {
+    type Result = Result<(), DataLogError>;
+
+    fn handle(&mut self, msg: LogRangePut, ctx: &mut Self::Context) -> Self::Result {
+        let msg = ServerMessage::LogRange(msg);
+        self.send_message(&msg, ctx);
+        Ok(())
+    }
+}
+
+// Handles subscription metadata sent by the server
+impl Handler<MetadataPut<Subscription>> for WebSocketSession {
+    type Result = Result<(), DataLogError>;
+
+    fn handle(&mut self, msg: MetadataPut<Subscription>, ctx: &mut Self::Context) -> Self::Result {
+        let msg = ServerMessage::SubscriptionInfo(msg.0);
+        self.send_message(&msg, ctx);
+        Ok(())
     }
 }
 
@@ -163,72 +841,15 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketSession
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         trace!("Message received: {:#?}", &msg);
         match msg {
-            Ok(ws::Message::Text(_)) => {
-                self.hb = Instant::now();
-                info!("Received Text Message from {}", self.id);
-                ctx.text(format!("Text messages not implemented"))
+            Ok(ws::Message::Text(msg)) => {
+                let request =
+                    serde_json::from_str::<ClientRequest>(&msg).map_err(ClientError::from);
+                self.receive_request(Encoding::Json, request, ctx)
             }
             Ok(ws::Message::Binary(msg)) => {
-                self.hb = Instant::now();
-                info!("Received Binary Message from {}", self.id);
-                match serde_cbor::from_slice::<ClientCommand>(&msg) {
-                    Ok(ClientCommand::GetLogEntries { log_id, entries }) => {
-                        if let Err(e) = self.datalog.try_send(DataLogPull {
-                            client: ctx.address().recipient(),
-                            data_log_id: log_id,
-                            selection: entries,
-                        }) {
-                            error!("Error while requesting DataLogEntries");
-                            ctx.binary(format!("{}", e));
-                        }
-                    }
-                    Ok(ClientCommand::GetLogIndex { log_id }) => {
-                        if let Err(e) = self.datalog.try_send(LogIndexPull {
-                            client: ctx.address().recipient(),
-                            data_log_id: log_id,
-                        }) {
-                            error!("Error while requesting DataLogIndex");
-                            ctx.binary(format!("{}", e));
-                        }
-                    }
-                    Ok(ClientCommand::SubmitPublication {
-                        subscription_id,
-                        submission,
-                    }) => {
-                        if let Err(e) = self.pubsub.try_send(SubmitCommand::new(
-                            &self.id,
-                            &subscription_id,
-                            &submission,
-                        )) {
-                            error!("Error during publication: {}", e);
-                            ctx.binary(format!("{}", e));
-                        }
-                    }
-                    Ok(ClientCommand::Subscribe { subscription_id }) => {
-                        if let Err(e) = self.pubsub.try_send(ManageSubscription::Add {
-                            client_id: self.id,
-                            subscription_id,
-                        }) {
-                            error!("Error while attempting to subscribe client to subscription");
-                            ctx.binary(format!("{}", e))
-                        }
-                    }
-                    Ok(ClientCommand::Unsubscribe { subscription_id }) => {
-                        if let Err(e) = self.pubsub.try_send(ManageSubscription::Remove {
-                            client_id: self.id,
-                            subscription_id,
-                        }) {
-                            error!(
-                                "Error while attempting to unsubscribe client from subscription"
-                            );
-                            ctx.binary(format!("{}", e))
-                        }
-                    }
-                    Err(e) => {
-                        error!("{}", &e);
-                        ctx.binary(format!("{}", &e))
-                    }
-                };
+                let request =
+                    serde_cbor::from_slice::<ClientRequest>(&msg).map_err(ClientError::from);
+                self.receive_request(Encoding::Cbor, request, ctx)
             }
             Ok(ws::Message::Ping(msg)) => {
                 self.hb = Instant::now();
@@ -250,48 +871,290 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketSession
     }
 }
 
+/// A `ClientCommand` together with an optional request id chosen by the client.
+/// If a request id is given, the server answers with a `ServerMessage::Ack` carrying it
+/// once the command was handled. A plain `ClientCommand` decodes as a request without id.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct ClientRequest {
+    #[serde(default)]
+    pub request_id: Option<u64>,
+    #[serde(flatten)]
+    pub command: ClientCommand,
+}
+
+impl ClientRequest {
+    pub fn new(request_id: u64, command: ClientCommand) -> ClientRequest {
+        ClientRequest {
+            request_id: Some(request_id),
+            command,
+        }
+    }
+}
+
 /// Represents a message from a client sent to the websocket.
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
 pub enum ClientCommand {
-    /// Retrieve a Subscriptions log index
-    GetLogIndex { log_id: Uuid },
+    /// Agree on the protocol version and capabilities of the session, answered by
+    /// `ServerMessage::Welcome`. Should be the first command of a session.
+    /// The server downgrades newer clients to its version and refuses clients that are too old.
+    Hello {
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
+    /// Retrieve a Subscriptions log index, ordered by sequence number.
+    /// If `since` is given, only publications with a greater sequence number are included.
+    GetLogIndex {
+        log_id: Uuid,
+        #[serde(default)]
+        since: Option<u64>,
+    },
     /// Fetch one or more entries from the datalog
     GetLogEntries { log_id: Uuid, entries: Vec<Uuid> },
-    /// Add client to a Subscription, creating it it if doesn't exist
-    Subscribe { subscription_id: Uuid },
+    /// Fetch a page of up to `limit` entries from the datalog, starting at `start`.
+    /// The response carries the position to request the next page from.
+    GetLogRange {
+        log_id: Uuid,
+        start: LogPosition,
+        limit: usize,
+        direction: RangeDirection,
+    },
+    /// Retrieve the persisted metadata of a Subscription
+    GetSubscriptionInfo { subscription_id: Uuid },
+    /// Add client to a Subscription. If it doesn't exist, it is created if `create` is set,
+    /// `kind` then determines how its publications are distributed.
+    /// Publications of a `durable` subscription that were not acknowledged with `Ack`
+    /// are replayed when the client reconnects.
+    Subscribe {
+        subscription_id: Uuid,
+        #[serde(default)]
+        kind: SubscriptionKind,
+        #[serde(default)]
+        durable: bool,
+        #[serde(default)]
+        create: bool,
+    },
     /// Remove client from a Subscription, deleting it, if client was last subscriber
     Unsubscribe { subscription_id: Uuid },
-    /// Submit new data for publication
+    /// Add client to the Subscription of a hierarchical topic like `sensors/building-a/temp`,
+    /// creating it if it doesn't exist and `create` is set. Topics may contain wildcards, `+` matching
+    /// a single level and `#` matching all remaining levels, to subscribe to every matching topic.
+    SubscribeTopic {
+        topic: String,
+        #[serde(default)]
+        kind: SubscriptionKind,
+        #[serde(default)]
+        durable: bool,
+        #[serde(default)]
+        create: bool,
+    },
+    /// Remove client from the Subscription of a topic or topic pattern
+    UnsubscribeTopic { topic: String },
+    /// Submit new data for publication to a topic, creating its Subscription if it doesn't exist
+    PublishTopic {
+        topic: String,
+        submission: Vec<u8>,
+        #[serde(default)]
+        key: Option<String>,
+        #[serde(default)]
+        retain: bool,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+    /// Retrieve the metadata of all topic Subscriptions matching a pattern
+    ListTopics { pattern: String },
+    /// Submit new data for publication.
+    /// Publications sharing a `key` replace each other when the log is compacted.
+    /// A publication submitted with `retain` is delivered to clients subscribing later on,
+    /// until another one is retained or it is cleared with `ClearRetained`.
+    /// `headers` are stored with the publication, e.g. its `content-type`.
     SubmitPublication {
         subscription_id: Uuid,
         submission: Vec<u8>,
+        #[serde(default)]
+        key: Option<String>,
+        #[serde(default)]
+        retain: bool,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+    /// Set whether subscribers of a Subscription receive publications in full
+    /// or as an `Issue` holding only their id. `None` leaves the choice to each session.
+    SetDeliveryMode {
+        subscription_id: Uuid,
+        mode: Option<DeliveryMode>,
+    },
+    /// Set how this session receives publications of Subscriptions without a delivery mode
+    SetSessionDeliveryMode { mode: DeliveryMode },
+    /// Replace the retention policy of a Subscription's log
+    SetRetentionPolicy {
+        subscription_id: Uuid,
+        policy: RetentionPolicy,
+    },
+    /// Restrict which clients may publish to or subscribe to a Subscription,
+    /// `None` permits every client. Only allowed for the Subscription's owner.
+    SetAccessControl {
+        subscription_id: Uuid,
+        publishers: Option<Vec<Uuid>>,
+        subscribers: Option<Vec<Uuid>>,
+    },
+    /// Discard the publication retained by a Subscription
+    ClearRetained { subscription_id: Uuid },
+    /// Acknowledge having processed a publication.
+    /// Required for publications of queue-type Subscriptions and durable subscribers.
+    Ack {
+        subscription_id: Uuid,
+        publication_id: Uuid,
     },
 }
 
+impl ClientCommand {
+    /// The name of the command variant, used to relate errors to the command causing them
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientCommand::Hello { .. } => "Hello",
+            ClientCommand::GetLogIndex { .. } => "GetLogIndex",
+            ClientCommand::GetLogEntries { .. } => "GetLogEntries",
+            ClientCommand::GetLogRange { .. } => "GetLogRange",
+            ClientCommand::GetSubscriptionInfo { .. } => "GetSubscriptionInfo",
+            ClientCommand::Subscribe { .. } => "Subscribe",
+            ClientCommand::Unsubscribe { .. } => "Unsubscribe",
+            ClientCommand::SubscribeTopic { .. } => "SubscribeTopic",
+            ClientCommand::UnsubscribeTopic { .. } => "UnsubscribeTopic",
+            ClientCommand::PublishTopic { .. } => "PublishTopic",
+            ClientCommand::ListTopics { .. } => "ListTopics",
+            ClientCommand::SubmitPublication { .. } => "SubmitPublication",
+            ClientCommand::SetDeliveryMode { .. } => "SetDeliveryMode",
+            ClientCommand::SetSessionDeliveryMode { .. } => "SetSessionDeliveryMode",
+            ClientCommand::SetRetentionPolicy { .. } => "SetRetentionPolicy",
+            ClientCommand::SetAccessControl { .. } => "SetAccessControl",
+            ClientCommand::ClearRetained { .. } => "ClearRetained",
+            ClientCommand::Ack { .. } => "Ack",
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
 
-    use std::collections::HashSet;
-    use std::convert::TryInto;
-    use std::env::temp_dir;
-    use std::path::{Path, PathBuf};
+    use std::collections::BTreeMap;
     use std::str::FromStr;
+    use std::sync::Arc;
 
     use actix_web::{test, web, App};
-    use futures_util::{sink::SinkExt, stream::StreamExt};
+    use futures_util::{
+        sink::{Sink, SinkExt},
+        stream::{Stream, StreamExt},
+    };
+
+    use crate::auth::StaticTokenAuthenticator;
+    use crate::pubsub::{QueueStrategy, RedeliveryPolicy, CONTENT_TYPE_HEADER};
 
     use crate::data_log::DataLogger;
+    use crate::test_utils::{create_test_directory, remove_test_directory};
+
+    async fn send_command<S>(conn: &mut S, cmd: &ClientCommand)
+    where
+        S: Sink<ws::Message> + Unpin,
+        S::Error: std::fmt::Debug,
+    {
+        conn.send(ws::Message::Binary(serde_cbor::to_vec(cmd).unwrap().into()))
+            .await
+            .unwrap();
+    }
+
+    async fn send_request<S>(conn: &mut S, request: &ClientRequest)
+    where
+        S: Sink<ws::Message> + Unpin,
+        S::Error: std::fmt::Debug,
+    {
+        conn.send(ws::Message::Binary(
+            serde_cbor::to_vec(request).unwrap().into(),
+        ))
+        .await
+        .unwrap();
+    }
+
+    async fn receive_message<S>(conn: &mut S) -> ServerMessage
+    where
+        S: Stream<Item = Result<ws::Frame, ws::ProtocolError>> + Unpin,
+    {
+        loop {
+            if let ws::Frame::Binary(a) = conn.next().await.unwrap().unwrap() {
+                return serde_cbor::from_slice::<ServerMessage>(&a[..]).unwrap();
+            }
+        }
+    }
+
+    /// Sends a command as request `request_id` and waits until the server acknowledges it
+    async fn confirm<S>(conn: &mut S, request_id: u64, command: ClientCommand)
+    where
+        S: Sink<ws::Message> + Stream<Item = Result<ws::Frame, ws::ProtocolError>> + Unpin,
+        <S as Sink<ws::Message>>::Error: std::fmt::Debug,
+    {
+        send_request(conn, &ClientRequest::new(request_id, command)).await;
+        match receive_message(conn).await {
+            ServerMessage::Ack {
+                request_id: id,
+                result: Ok(_),
+            } if id == request_id => (),
+            m => panic!("Expected an ack of request {}, got {:?}", request_id, m),
+        }
+    }
 
-    fn create_test_directory() -> PathBuf {
-        let mut p = temp_dir();
-        p.push(format!("infotainer-{}", Uuid::new_v4().to_hyphenated()));
-        std::fs::create_dir(&p).unwrap();
-        p
+    /// Closes a connection and waits until the server hangs up, which it does once the session stopped
+    async fn disconnect<S>(conn: &mut S)
+    where
+        S: Sink<ws::Message> + Stream<Item = Result<ws::Frame, ws::ProtocolError>> + Unpin,
+        <S as Sink<ws::Message>>::Error: std::fmt::Debug,
+    {
+        conn.close().await.unwrap();
+        while let Some(Ok(_)) = conn.next().await {}
+    }
+
+    /// Receives the offer a new session starts with
+    async fn skip_offer<S>(conn: &mut S)
+    where
+        S: Stream<Item = Result<ws::Frame, ws::ProtocolError>> + Unpin,
+    {
+        match receive_message(conn).await {
+            ServerMessage::Offer { capabilities, .. } => {
+                assert_eq!(capabilities, SERVER_CAPABILITIES)
+            }
+            m => panic!("Expected an offer, got {:?}", m),
+        }
     }
 
-    fn remove_test_directory(p: &Path) {
-        std::fs::remove_dir_all(p).unwrap();
+    /// Completes the handshake of a new session, agreeing on every capability the server offers
+    async fn handshake<S>(conn: &mut S)
+    where
+        S: Sink<ws::Message> + Stream<Item = Result<ws::Frame, ws::ProtocolError>> + Unpin,
+        <S as Sink<ws::Message>>::Error: std::fmt::Debug,
+    {
+        skip_offer(conn).await;
+        let hello = ClientCommand::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: SERVER_CAPABILITIES.to_vec(),
+        };
+        send_command(conn, &hello).await;
+        match receive_message(conn).await {
+            ServerMessage::Welcome { .. } => (),
+            m => panic!("Expected a welcome, got {:?}", m),
+        }
+    }
+
+    /// Starts a test server accepting websocket sessions at `/{session_id}`
+    fn start_server(
+        pubsub_server: Addr<PubSubService>,
+        data_log: Addr<DataLogger>,
+    ) -> test::TestServer {
+        test::start(move || {
+            App::new()
+                .data(pubsub_server.clone())
+                .data(data_log.clone())
+                .route("/{session_id}", web::get().to(websocket_handler))
+        })
     }
 
     #[actix_rt::test]
@@ -302,42 +1165,41 @@ pub mod tests {
         let session_id = Uuid::new_v4();
         let subscription_id = Uuid::new_v4();
         let test_data_text = "Milton Beats <Giver of Beatings>";
-        let mut srv = test::start(move || {
-            App::new()
-                .data(pubsub_server.clone())
-                .data(data_log.clone())
-                .route("/{session_id}", web::get().to(websocket_handler))
-        });
+        let mut srv = start_server(pubsub_server, data_log);
         let mut conn = srv
             .ws_at(&format!("/{}", session_id))
             .await
             .expect("Could not start ws connection");
+        handshake(&mut conn).await;
         assert!(&conn.is_write_ready());
         let sub_message = ClientCommand::Subscribe {
-            subscription_id: subscription_id,
-        };
-        &conn
-            .send(ws::Message::Binary(
-                serde_cbor::to_vec(&sub_message)
-                    .unwrap()
-                    .try_into()
-                    .unwrap(),
-            ))
-            .await
-            .unwrap();
+            subscription_id,
+            kind: SubscriptionKind::Broadcast,
+            durable: false,
+            create: true,
+        };
+        conn.send(ws::Message::Binary(
+            serde_cbor::to_vec(&sub_message).unwrap().into(),
+        ))
+        .await
+        .unwrap();
         let pub_message = ClientCommand::SubmitPublication {
-            subscription_id: subscription_id,
+            subscription_id,
             submission: test_data_text.into(),
+            key: None,
+            retain: false,
+            headers: vec![(
+                String::from(CONTENT_TYPE_HEADER),
+                String::from("text/plain"),
+            )]
+            .into_iter()
+            .collect(),
         };
-        &conn
-            .send(ws::Message::Binary(
-                serde_cbor::to_vec(&pub_message)
-                    .unwrap()
-                    .try_into()
-                    .unwrap(),
-            ))
-            .await
-            .unwrap();
+        conn.send(ws::Message::Binary(
+            serde_cbor::to_vec(&pub_message).unwrap().into(),
+        ))
+        .await
+        .unwrap();
         let issue_server_message = match conn.next().await.unwrap().unwrap() {
             ws::Frame::Binary(a) => serde_cbor::from_slice::<ServerMessage>(&a[..]).unwrap(),
             _ => panic!("Could not parse response"),
@@ -349,43 +1211,51 @@ pub mod tests {
             }
             _ => panic!("Received unexpected response: {:?}", issue_server_message),
         };
-        let log_message = ClientCommand::GetLogIndex {
-            log_id: subscription_id,
-        };
-        &conn
-            .send(ws::Message::Binary(
-                serde_cbor::to_vec(&log_message)
-                    .unwrap()
-                    .try_into()
-                    .unwrap(),
-            ))
-            .await
-            .unwrap();
-        let mut log_response = HashSet::new();
+        let info_message = ClientCommand::GetSubscriptionInfo { subscription_id };
+        conn.send(ws::Message::Binary(
+            serde_cbor::to_vec(&info_message).unwrap().into(),
+        ))
+        .await
+        .unwrap();
         match conn.next().await.unwrap().unwrap() {
             ws::Frame::Binary(a) => {
                 match serde_cbor::from_slice::<ServerMessage>(&a[..]).unwrap() {
-                    ServerMessage::LogIndex(i) => log_response = i.1,
-                    _ => panic!("Received invalid response from server"),
+                    ServerMessage::SubscriptionInfo(i) => {
+                        assert_eq!(i.len(), 1);
+                        assert_eq!(i[0].id, subscription_id);
+                        assert_eq!(i[0].subscribers, vec![session_id]);
+                    }
+                    m => panic!("Received unexpected response: {:?}", m),
                 }
             }
-            _ => (),
+            _ => panic!("Received invalid server response"),
         };
-        assert!(!&log_response.is_empty());
-        assert!(&log_response.contains(&published_issue.1));
+        let log_message = ClientCommand::GetLogIndex {
+            log_id: subscription_id,
+            since: None,
+        };
+        conn.send(ws::Message::Binary(
+            serde_cbor::to_vec(&log_message).unwrap().into(),
+        ))
+        .await
+        .unwrap();
+        let mut log_response = BTreeMap::new();
+        if let ws::Frame::Binary(a) = conn.next().await.unwrap().unwrap() {
+            match serde_cbor::from_slice::<ServerMessage>(&a[..]).unwrap() {
+                ServerMessage::LogIndex(i) => log_response = i.1,
+                _ => panic!("Received invalid response from server"),
+            }
+        };
+        assert_eq!(log_response.get(&1), Some(&published_issue.1));
         let entry_message = ClientCommand::GetLogEntries {
             log_id: subscription_id,
-            entries: log_response.drain().collect(),
-        };
-        &conn
-            .send(ws::Message::Binary(
-                serde_cbor::to_vec(&entry_message)
-                    .unwrap()
-                    .try_into()
-                    .unwrap(),
-            ))
-            .await
-            .unwrap();
+            entries: log_response.values().copied().collect(),
+        };
+        conn.send(ws::Message::Binary(
+            serde_cbor::to_vec(&entry_message).unwrap().into(),
+        ))
+        .await
+        .unwrap();
         let entry_response = match conn.next().await.unwrap().unwrap() {
             ws::Frame::Binary(a) => serde_cbor::from_slice::<ServerMessage>(&a[..]).unwrap(),
             _ => panic!("Received invalid server response"),
@@ -394,28 +1264,1056 @@ pub mod tests {
             ServerMessage::LogEntry(e) => e[0].clone(),
             _ => panic!("Unexpected server message"),
         };
+        assert_eq!(data_log_entry.sequence, 1);
+        assert_eq!(data_log_entry.publisher, Some(session_id));
+        assert!(data_log_entry.timestamp > 0);
+        assert_eq!(
+            data_log_entry
+                .headers
+                .get(CONTENT_TYPE_HEADER)
+                .map(String::as_str),
+            Some("text/plain")
+        );
+        let range_message = ClientCommand::GetLogRange {
+            log_id: subscription_id,
+            start: LogPosition::Sequence(0),
+            limit: 10,
+            direction: RangeDirection::Forward,
+        };
+        conn.send(ws::Message::Binary(
+            serde_cbor::to_vec(&range_message).unwrap().into(),
+        ))
+        .await
+        .unwrap();
+        match conn.next().await.unwrap().unwrap() {
+            ws::Frame::Binary(a) => {
+                match serde_cbor::from_slice::<ServerMessage>(&a[..]).unwrap() {
+                    ServerMessage::LogRange(r) => {
+                        assert_eq!(r.entries, vec![data_log_entry.clone()]);
+                        assert_eq!(r.next, None);
+                    }
+                    m => panic!("Received unexpected response: {:?}", m),
+                }
+            }
+            _ => panic!("Received invalid server response"),
+        };
         assert_eq!(
             String::from_utf8(data_log_entry.data).unwrap(),
             test_data_text
         );
-        let unsub_message = ClientCommand::Unsubscribe {
-            subscription_id: subscription_id,
-        };
-        &conn
-            .send(ws::Message::Binary(
-                serde_cbor::to_vec(&unsub_message)
-                    .unwrap()
-                    .try_into()
-                    .unwrap(),
-            ))
+        let unsub_message = ClientRequest::new(7, ClientCommand::Unsubscribe { subscription_id });
+        conn.send(ws::Message::Binary(
+            serde_cbor::to_vec(&unsub_message).unwrap().into(),
+        ))
+        .await
+        .unwrap();
+        match receive_message(&mut conn).await {
+            ServerMessage::Ack { request_id, result } => {
+                assert_eq!(request_id, 7);
+                assert_eq!(result, Ok(CommandOutcome::Done));
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        remove_test_directory(&test_dir);
+    }
+
+    #[actix_rt::test]
+    async fn test_queue_subscription() {
+        let test_dir = create_test_directory();
+        let data_log = DataLogger::new(&test_dir).unwrap().start();
+        let pubsub_server = PubSubService::new(&data_log).start();
+        let subscription_id = Uuid::new_v4();
+        let mut srv = start_server(pubsub_server, data_log);
+        let mut first = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        handshake(&mut first).await;
+        let mut second = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        handshake(&mut second).await;
+        let sub_message = ClientCommand::Subscribe {
+            subscription_id,
+            kind: SubscriptionKind::Queue(QueueStrategy::RoundRobin),
+            durable: false,
+            create: true,
+        };
+        confirm(&mut first, 1, sub_message.clone()).await;
+        confirm(&mut second, 1, sub_message).await;
+        for submission in &["first job", "second job"] {
+            send_command(
+                &mut first,
+                &ClientCommand::SubmitPublication {
+                    subscription_id,
+                    submission: Vec::from(*submission),
+                    key: None,
+                    retain: false,
+                    headers: HashMap::new(),
+                },
+            )
+            .await;
+        }
+        let first_issue = match receive_message(&mut first).await {
+            ServerMessage::Issue(i) => i,
+            m => panic!("Received unexpected response: {:?}", m),
+        };
+        let second_issue = match receive_message(&mut second).await {
+            ServerMessage::Issue(i) => i,
+            m => panic!("Received unexpected response: {:?}", m),
+        };
+        assert_ne!(first_issue.1, second_issue.1);
+        send_command(
+            &mut second,
+            &ClientCommand::Ack {
+                subscription_id,
+                publication_id: second_issue.1,
+            },
+        )
+        .await;
+        first.close().await.unwrap();
+        match receive_message(&mut second).await {
+            ServerMessage::Issue(i) => assert_eq!(i.1, first_issue.1),
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        remove_test_directory(&test_dir);
+    }
+
+    #[actix_rt::test]
+    async fn test_full_delivery() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub_server = PubSubService::new(&data_log).start();
+        let subscription_id = Uuid::new_v4();
+        let mut srv = start_server(pubsub_server, data_log);
+        let mut conn = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        handshake(&mut conn).await;
+        let setup = [
+            ClientCommand::SetSessionDeliveryMode {
+                mode: DeliveryMode::Full,
+            },
+            ClientCommand::Subscribe {
+                subscription_id,
+                kind: SubscriptionKind::Broadcast,
+                durable: false,
+                create: true,
+            },
+        ];
+        for (id, command) in (1..).zip(setup) {
+            confirm(&mut conn, id, command).await;
+        }
+        let submit = |submission: &str| ClientCommand::SubmitPublication {
+            subscription_id,
+            submission: Vec::from(submission),
+            key: None,
+            retain: false,
+            headers: HashMap::new(),
+        };
+        send_command(&mut conn, &submit("full")).await;
+        match receive_message(&mut conn).await {
+            ServerMessage::Publication(p) => {
+                assert_eq!(p.subscription_id, subscription_id);
+                assert_eq!(p.data, b"full");
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        let notify = ClientCommand::SetDeliveryMode {
+            subscription_id,
+            mode: Some(DeliveryMode::Notify),
+        };
+        confirm(&mut conn, 3, notify).await;
+        send_command(&mut conn, &submit("large payload")).await;
+        match receive_message(&mut conn).await {
+            ServerMessage::Issue(i) => assert_eq!(i.0, subscription_id),
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_error_responses() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub_server = PubSubService::new(&data_log).start();
+        let mut srv = start_server(pubsub_server, data_log);
+        let mut conn = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        handshake(&mut conn).await;
+        conn.send(ws::Message::Binary(b"garbage".to_vec().into()))
             .await
             .unwrap();
-        let unsub_response = match conn.next().await.unwrap().unwrap() {
-            ws::Frame::Binary(a) => Some(serde_cbor::from_slice::<String>(&a[..]).unwrap()),
-            _ => None,
+        match receive_message(&mut conn).await {
+            ServerMessage::Error { code, command, .. } => {
+                assert_eq!(code, ErrorCode::InvalidInput);
+                assert_eq!(command, None);
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        let subscription_id = Uuid::new_v4();
+        send_command(
+            &mut conn,
+            &ClientCommand::SubmitPublication {
+                subscription_id,
+                submission: Vec::from("nobody listens"),
+                key: None,
+                retain: false,
+                headers: HashMap::new(),
+            },
+        )
+        .await;
+        match receive_message(&mut conn).await {
+            ServerMessage::Error { code, command, .. } => {
+                assert_eq!(code, ErrorCode::SubscriptionNotFound);
+                assert_eq!(command.as_deref(), Some("SubmitPublication"));
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        let subscribe = ClientCommand::Subscribe {
+            subscription_id,
+            kind: SubscriptionKind::Broadcast,
+            durable: false,
+            create: true,
         };
-        assert_eq!(unsub_response, None);
-        remove_test_directory(&test_dir);
+        send_request(&mut conn, &ClientRequest::new(1, subscribe)).await;
+        match receive_message(&mut conn).await {
+            ServerMessage::Ack { request_id: 1, .. } => (),
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        send_command(
+            &mut conn,
+            &ClientCommand::GetLogEntries {
+                log_id: subscription_id,
+                entries: vec![Uuid::new_v4()],
+            },
+        )
+        .await;
+        match receive_message(&mut conn).await {
+            ServerMessage::Error { code, command, .. } => {
+                assert_eq!(code, ErrorCode::PublicationNotFound);
+                assert_eq!(command.as_deref(), Some("GetLogEntries"));
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        send_command(
+            &mut conn,
+            &ClientCommand::GetSubscriptionInfo {
+                subscription_id: Uuid::new_v4(),
+            },
+        )
+        .await;
+        match receive_message(&mut conn).await {
+            ServerMessage::Error { code, command, .. } => {
+                assert_eq!(code, ErrorCode::SubscriptionNotFound);
+                assert_eq!(command.as_deref(), Some("GetSubscriptionInfo"));
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_reading_requires_subscriber_access() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub_server = PubSubService::new(&data_log).start();
+        let mut srv = start_server(pubsub_server, data_log);
+        let subscription_id = Uuid::new_v4();
+        let mut owner = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        handshake(&mut owner).await;
+        let setup = vec![
+            ClientCommand::Subscribe {
+                subscription_id,
+                kind: SubscriptionKind::Broadcast,
+                durable: false,
+                create: true,
+            },
+            ClientCommand::SetAccessControl {
+                subscription_id,
+                publishers: None,
+                subscribers: Some(Vec::new()),
+            },
+        ];
+        for (id, command) in (1..).zip(setup) {
+            send_request(&mut owner, &ClientRequest::new(id, command)).await;
+            match receive_message(&mut owner).await {
+                ServerMessage::Ack {
+                    request_id,
+                    result: Ok(_),
+                } if request_id == id => (),
+                m => panic!("Received unexpected response: {:?}", m),
+            }
+        }
+        let get_range = ClientCommand::GetLogRange {
+            log_id: subscription_id,
+            start: LogPosition::Sequence(0),
+            limit: 10,
+            direction: RangeDirection::Forward,
+        };
+        let mut stranger = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        handshake(&mut stranger).await;
+        send_command(&mut stranger, &get_range).await;
+        match receive_message(&mut stranger).await {
+            ServerMessage::Error { code, command, .. } => {
+                assert_eq!(code, ErrorCode::AccessDenied);
+                assert_eq!(command.as_deref(), Some("GetLogRange"));
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        send_command(
+            &mut stranger,
+            &ClientCommand::GetSubscriptionInfo { subscription_id },
+        )
+        .await;
+        match receive_message(&mut stranger).await {
+            ServerMessage::Error { code, .. } => assert_eq!(code, ErrorCode::AccessDenied),
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        send_command(&mut owner, &get_range).await;
+        match receive_message(&mut owner).await {
+            ServerMessage::LogRange(range) => assert!(range.entries.is_empty()),
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_acknowledgements() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub_server = PubSubService::new(&data_log).start();
+        let mut srv = start_server(pubsub_server, data_log);
+        let mut conn = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        handshake(&mut conn).await;
+        let subscription_id = Uuid::new_v4();
+        send_request(
+            &mut conn,
+            &ClientRequest::new(
+                1,
+                ClientCommand::Subscribe {
+                    subscription_id,
+                    kind: SubscriptionKind::Broadcast,
+                    durable: false,
+                    create: true,
+                },
+            ),
+        )
+        .await;
+        match receive_message(&mut conn).await {
+            ServerMessage::Ack { request_id, result } => {
+                assert_eq!(request_id, 1);
+                assert_eq!(result, Ok(CommandOutcome::Done));
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        send_request(
+            &mut conn,
+            &ClientRequest::new(
+                2,
+                ClientCommand::SubmitPublication {
+                    subscription_id,
+                    submission: Vec::from("acknowledged"),
+                    key: None,
+                    retain: false,
+                    headers: HashMap::new(),
+                },
+            ),
+        )
+        .await;
+        let mut issued = None;
+        let mut acknowledged = None;
+        while issued.is_none() || acknowledged.is_none() {
+            match receive_message(&mut conn).await {
+                ServerMessage::Issue(i) => issued = Some(i.1),
+                ServerMessage::Ack { request_id, result } => {
+                    assert_eq!(request_id, 2);
+                    acknowledged = Some(result)
+                }
+                m => panic!("Received unexpected response: {:?}", m),
+            }
+        }
+        assert_eq!(
+            acknowledged,
+            Some(Ok(CommandOutcome::Published {
+                publication_id: issued.unwrap()
+            }))
+        );
+        send_request(
+            &mut conn,
+            &ClientRequest::new(
+                3,
+                ClientCommand::SubmitPublication {
+                    subscription_id: Uuid::new_v4(),
+                    submission: Vec::from("nobody listens"),
+                    key: None,
+                    retain: false,
+                    headers: HashMap::new(),
+                },
+            ),
+        )
+        .await;
+        match receive_message(&mut conn).await {
+            ServerMessage::Ack {
+                request_id,
+                result: Err(error),
+            } => {
+                assert_eq!(request_id, 3);
+                assert_eq!(error.code, ErrorCode::SubscriptionNotFound);
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_durable_subscription() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub_server = PubSubService::new(&data_log).start();
+        let (subscriber_id, subscription_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut srv = start_server(pubsub_server, data_log);
+        let mut publisher = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        handshake(&mut publisher).await;
+        let mut subscriber = srv.ws_at(&format!("/{}", subscriber_id)).await.unwrap();
+        handshake(&mut subscriber).await;
+        send_request(
+            &mut subscriber,
+            &ClientRequest::new(
+                1,
+                ClientCommand::Subscribe {
+                    subscription_id,
+                    kind: SubscriptionKind::Broadcast,
+                    durable: true,
+                    create: true,
+                },
+            ),
+        )
+        .await;
+        receive_message(&mut subscriber).await;
+        let mut published = Vec::new();
+        for (request_id, submission) in (1..).zip(&["first", "second", "third"]) {
+            if request_id == 2 {
+                disconnect(&mut subscriber).await;
+            }
+            let submit = ClientCommand::SubmitPublication {
+                subscription_id,
+                submission: Vec::from(*submission),
+                key: None,
+                retain: false,
+                headers: HashMap::new(),
+            };
+            send_request(&mut publisher, &ClientRequest::new(request_id, submit)).await;
+            match receive_message(&mut publisher).await {
+                ServerMessage::Ack {
+                    result: Ok(CommandOutcome::Published { publication_id }),
+                    ..
+                } => published.push(publication_id),
+                m => panic!("Received unexpected response: {:?}", m),
+            }
+            if request_id == 1 {
+                match receive_message(&mut subscriber).await {
+                    ServerMessage::Issue(i) => assert_eq!(i.1, published[0]),
+                    m => panic!("Received unexpected response: {:?}", m),
+                }
+                let ack = ClientCommand::Ack {
+                    subscription_id,
+                    publication_id: published[0],
+                };
+                confirm(&mut subscriber, 2, ack).await;
+            }
+        }
+        let mut subscriber = srv.ws_at(&format!("/{}", subscriber_id)).await.unwrap();
+        // Reconnecting without a handshake, missed publications are replayed right away
+        skip_offer(&mut subscriber).await;
+        for publication_id in &published[1..] {
+            match receive_message(&mut subscriber).await {
+                ServerMessage::Issue(i) => assert_eq!(&i.1, publication_id),
+                m => panic!("Received unexpected response: {:?}", m),
+            }
+        }
+        let ack = ClientCommand::Ack {
+            subscription_id,
+            publication_id: published[1],
+        };
+        confirm(&mut subscriber, 1, ack).await;
+        disconnect(&mut subscriber).await;
+        let mut subscriber = srv.ws_at(&format!("/{}", subscriber_id)).await.unwrap();
+        skip_offer(&mut subscriber).await;
+        match receive_message(&mut subscriber).await {
+            ServerMessage::Issue(i) => assert_eq!(i.1, published[2]),
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_redelivery() {
+        let data_log = DataLogger::ephemeral().start();
+        let (subscription_id, dead_letter_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let operator_id = Uuid::new_v4();
+        let pubsub_server = PubSubService::new(&data_log)
+            .with_redelivery(RedeliveryPolicy {
+                timeout: Duration::from_millis(200),
+                max_attempts: 2,
+            })
+            .start();
+        let mut srv = start_server(pubsub_server.clone(), data_log);
+        let mut operator = srv.ws_at(&format!("/{}", operator_id)).await.unwrap();
+        handshake(&mut operator).await;
+        let mut consumer = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        handshake(&mut consumer).await;
+        let subscriptions = [
+            (&mut operator, dead_letter_id, SubscriptionKind::Broadcast),
+            (
+                &mut consumer,
+                subscription_id,
+                SubscriptionKind::Queue(QueueStrategy::RoundRobin),
+            ),
+        ];
+        for (conn, subscription_id, kind) in subscriptions {
+            let subscribe = ClientCommand::Subscribe {
+                subscription_id,
+                kind,
+                durable: false,
+                create: true,
+            };
+            send_request(conn, &ClientRequest::new(1, subscribe)).await;
+            receive_message(conn).await;
+        }
+        pubsub_server
+            .send(ManageSubscription::SetDeadLetter {
+                client_id: operator_id,
+                subscription_id: Some(dead_letter_id),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        for submission in &["acknowledged", "ignored"] {
+            let submit = ClientCommand::SubmitPublication {
+                subscription_id,
+                submission: Vec::from(*submission),
+                key: None,
+                retain: false,
+                headers: HashMap::new(),
+            };
+            send_command(&mut operator, &submit).await;
+        }
+        let acknowledged = match receive_message(&mut consumer).await {
+            ServerMessage::Issue(i) => i.1,
+            m => panic!("Received unexpected response: {:?}", m),
+        };
+        let ack = ClientCommand::Ack {
+            subscription_id,
+            publication_id: acknowledged,
+        };
+        send_command(&mut consumer, &ack).await;
+        let ignored = match receive_message(&mut consumer).await {
+            ServerMessage::Issue(i) => i.1,
+            m => panic!("Received unexpected response: {:?}", m),
+        };
+        match receive_message(&mut consumer).await {
+            ServerMessage::Issue(i) => assert_eq!(i.1, ignored),
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        let dead_letter = match receive_message(&mut operator).await {
+            ServerMessage::Issue(i) => {
+                assert_eq!(i.0, dead_letter_id);
+                i.1
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        };
+        let entries = ClientCommand::GetLogEntries {
+            log_id: dead_letter_id,
+            entries: vec![dead_letter],
+        };
+        send_command(&mut operator, &entries).await;
+        match receive_message(&mut operator).await {
+            ServerMessage::LogEntry(e) => {
+                let publication: Publication = serde_cbor::from_slice(&e[0].data).unwrap();
+                assert_eq!(publication.publication_id, ignored);
+                assert_eq!(publication.data, b"ignored");
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_topics() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub_server = PubSubService::new(&data_log).start();
+        let mut srv = start_server(pubsub_server, data_log);
+        let mut pattern_subscriber = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        handshake(&mut pattern_subscriber).await;
+        let mut topic_subscriber = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        handshake(&mut topic_subscriber).await;
+        let mut publisher = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        handshake(&mut publisher).await;
+        let subscriptions = [
+            (&mut pattern_subscriber, "sensors/+/temp"),
+            (&mut topic_subscriber, "sensors/building-a/temp"),
+        ];
+        for (conn, topic) in subscriptions {
+            let subscribe = ClientCommand::SubscribeTopic {
+                topic: String::from(topic),
+                kind: SubscriptionKind::Broadcast,
+                durable: false,
+                create: true,
+            };
+            send_request(conn, &ClientRequest::new(1, subscribe)).await;
+            match receive_message(conn).await {
+                ServerMessage::Ack { result, .. } => assert_eq!(result, Ok(CommandOutcome::Done)),
+                m => panic!("Received unexpected response: {:?}", m),
+            }
+        }
+        let topics = [
+            "sensors/building-a/temp",
+            "sensors/building-a/humidity",
+            "sensors/building-b/temp",
+        ];
+        let mut published = Vec::new();
+        for (request_id, topic) in (1..).zip(&topics) {
+            let publish = ClientCommand::PublishTopic {
+                topic: String::from(*topic),
+                submission: Vec::from("21.5"),
+                key: None,
+                retain: false,
+                headers: HashMap::new(),
+            };
+            send_request(&mut publisher, &ClientRequest::new(request_id, publish)).await;
+            match receive_message(&mut publisher).await {
+                ServerMessage::Ack {
+                    result: Ok(CommandOutcome::Published { publication_id }),
+                    ..
+                } => published.push(publication_id),
+                m => panic!("Received unexpected response: {:?}", m),
+            }
+        }
+        match receive_message(&mut topic_subscriber).await {
+            ServerMessage::Issue(i) => {
+                assert_eq!(i.0, topic_id(topics[0]));
+                assert_eq!(i.1, published[0]);
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        for (topic, publication_id) in [(topics[0], published[0]), (topics[2], published[2])] {
+            match receive_message(&mut pattern_subscriber).await {
+                ServerMessage::Issue(i) => {
+                    assert_eq!(i.0, topic_id(topic));
+                    assert_eq!(i.1, publication_id);
+                }
+                m => panic!("Received unexpected response: {:?}", m),
+            }
+        }
+        let publish = ClientCommand::PublishTopic {
+            topic: String::from("sensors/+/temp"),
+            submission: Vec::from("21.5"),
+            key: None,
+            retain: false,
+            headers: HashMap::new(),
+        };
+        send_request(&mut publisher, &ClientRequest::new(4, publish)).await;
+        match receive_message(&mut publisher).await {
+            ServerMessage::Ack {
+                result: Err(error), ..
+            } => assert_eq!(error.code, ErrorCode::InvalidInput),
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        let list = ClientCommand::ListTopics {
+            pattern: String::from("sensors/#"),
+        };
+        send_command(&mut publisher, &list).await;
+        match receive_message(&mut publisher).await {
+            ServerMessage::SubscriptionInfo(subscriptions) => {
+                let mut listed: Vec<String> =
+                    subscriptions.into_iter().filter_map(|s| s.topic).collect();
+                listed.sort();
+                let mut expected: Vec<String> = topics.iter().map(|t| String::from(*t)).collect();
+                expected.sort();
+                assert_eq!(listed, expected);
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_retained_publications() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub_server = PubSubService::new(&data_log).start();
+        let mut srv = start_server(pubsub_server, data_log);
+        let mut publisher = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        handshake(&mut publisher).await;
+        let publish = ClientCommand::PublishTopic {
+            topic: String::from("status/door"),
+            submission: Vec::from("open"),
+            key: None,
+            retain: true,
+            headers: HashMap::new(),
+        };
+        send_request(&mut publisher, &ClientRequest::new(1, publish)).await;
+        let retained_id = match receive_message(&mut publisher).await {
+            ServerMessage::Ack {
+                result: Ok(CommandOutcome::Published { publication_id }),
+                ..
+            } => publication_id,
+            m => panic!("Received unexpected response: {:?}", m),
+        };
+        for topic in ["status/door", "status/#"] {
+            let mut subscriber = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+            handshake(&mut subscriber).await;
+            let subscribe = ClientCommand::SubscribeTopic {
+                topic: String::from(topic),
+                kind: SubscriptionKind::Broadcast,
+                durable: false,
+                create: true,
+            };
+            send_request(&mut subscriber, &ClientRequest::new(1, subscribe)).await;
+            let (mut acknowledged, mut issued) = (false, false);
+            for _ in 0..2 {
+                match receive_message(&mut subscriber).await {
+                    ServerMessage::Ack { result, .. } => {
+                        assert_eq!(result, Ok(CommandOutcome::Done));
+                        acknowledged = true;
+                    }
+                    ServerMessage::Issue(i) => {
+                        assert_eq!(i.0, topic_id("status/door"));
+                        assert_eq!(i.1, retained_id);
+                        issued = true;
+                    }
+                    m => panic!("Received unexpected response: {:?}", m),
+                }
+            }
+            assert!(acknowledged && issued);
+        }
+        let clear = ClientCommand::ClearRetained {
+            subscription_id: topic_id("status/door"),
+        };
+        send_request(&mut publisher, &ClientRequest::new(2, clear)).await;
+        match receive_message(&mut publisher).await {
+            ServerMessage::Ack { result, .. } => assert_eq!(result, Ok(CommandOutcome::Done)),
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        let mut late_subscriber = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        handshake(&mut late_subscriber).await;
+        let subscribe = ClientCommand::SubscribeTopic {
+            topic: String::from("status/door"),
+            kind: SubscriptionKind::Broadcast,
+            durable: false,
+            create: true,
+        };
+        send_request(&mut late_subscriber, &ClientRequest::new(1, subscribe)).await;
+        match receive_message(&mut late_subscriber).await {
+            ServerMessage::Ack { result, .. } => assert_eq!(result, Ok(CommandOutcome::Done)),
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_json_encoding() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub_server = PubSubService::new(&data_log).start();
+        let mut srv = start_server(pubsub_server, data_log);
+        let subscription_id = Uuid::new_v4();
+        let mut conn = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        // The offer precedes the client's first message, so it is encoded as CBOR
+        match receive_message(&mut conn).await {
+            ServerMessage::Offer { .. } => (),
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        let hello = format!(
+            r#"{{"Hello": {{"version": {}, "capabilities": ["PushDelivery", "Acks"]}}}}"#,
+            PROTOCOL_VERSION
+        );
+        conn.send(ws::Message::Text(hello)).await.unwrap();
+        match conn.next().await.unwrap().unwrap() {
+            ws::Frame::Text(a) => match serde_json::from_slice::<ServerMessage>(&a[..]).unwrap() {
+                ServerMessage::Welcome { capabilities, .. } => {
+                    assert_eq!(capabilities, SERVER_CAPABILITIES)
+                }
+                m => panic!("Received unexpected response: {:?}", m),
+            },
+            f => panic!("Received unexpected frame: {:?}", f),
+        }
+        let subscribe = format!(
+            r#"{{"request_id": 1, "Subscribe": {{"subscription_id": "{}", "create": true}}}}"#,
+            subscription_id
+        );
+        conn.send(ws::Message::Text(subscribe)).await.unwrap();
+        match conn.next().await.unwrap().unwrap() {
+            ws::Frame::Text(a) => match serde_json::from_slice::<ServerMessage>(&a[..]).unwrap() {
+                ServerMessage::Ack { request_id, result } => {
+                    assert_eq!(request_id, 1);
+                    assert_eq!(result, Ok(CommandOutcome::Done));
+                }
+                m => panic!("Received unexpected response: {:?}", m),
+            },
+            f => panic!("Received unexpected frame: {:?}", f),
+        }
+        conn.send(ws::Message::Text(String::from("{\"Subscribe\": 42}")))
+            .await
+            .unwrap();
+        match conn.next().await.unwrap().unwrap() {
+            ws::Frame::Text(a) => match serde_json::from_slice::<ServerMessage>(&a[..]).unwrap() {
+                ServerMessage::Error { code, .. } => assert_eq!(code, ErrorCode::InvalidInput),
+                m => panic!("Received unexpected response: {:?}", m),
+            },
+            f => panic!("Received unexpected frame: {:?}", f),
+        }
+        // Replies follow the encoding of the client's last message
+        let unsubscribe = ClientRequest::new(2, ClientCommand::Unsubscribe { subscription_id });
+        send_request(&mut conn, &unsubscribe).await;
+        match receive_message(&mut conn).await {
+            ServerMessage::Ack { request_id, .. } => assert_eq!(request_id, 2),
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        let (response, mut conn) = awc::Client::new()
+            .ws(srv.url(&format!("/{}", Uuid::new_v4())))
+            .protocols(["chat", JSON_SUBPROTOCOL])
+            .connect()
+            .await
+            .unwrap();
+        assert_eq!(
+            response
+                .headers()
+                .get(header::SEC_WEBSOCKET_PROTOCOL)
+                .unwrap(),
+            JSON_SUBPROTOCOL
+        );
+        match conn.next().await.unwrap().unwrap() {
+            ws::Frame::Text(a) => match serde_json::from_slice::<ServerMessage>(&a[..]).unwrap() {
+                ServerMessage::Offer { .. } => (),
+                m => panic!("Received unexpected response: {:?}", m),
+            },
+            f => panic!("Received unexpected frame: {:?}", f),
+        }
+        // A negotiated encoding is kept regardless of the client's messages
+        let hello = ClientCommand::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: SERVER_CAPABILITIES.to_vec(),
+        };
+        send_command(&mut conn, &hello).await;
+        match conn.next().await.unwrap().unwrap() {
+            ws::Frame::Text(a) => match serde_json::from_slice::<ServerMessage>(&a[..]).unwrap() {
+                ServerMessage::Welcome { .. } => (),
+                m => panic!("Received unexpected response: {:?}", m),
+            },
+            f => panic!("Received unexpected frame: {:?}", f),
+        }
+        let subscribe = ClientRequest::new(
+            3,
+            ClientCommand::Subscribe {
+                subscription_id,
+                kind: SubscriptionKind::Broadcast,
+                durable: false,
+                create: true,
+            },
+        );
+        send_request(&mut conn, &subscribe).await;
+        match conn.next().await.unwrap().unwrap() {
+            ws::Frame::Text(a) => match serde_json::from_slice::<ServerMessage>(&a[..]).unwrap() {
+                ServerMessage::Ack { request_id, .. } => assert_eq!(request_id, 3),
+                m => panic!("Received unexpected response: {:?}", m),
+            },
+            f => panic!("Received unexpected frame: {:?}", f),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_handshake() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub_server = PubSubService::new(&data_log).start();
+        let mut srv = start_server(pubsub_server, data_log);
+        let mut conn = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        match receive_message(&mut conn).await {
+            ServerMessage::Offer {
+                min_version,
+                max_version,
+                capabilities,
+            } => {
+                assert_eq!(min_version, MIN_PROTOCOL_VERSION);
+                assert_eq!(max_version, PROTOCOL_VERSION);
+                assert_eq!(capabilities, SERVER_CAPABILITIES);
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        let hello = ClientCommand::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::Acks, Capability::Compression],
+        };
+        send_request(&mut conn, &ClientRequest::new(1, hello.clone())).await;
+        match receive_message(&mut conn).await {
+            ServerMessage::Welcome {
+                version,
+                capabilities,
+            } => {
+                assert_eq!(version, PROTOCOL_VERSION);
+                assert_eq!(capabilities, vec![Capability::Acks]);
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        match receive_message(&mut conn).await {
+            ServerMessage::Ack { request_id, result } => {
+                assert_eq!(request_id, 1);
+                assert_eq!(result, Ok(CommandOutcome::Done));
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        send_request(&mut conn, &ClientRequest::new(2, hello)).await;
+        match receive_message(&mut conn).await {
+            ServerMessage::Ack {
+                result: Err(error), ..
+            } => assert_eq!(error.code, ErrorCode::InvalidInput),
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        // Publications are not pushed to sessions without push delivery
+        let subscription_id = Uuid::new_v4();
+        let commands = [
+            ClientCommand::Subscribe {
+                subscription_id,
+                kind: SubscriptionKind::Broadcast,
+                durable: false,
+                create: true,
+            },
+            ClientCommand::SubmitPublication {
+                subscription_id,
+                submission: Vec::from("Test"),
+                key: None,
+                retain: false,
+                headers: HashMap::new(),
+            },
+        ];
+        let index = ClientCommand::GetLogIndex {
+            log_id: subscription_id,
+            since: None,
+        };
+        // The index of a log without publications is empty
+        for (request_id, (command, indexed)) in (3..).zip(commands.iter().zip(0..)) {
+            send_request(&mut conn, &ClientRequest::new(request_id, command.clone())).await;
+            match receive_message(&mut conn).await {
+                ServerMessage::Ack { request_id: r, .. } => assert_eq!(r, request_id),
+                m => panic!("Received unexpected response: {:?}", m),
+            }
+            send_command(&mut conn, &index).await;
+            match receive_message(&mut conn).await {
+                ServerMessage::LogIndex(i) => assert_eq!(i.1.len(), indexed),
+                m => panic!("Received unexpected response: {:?}", m),
+            }
+        }
+        // Versions outside of the offered range are refused
+        for version in [MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let mut unsupported = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+            match receive_message(&mut unsupported).await {
+                ServerMessage::Offer { .. } => (),
+                m => panic!("Received unexpected response: {:?}", m),
+            }
+            let hello = ClientCommand::Hello {
+                version,
+                capabilities: Vec::new(),
+            };
+            send_command(&mut unsupported, &hello).await;
+            match receive_message(&mut unsupported).await {
+                ServerMessage::Error { code, command, .. } => {
+                    assert_eq!(code, ErrorCode::UnsupportedVersion);
+                    assert_eq!(command.as_deref(), Some("Hello"));
+                }
+                m => panic!("Received unexpected response: {:?}", m),
+            }
+            match unsupported.next().await.unwrap().unwrap() {
+                ws::Frame::Close(Some(reason)) => {
+                    assert_eq!(reason.code, ws::CloseCode::Protocol)
+                }
+                f => panic!("Received unexpected frame: {:?}", f),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_skipping_the_handshake() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub_server = PubSubService::new(&data_log).start();
+        let mut srv = start_server(pubsub_server, data_log);
+        let mut conn = srv.ws_at(&format!("/{}", Uuid::new_v4())).await.unwrap();
+        skip_offer(&mut conn).await;
+        // Clients that don't send `Hello` keep the behaviour of version 1
+        let subscription_id = Uuid::new_v4();
+        let subscribe = ClientCommand::Subscribe {
+            subscription_id,
+            kind: SubscriptionKind::Broadcast,
+            durable: false,
+            create: true,
+        };
+        send_request(&mut conn, &ClientRequest::new(1, subscribe)).await;
+        match receive_message(&mut conn).await {
+            ServerMessage::Ack { request_id, result } => {
+                assert_eq!(request_id, 1);
+                assert_eq!(result, Ok(CommandOutcome::Done));
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+        let submit = ClientCommand::SubmitPublication {
+            subscription_id,
+            submission: Vec::from("Test"),
+            key: None,
+            retain: false,
+            headers: HashMap::new(),
+        };
+        send_command(&mut conn, &submit).await;
+        match receive_message(&mut conn).await {
+            ServerMessage::Issue(Issue(issued_for, _)) => assert_eq!(issued_for, subscription_id),
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_authentication() {
+        let data_log = DataLogger::ephemeral().start();
+        let pubsub_server = PubSubService::new(&data_log).start();
+        let client_id = Uuid::new_v4();
+        let mut tokens = HashMap::new();
+        tokens.insert(String::from("secret-token"), client_id);
+        let authenticator: Arc<dyn Authenticator> = Arc::new(StaticTokenAuthenticator::new(tokens));
+        let mut srv = test::start(move || {
+            App::new()
+                .data(pubsub_server.clone())
+                .data(data_log.clone())
+                .app_data(web::Data::from(authenticator.clone()))
+                .route("/{session_id}", web::get().to(websocket_handler))
+        });
+        assert!(srv.ws_at(&format!("/{}", client_id)).await.is_err());
+        assert!(srv
+            .ws_at(&format!("/{}?access_token=guessed-token", client_id))
+            .await
+            .is_err());
+        assert!(srv
+            .ws_at(&format!("/{}?access_token=secret-token", Uuid::new_v4()))
+            .await
+            .is_err());
+        let mut conn = srv
+            .ws_at(&format!("/{}?access_token=secret-token", client_id))
+            .await
+            .unwrap();
+        handshake(&mut conn).await;
+        send_request(
+            &mut conn,
+            &ClientRequest::new(
+                1,
+                ClientCommand::SetSessionDeliveryMode {
+                    mode: DeliveryMode::Full,
+                },
+            ),
+        )
+        .await;
+        match receive_message(&mut conn).await {
+            ServerMessage::Ack { request_id, result } => {
+                assert_eq!(request_id, 1);
+                assert_eq!(result, Ok(CommandOutcome::Done));
+            }
+            m => panic!("Received unexpected response: {:?}", m),
+        }
+    }
+
+    #[test]
+    fn test_decoding_plain_commands_as_requests() {
+        let cmd = ClientCommand::Unsubscribe {
+            subscription_id: Uuid::new_v4(),
+        };
+        let request: ClientRequest =
+            serde_cbor::from_slice(&serde_cbor::to_vec(&cmd).unwrap()).unwrap();
+        assert_eq!(request.request_id, None);
+        assert_eq!(request.command, cmd);
+    }
+
+    #[test]
+    fn test_classifying_errors() {
+        let response = ErrorResponse::from(DataLogError::ChecksumMismatch(String::from("test")));
+        assert_eq!(response.code, ErrorCode::DataCorrupted);
+        assert_eq!(response.message, "Checksum mismatch: test");
+        let response = ErrorResponse::from(PublicationError::Publishing(String::from("test")));
+        assert_eq!(response.code, ErrorCode::PublishingFailed);
+        let response = ErrorResponse::from(MailboxError::Closed);
+        assert_eq!(response.code, ErrorCode::Unavailable);
     }
 
     #[test]